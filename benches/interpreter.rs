@@ -0,0 +1,47 @@
+use std::sync::atomic::Ordering;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use cute_vm::{cache::DecodeCache, HALTED};
+
+/// Counts to 2000 on the primary stack, about 22k instructions
+const COUNT_LOOP: &str = "
+    lit$sr 0        ; stands in for the return address the loop drops
+    lit$s 0
+#loop
+    drop$sr
+    lit$s 1
+    add$s
+    dup$s
+    lit$s 2000
+    cmp$s
+    dup$sr
+    drop$sr
+    lit$s #loop
+    jsr$g
+    halt
+";
+
+fn interpreter(c: &mut Criterion) {
+    let image = cute_vm::asm::assemble("count_loop.casm", COUNT_LOOP).unwrap().image;
+
+    let mut group = c.benchmark_group("count_loop");
+
+    group.bench_function("step", |b| b.iter(|| {
+        cute_vm::load_image(&image, 0x2400);
+
+        while !HALTED.load(Ordering::Relaxed) {
+            cute_vm::step();
+        }
+    }));
+
+    group.bench_function("predecoded", |b| b.iter(|| {
+        cute_vm::load_image(&image, 0x2400);
+
+        cute_vm::cache::run(&mut DecodeCache::new());
+    }));
+
+    group.finish();
+}
+
+criterion_group!(benches, interpreter);
+criterion_main!(benches);
@@ -0,0 +1,266 @@
+/*
+Ahead of time translation of cute images to Rust
+
+Basic blocks are found by following the code from the entry point, the
+address after every `jsr` and the target of every `jsr` whose address was
+pushed by the `lit` right before it. Each block becomes a function that runs
+its instructions in a straight line, the generated `run` picks the block for
+the current instruction pointer and falls back to `cute_vm::step` for any
+address that is not the start of a block, such as computed jumps.
+
+A block ends after a `jsr`, `halt` or anything touching memory, since those
+can move the instruction pointer, and before anything that can not be
+decoded ahead of time, which is left to the interpreter. The instruction
+pointer is written before every op, and an op that raises an interrupt,
+such as a division by zero or a stack fault, returns from the block with
+the pointer after it, as the interpreter leaves it.
+
+The image is embedded in the module and has to be loaded with
+`cute_vm::load_image`, on the same `machine` it was translated for, before
+calling `run`. Self modifying code is not supported.
+*/
+
+use std::{collections::{BTreeMap, BTreeSet}, fmt::Write as _};
+
+use crate::instructions::{Instr, Status};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Op {
+    pub addr: u32,
+    pub instr: Instr,
+    pub status: Status,
+    /// Immediate of a `lit`
+    pub immediate: Option<u32>,
+}
+
+impl Op {
+    /// Bytes from this instruction to the next one
+    pub fn size(&self) -> u32 {
+        match (self.instr, self.status.contains(Status::SHORT)) {
+            (Instr::Lit, true) => 6,
+            (Instr::Lit, false) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Whether the op goes through `Instruction::execute`, which needs the
+    /// instruction pointer and moves it on
+    fn interpreted(&self) -> bool {
+        matches!(self.instr, Instr::Jsr | Instr::Str | Instr::Load | Instr::Cas | Instr::Fadd | Instr::Swap)
+    }
+
+    fn conditional(&self) -> bool {
+        self.status.intersects(Status::IF_EQUAL | Status::IF_GREATER | Status::IF_LESS)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub start: u32,
+    pub ops: Vec<Op>,
+    /// Where the instruction pointer is left when the block falls off its end
+    pub end: u32,
+}
+
+/// Decodes the instruction at `addr`, `None` when the interpreter has to
+/// handle it
+fn decode(image: &[u8], addr: u32) -> Option<Op> {
+    // Running from an odd address faults
+    if addr & 1 != 0 {
+        return None;
+    }
+
+    let index = addr.checked_sub(crate::machine::get().program.load)? as usize;
+    let bytes = image.get(index..index + 2)?;
+
+    let instr: Instr = num::FromPrimitive::from_u8(bytes[0])?;
+    let status = Status::from_bits(bytes[1])?;
+
+    let immediate = match (instr, status.contains(Status::SHORT)) {
+        // Misaligned immediates raise an exception
+        (Instr::Lit, true) if (addr + 2) & 0b11 == 0 => {
+            let bytes = image.get(index + 2..index + 6)?;
+            Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+        },
+        (Instr::Lit, true) => return None,
+        (Instr::Lit, false) => {
+            let bytes = image.get(index + 2..index + 4)?;
+            Some(u16::from_le_bytes(bytes.try_into().unwrap()) as u32)
+        },
+        _ => None,
+    };
+
+    Some(Op { addr, instr, status, immediate })
+}
+
+/// Recovers the basic blocks reachable from `entry`
+pub fn blocks(image: &[u8], entry: u32) -> BTreeMap<u32, Block> {
+    let mut blocks = BTreeMap::new();
+    let mut pending = vec![entry];
+    let mut seen = BTreeSet::new();
+
+    while let Some(start) = pending.pop() {
+        if !seen.insert(start) {
+            continue;
+        }
+
+        let mut ops: Vec<Op> = Vec::new();
+        let mut addr = start;
+
+        while let Some(op) = decode(image, addr) {
+            ops.push(op);
+            addr += op.size();
+
+            match op.instr {
+                Instr::Jsr => {
+                    pending.push(addr);
+
+                    let target = ops.iter().rev().nth(1)
+                        .filter(|lit| lit.instr == Instr::Lit && !lit.conditional())
+                        .filter(|lit| !lit.status.contains(Status::RETURN) && !op.status.contains(Status::RETURN))
+                        .and_then(|lit| lit.immediate);
+
+                    if let Some(target) = target {
+                        pending.push(target);
+                    }
+                    break;
+                },
+                Instr::Halt if !op.conditional() => break,
+                Instr::Str | Instr::Load | Instr::Cas | Instr::Fadd | Instr::Swap | Instr::Halt => {
+                    pending.push(addr);
+                    break;
+                },
+                _ => (),
+            }
+        }
+
+        if !ops.is_empty() {
+            blocks.insert(start, Block { start, ops, end: addr });
+        }
+    }
+
+    blocks
+}
+
+/// Writes a Rust module running `image` from `entry`
+pub fn translate(image: &[u8], entry: u32) -> String {
+    let blocks = blocks(image, entry);
+    let mut out = String::new();
+
+    writeln!(out, "// Translated from a cute image by `cute-vm --translate`").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "use std::sync::atomic::Ordering;").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "#[allow(unused_imports)]").unwrap();
+    writeln!(out, "use cute_vm::instructions::{{ConditionRegister, Instr, Instruction, Status}};").unwrap();
+    writeln!(out).unwrap();
+
+    writeln!(out, "/// Load with `cute_vm::load_image` before calling `run`").unwrap();
+    write!(out, "pub static IMAGE: [u8; {}] = [", image.len()).unwrap();
+    for (i, byte) in image.iter().enumerate() {
+        if i % 16 == 0 {
+            write!(out, "\n   ").unwrap();
+        }
+        write!(out, " 0x{:02x},", byte).unwrap();
+    }
+    writeln!(out, "\n];").unwrap();
+    writeln!(out).unwrap();
+
+    if blocks.values().flat_map(|block| block.ops.iter()).any(|op| op.conditional() && !op.interpreted()) {
+        writeln!(out, "fn met(flags: u8) -> bool {{").unwrap();
+        writeln!(out, "    Status::from_bits_truncate(flags).condition_met(ConditionRegister::read())").unwrap();
+        writeln!(out, "}}").unwrap();
+        writeln!(out).unwrap();
+    }
+
+    writeln!(out, "fn set_ip(ip: u32) {{").unwrap();
+    writeln!(out, "    cute_vm::cpu::set_ip(ip)").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    for block in blocks.values() {
+        writeln!(out).unwrap();
+        writeln!(out, "fn block_{:x}() {{", block.start).unwrap();
+
+        for op in block.ops.iter() {
+            write_op(&mut out, op);
+        }
+
+        let last = block.ops.last().unwrap();
+        if !last.interpreted() {
+            writeln!(out, "    set_ip(0x{:x});", block.end).unwrap();
+        }
+
+        writeln!(out, "}}").unwrap();
+    }
+
+    writeln!(out).unwrap();
+    writeln!(out, "/// Runs the current core until the machine stops").unwrap();
+    writeln!(out, "pub fn run() {{").unwrap();
+    writeln!(out, "    while cute_vm::running() {{").unwrap();
+    writeln!(out, "        if cute_vm::interrupt().load(Ordering::Relaxed) {{").unwrap();
+    writeln!(out, "            cute_vm::step();").unwrap();
+    writeln!(out, "            continue;").unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "        match cute_vm::cpu::ip() {{").unwrap();
+    for start in blocks.keys() {
+        writeln!(out, "            0x{:x} => block_{:x}(),", start, start).unwrap();
+    }
+    writeln!(out, "            _ => cute_vm::step(),").unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "        while !cute_vm::OUT_PUT_READY.load(Ordering::Relaxed) {{").unwrap();
+    writeln!(out, "            std::hint::spin_loop();").unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    out
+}
+
+fn write_op(out: &mut String, op: &Op) {
+    let flags = op.status.bits();
+
+    writeln!(out, "    // 0x{:x}: {:?} {:?}", op.addr, op.instr, op.status).unwrap();
+    writeln!(out, "    set_ip(0x{:x});", op.addr).unwrap();
+    writeln!(out, "    if !cute_vm::limits::tick() {{").unwrap();
+    writeln!(out, "        return;").unwrap();
+    writeln!(out, "    }}").unwrap();
+
+    if op.interpreted() {
+        writeln!(out, "    Instruction::new(Instr::{:?}, Status::from_bits_truncate(0x{:x})).execute();", op.instr, flags).unwrap();
+        return;
+    }
+
+    let body = match op.instr {
+        Instr::Lit => vec![
+            "cute_vm::timing::access(false);".to_string(),
+            format!("cute_vm::push(0x{:x}, Status::from_bits_truncate(0x{:x}));", op.immediate.unwrap(), flags),
+        ],
+        Instr::Nop => vec![],
+        instr => vec![format!("Instr::{:?}.execute(Status::from_bits_truncate(0x{:x}));", instr, flags)],
+    };
+
+    if op.conditional() {
+        writeln!(out, "    if met(0x{:x}) {{", flags).unwrap();
+        for line in body {
+            writeln!(out, "        {}", line).unwrap();
+        }
+        writeln!(out, "        cute_vm::timing::retire(Instr::{:?}, true);", op.instr).unwrap();
+        writeln!(out, "    }} else {{").unwrap();
+        writeln!(out, "        cute_vm::timing::retire(Instr::{:?}, false);", op.instr).unwrap();
+        writeln!(out, "    }}").unwrap();
+    } else {
+        for line in body {
+            writeln!(out, "    {}", line).unwrap();
+        }
+        writeln!(out, "    cute_vm::timing::retire(Instr::{:?}, true);", op.instr).unwrap();
+    }
+
+    // Stack faults and division by zero
+    if op.instr != Instr::Nop {
+        writeln!(out, "    if cute_vm::interrupt().load(Ordering::Relaxed) {{").unwrap();
+        writeln!(out, "        return set_ip(0x{:x});", op.addr.wrapping_add(op.size())).unwrap();
+        writeln!(out, "    }}").unwrap();
+    }
+}
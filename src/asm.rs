@@ -0,0 +1,361 @@
+/*
+Assembler for casm source
+
+    @1600               ; move to an address, padding with zeroes
+    #label              ; define a label at the current address
+    lit$s #label        ; push the address of a label
+    lit 0x41            ; immediates are hex with `0x`/`0xx`, or decimal
+    add$sk              ; flags follow a `$`
+    .include "lib.casm" ; assemble another file in place, relative to this one
+
+| flag | Status       |
+| ---- | ------------ |
+| `k`  | `KEEP`       |
+| `r`  | `RETURN`     |
+| `s`  | `SHORT`      |
+| `e`  | `IF_EQUAL`   |
+| `g`  | `IF_GREATER` |
+| `l`  | `IF_LESS`    |
+
+A label whose comment starts with `test` is a unit test of the routine there,
+see `unit` for the rest of the annotation.
+
+    #double ;test 3 -> 6
+
+`lit$s` reads its immediate as a u32, so a `nop` is inserted in front of it
+whenever that immediate would not be 4 byte aligned.
+*/
+
+use std::{collections::{BTreeMap, BTreeSet}, fmt, path::Path};
+
+use crate::{instructions::{Instr, Status}, symbols::Symbols, unit::Case};
+
+/// Address the loader places the image at with the built-in `machine`
+pub const LOAD_ADDR: u32 = 0x1600;
+
+#[derive(Debug)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLine {
+    /// Index into `DebugInfo::files`
+    pub file: usize,
+    pub line: usize,
+}
+
+/// Where everything in an image came from
+#[derive(Debug, Default, Clone)]
+pub struct DebugInfo {
+    pub symbols: Symbols,
+    pub files: Vec<String>,
+    /// Source line of the instruction starting at each address
+    pub lines: BTreeMap<u32, SourceLine>,
+    /// Instructions that only run when a condition flag matches
+    pub conditionals: BTreeSet<u32>,
+    /// Labels annotated with `;test`, in source order
+    pub tests: Vec<Case>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    /// Bytes to load at the address it was assembled for
+    pub image: Vec<u8>,
+    pub debug: DebugInfo,
+}
+
+enum Operand {
+    Number(u32),
+    Label(String),
+}
+
+enum Item {
+    Org(u32),
+    Label(String),
+    Instr {
+        instr: Instr,
+        status: Status,
+        operand: Option<Operand>,
+    },
+}
+
+struct Line {
+    item: Item,
+    source: SourceLine,
+}
+
+pub fn assemble_file(path: &Path) -> Result<Assembly, AsmError> {
+    let mut debug = DebugInfo::default();
+    let mut lines = Vec::new();
+
+    parse_file(path, &mut debug, &mut lines, 0)?;
+
+    layout(lines, debug, crate::machine::get().program.load)
+}
+
+/// Assembles source that does not live in a file, `.include` is resolved
+/// relative to the working directory
+pub fn assemble(name: &str, source: &str) -> Result<Assembly, AsmError> {
+    assemble_at(name, source, crate::machine::get().program.load)
+}
+
+/// `assemble` for an image loaded at `origin`
+pub fn assemble_at(name: &str, source: &str, origin: u32) -> Result<Assembly, AsmError> {
+    let mut debug = DebugInfo::default();
+    let mut lines = Vec::new();
+
+    parse_source(name, Path::new("."), source, &mut debug, &mut lines, 0)?;
+
+    layout(lines, debug, origin)
+}
+
+fn parse_file(path: &Path, debug: &mut DebugInfo, lines: &mut Vec<Line>, depth: usize) -> Result<(), AsmError> {
+    let name = path.display().to_string();
+
+    let source = std::fs::read_to_string(path).map_err(|err| AsmError {
+        file: name.clone(),
+        line: 0,
+        message: err.to_string(),
+    })?;
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+
+    parse_source(&name, dir, &source, debug, lines, depth)
+}
+
+fn parse_source(name: &str, dir: &Path, source: &str, debug: &mut DebugInfo, lines: &mut Vec<Line>, depth: usize) -> Result<(), AsmError> {
+    let file = debug.files.len();
+    debug.files.push(name.to_string());
+
+    for (line_no, text) in source.lines().enumerate() {
+        let line = line_no + 1;
+        let error = |message: String| AsmError { file: name.to_string(), line, message };
+
+        let (code, comment) = match text.split_once(';') {
+            Some((code, comment)) => (code, Some(comment)),
+            None => (text, None),
+        };
+        let mut words = code.split_whitespace();
+
+        let word = match words.next() {
+            Some(word) => word,
+            None => continue,
+        };
+        let operand = words.next();
+
+        if let Some(extra) = words.next() {
+            return Err(error(format!("unexpected {}", extra)));
+        }
+
+        let source = SourceLine { file, line };
+
+        if word == ".include" {
+            if depth > 16 {
+                return Err(error("includes nested too deeply".to_string()));
+            }
+
+            let include = operand.ok_or_else(|| error("missing file to include".to_string()))?;
+            let include = dir.join(include.trim_matches('"'));
+
+            parse_file(&include, debug, lines, depth + 1)?;
+            continue;
+        }
+
+        let item = if let Some(addr) = word.strip_prefix('@') {
+            Item::Org(u32::from_str_radix(addr, 16).map_err(|_| error(format!("invalid address {}", addr)))?)
+        } else if let Some(label) = word.strip_prefix('#') {
+            if operand.is_some() {
+                return Err(error("labels go on their own line".to_string()));
+            }
+
+            let test = comment.map(str::trim).and_then(|comment| comment.strip_prefix("test"))
+                .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace));
+
+            if let Some(test) = test {
+                debug.tests.push(Case::parse(label, source, test).map_err(error)?);
+            }
+
+            Item::Label(label.to_string())
+        } else {
+            let (mnemonic, flags) = word.split_once('$').unwrap_or((word, ""));
+
+            let instr = parse_instr(mnemonic).ok_or_else(|| error(format!("unknown instruction {}", mnemonic)))?;
+            let status = parse_flags(flags).ok_or_else(|| error(format!("unknown flags {}", flags)))?;
+
+            let operand = match (instr, operand) {
+                (Instr::Lit, Some(operand)) => Some(parse_operand(operand).ok_or_else(|| error(format!("invalid operand {}", operand)))?),
+                (Instr::Lit, None) => return Err(error("lit needs an operand".to_string())),
+                (_, Some(operand)) => return Err(error(format!("unexpected {}", operand))),
+                (_, None) => None,
+            };
+
+            Item::Instr { instr, status, operand }
+        };
+
+        lines.push(Line { item, source });
+    }
+
+    Ok(())
+}
+
+fn layout(lines: Vec<Line>, mut debug: DebugInfo, origin: u32) -> Result<Assembly, AsmError> {
+    let error = |debug: &DebugInfo, source: SourceLine, message: String| AsmError {
+        file: debug.files[source.file].clone(),
+        line: source.line,
+        message,
+    };
+
+    // First pass places every label
+    let mut addr = origin;
+    for line in lines.iter() {
+        match &line.item {
+            Item::Org(org) => {
+                if *org < addr {
+                    return Err(error(&debug, line.source, format!("0x{:x} is behind the current address 0x{:x}", org, addr)));
+                }
+
+                addr = *org;
+            },
+            Item::Label(label) => {
+                if debug.symbols.find(label).is_some() {
+                    return Err(error(&debug, line.source, format!("label #{} defined twice", label)));
+                }
+
+                debug.symbols.insert(addr, label);
+            },
+            Item::Instr { instr, status, .. } => addr += size(addr, *instr, *status),
+        }
+    }
+
+    // Second pass emits the bytes
+    let mut image = Vec::new();
+    let mut addr = origin;
+    for line in lines.iter() {
+        match &line.item {
+            Item::Org(org) => {
+                image.resize((*org - origin) as usize, 0);
+                addr = *org;
+            },
+            Item::Label(_) => (),
+            Item::Instr { instr, status, operand } => {
+                if needs_padding(addr, *instr, *status) {
+                    image.extend_from_slice(&[Instr::Nop as u8, 0]);
+                    addr += 2;
+                }
+
+                debug.lines.insert(addr, line.source);
+                if status.intersects(Status::IF_EQUAL | Status::IF_GREATER | Status::IF_LESS) {
+                    debug.conditionals.insert(addr);
+                }
+
+                image.push(*instr as u8);
+                image.push(status.bits());
+                addr += 2;
+
+                if let Some(operand) = operand {
+                    let value = match operand {
+                        Operand::Number(value) => *value,
+                        Operand::Label(label) => debug.symbols.find(label)
+                            .ok_or_else(|| error(&debug, line.source, format!("unknown label #{}", label)))?,
+                    };
+
+                    if status.contains(Status::SHORT) {
+                        image.extend_from_slice(&value.to_le_bytes());
+                        addr += 4;
+                    } else {
+                        image.extend_from_slice(&(value as u16).to_le_bytes());
+                        addr += 2;
+                    }
+                }
+            },
+        }
+    }
+
+    Ok(Assembly { image, debug })
+}
+
+fn needs_padding(addr: u32, instr: Instr, status: Status) -> bool {
+    instr == Instr::Lit && status.contains(Status::SHORT) && (addr + 2) & 0b11 != 0
+}
+
+fn size(addr: u32, instr: Instr, status: Status) -> u32 {
+    let padding = if needs_padding(addr, instr, status) { 2 } else { 0 };
+
+    let immediate = match (instr, status.contains(Status::SHORT)) {
+        (Instr::Lit, true) => 4,
+        (Instr::Lit, false) => 2,
+        _ => 0,
+    };
+
+    padding + 2 + immediate
+}
+
+fn parse_instr(mnemonic: &str) -> Option<Instr> {
+    let instr = match mnemonic {
+        "nop" => Instr::Nop,
+        "lit" => Instr::Lit,
+        "dup" => Instr::Dup,
+        "over" => Instr::Over,
+        "str" => Instr::Str,
+        "load" => Instr::Load,
+        "push" => Instr::Push,
+        "drop" => Instr::Drop,
+        "jsr" => Instr::Jsr,
+        "cmp" => Instr::Cmp,
+        "add" => Instr::Add,
+        "sub" => Instr::Sub,
+        "mul" => Instr::Mul,
+        "div" => Instr::Div,
+        "halt" => Instr::Halt,
+        "cas" => Instr::Cas,
+        "fadd" => Instr::Fadd,
+        "swap" => Instr::Swap,
+        _ => return None,
+    };
+
+    Some(instr)
+}
+
+fn parse_flags(flags: &str) -> Option<Status> {
+    let mut status = Status::NONE;
+
+    for flag in flags.chars() {
+        status |= match flag {
+            'k' => Status::KEEP,
+            'r' => Status::RETURN,
+            's' => Status::SHORT,
+            'e' => Status::IF_EQUAL,
+            'g' => Status::IF_GREATER,
+            'l' => Status::IF_LESS,
+            _ => return None,
+        };
+    }
+
+    Some(status)
+}
+
+fn parse_operand(operand: &str) -> Option<Operand> {
+    if let Some(label) = operand.strip_prefix('#') {
+        return Some(Operand::Label(label.to_string()));
+    }
+
+    parse_number(operand).map(Operand::Number)
+}
+
+/// Hex with `0x`/`0xx`, or decimal
+pub(crate) fn parse_number(number: &str) -> Option<u32> {
+    if let Some(hex) = number.strip_prefix("0xx").or_else(|| number.strip_prefix("0x")) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        number.parse().ok()
+    }
+}
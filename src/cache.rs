@@ -0,0 +1,281 @@
+use std::{collections::HashMap, sync::{atomic::{AtomicU64, Ordering}, MutexGuard}};
+
+use crate::{
+    instructions::{ConditionRegister, Instr, Instruction, Stacks, Status},
+    mmu, stack::Stack,
+    cpu, limits, timing, MMU, OUT_PUT_READY,
+};
+
+/// Code is tracked in pages of `1 << PAGE_SHIFT` bytes of `MEM`
+const PAGE_SHIFT: usize = 8;
+const PAGES: usize = (u32::MAX as usize + 1) >> PAGE_SHIFT;
+
+/// One bit per page of `MEM` that holds an instruction cached by any core,
+/// pages stay marked until `reset`
+static CODE_PAGES: [AtomicU64; PAGES / 64] = [const { AtomicU64::new(0) }; PAGES / 64];
+/// Bumped when a code page is written, caches from an older generation are
+/// flushed before their next instruction
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Most instructions run while holding the stacks and the MMU at once
+const SLICE: usize = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Decoded {
+    pub instr: Instr,
+    pub status: Status,
+    /// Immediate of a `lit`, `None` when it has to go through the MMU
+    pub immediate: Option<u32>,
+}
+
+impl Decoded {
+    /// Bytes from this instruction to the next one
+    pub fn size(&self) -> u32 {
+        match (self.instr, self.status.contains(Status::SHORT)) {
+            (Instr::Lit, true) => 6,
+            (Instr::Lit, false) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Whether this only works on the stacks and can run in a slice
+    pub fn on_stacks(&self) -> bool {
+        match self.instr {
+            Instr::Lit => self.immediate.is_some(),
+            Instr::Nop | Instr::Dup | Instr::Over | Instr::Push | Instr::Drop
+                | Instr::Cmp | Instr::Add | Instr::Sub | Instr::Mul | Instr::Div => true,
+            _ => false,
+        }
+    }
+}
+
+/// Both stacks of the current core and the MMU, locked in that order
+struct Held<'a> {
+    primary: MutexGuard<'a, Stack>,
+    ret: MutexGuard<'a, Stack>,
+    mmu: MutexGuard<'a, mmu::MMU>,
+}
+
+impl Stacks for Held<'_> {
+    fn push(&mut self, data: u32, flags: Status) {
+        let stack = if flags.contains(Status::RETURN) { &mut self.ret } else { &mut self.primary };
+        stack.push_with(&self.mmu, data, flags);
+    }
+
+    fn pop(&mut self, flags: Status) -> u32 {
+        let stack = if flags.contains(Status::RETURN) { &mut self.ret } else { &mut self.primary };
+        stack.pop_with(&self.mmu, flags)
+    }
+}
+
+/// Decoded instructions keyed by their address
+#[derive(Debug, Default)]
+pub struct DecodeCache {
+    entries: HashMap<u32, Decoded>,
+    generation: u64,
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The instruction at `ip`, None when there is none
+    pub fn get(&mut self, ip: u32) -> Option<Decoded> {
+        if let Some(decoded) = self.entries.get(&ip) {
+            return Some(*decoded);
+        }
+
+        let decoded = decode(ip)?;
+        self.entries.insert(ip, decoded);
+
+        let index = crate::machine::get().index(ip);
+        for page in [index >> PAGE_SHIFT, (index + decoded.size() as usize - 1) >> PAGE_SHIFT] {
+            CODE_PAGES[page / 64].fetch_or(1 << (page % 64), Ordering::Relaxed);
+        }
+
+        Some(decoded)
+    }
+
+    /// The instruction at `ip` if it was already decoded
+    pub fn cached(&self, ip: u32) -> Option<Decoded> {
+        self.entries.get(&ip).copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Forgets every code page, called when a new image is loaded
+pub fn reset() {
+    for pages in CODE_PAGES.iter() {
+        pages.store(0, Ordering::Relaxed);
+    }
+
+    GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Called by `Memory` for every byte written
+pub fn invalidate(index: usize) {
+    let page = index >> PAGE_SHIFT;
+
+    if page < PAGES && CODE_PAGES[page / 64].load(Ordering::Relaxed) & (1 << (page % 64)) != 0 {
+        GENERATION.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn decode(ip: u32) -> Option<Decoded> {
+    let instruction = Instruction::fetch(ip)?;
+    let (instr, status) = (*instruction.instr(), instruction.status());
+
+    // Misaligned or unmapped immediates raise an exception, which only the
+    // MMU does
+    let mmu = MMU.lock().unwrap();
+    let immediate = match (instr, status.contains(Status::SHORT)) {
+        (Instr::Lit, true) if ip.wrapping_add(2) & 0b11 == 0 => mmu.peek(ip.wrapping_add(2), 32),
+        (Instr::Lit, false) => mmu.peek(ip.wrapping_add(2), 16),
+        _ => None,
+    };
+
+    Some(Decoded { instr, status, immediate })
+}
+
+/// Runs the current core until the machine halts or is signalled, decoding
+/// every address only once and keeping the instruction pointer out of the MMU
+///
+/// Runs of instructions that only touch the stacks go through `run_slice`,
+/// which locks the stacks and the MMU once for up to `SLICE` of them.
+/// Tracing, profiling, coverage, watchpoints and history all hook into
+/// `crate::step` and are not seen here.
+pub fn run(cache: &mut DecodeCache) {
+    let core = cpu::current();
+
+    while crate::running() {
+        if core.halted.load(Ordering::SeqCst) {
+            std::thread::yield_now();
+            continue;
+        }
+
+        if !limits::tick() {
+            break;
+        }
+
+        let generation = GENERATION.load(Ordering::Relaxed);
+        if cache.generation != generation {
+            cache.clear();
+            cache.generation = generation;
+        }
+
+        if core.interrupt.load(Ordering::Relaxed) && !crate::take_interrupt() {
+            continue;
+        }
+
+        let ip = cpu::ip();
+        let decoded = match cache.get(ip) {
+            Some(decoded) => decoded,
+            None => {
+                crate::fetch_fault(ip);
+                continue;
+            },
+        };
+
+        match (decoded.instr, decoded.immediate) {
+            // A clock may put the core to sleep, which it must not do while
+            // holding the MMU
+            _ if decoded.on_stacks() && !timing::throttled() => {
+                run_slice(cache, decoded, ip);
+            },
+            (Instr::Lit, Some(immediate)) => {
+                let met = decoded.status.condition_met(ConditionRegister::read());
+
+                if met {
+                    // Fetching the immediate is still a memory access
+                    timing::access(false);
+                    crate::push(immediate, decoded.status);
+                }
+
+                timing::retire(Instr::Lit, met);
+
+                cpu::set_ip(ip.wrapping_add(decoded.size()));
+            },
+            (Instr::Jsr, _) | (Instr::Lit, None) => {
+                Instruction::new(decoded.instr, decoded.status).execute();
+            },
+            (instr, _) => {
+                let met = decoded.status.condition_met(ConditionRegister::read());
+
+                if met {
+                    instr.execute(decoded.status);
+                }
+
+                timing::retire(instr, met);
+
+                // The instruction may have written the instruction pointer
+                cpu::set_ip(cpu::ip().wrapping_add(2));
+            },
+        }
+
+        while !OUT_PUT_READY.load(Ordering::Relaxed) {
+            std::hint::spin_loop();
+        }
+    }
+}
+
+/// Runs `decoded` at `ip` and the cached stack instructions after it with
+/// the locks held, stopping early for an interrupt, a write to code or
+/// anything that is not cached yet
+fn run_slice(cache: &DecodeCache, mut decoded: Decoded, mut ip: u32) {
+    let core = cpu::current();
+    let mut held = Held {
+        primary: core.primary.lock().unwrap(),
+        ret: core.ret.lock().unwrap(),
+        mmu: MMU.lock().unwrap(),
+    };
+
+    for n in 0..SLICE {
+        if n > 0 {
+            match cache.cached(ip) {
+                Some(next) if next.on_stacks() => decoded = next,
+                _ => break,
+            }
+
+            if !limits::tick() {
+                break;
+            }
+        }
+
+        let met = decoded.status.condition_met(ConditionRegister::read());
+
+        if met {
+            match decoded.immediate {
+                Some(immediate) => {
+                    timing::access(false);
+                    held.push(immediate, decoded.status);
+                },
+                None => {
+                    decoded.instr.execute_on(&mut held, decoded.status);
+                },
+            }
+        }
+
+        timing::retire(decoded.instr, met);
+
+        ip = ip.wrapping_add(decoded.size());
+        cpu::set_ip(ip);
+
+        if core.interrupt.load(Ordering::Relaxed)
+            || GENERATION.load(Ordering::Relaxed) != cache.generation
+            || !crate::running() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    sync::{atomic::{AtomicBool, Ordering}, Mutex},
+};
+
+use crate::{asm::DebugInfo, instructions::Status};
+
+pub static COVERAGE: Mutex<Option<Coverage>> = Mutex::new(None);
+pub static COVERING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Default)]
+pub struct Coverage {
+    /// Times the instruction at each address was reached
+    hits: HashMap<u32, u64>,
+    /// Times each conditional instruction ran and was skipped
+    branches: HashMap<u32, (u64, u64)>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, ip: u32, status: Status, executed: bool) {
+        *self.hits.entry(ip).or_insert(0) += 1;
+
+        if status.intersects(Status::IF_EQUAL | Status::IF_GREATER | Status::IF_LESS) {
+            let branch = self.branches.entry(ip).or_insert((0, 0));
+
+            if executed {
+                branch.0 += 1;
+            } else {
+                branch.1 += 1;
+            }
+        }
+    }
+
+    /// Writes an lcov tracefile covering every source file in `debug`,
+    /// labels are reported as functions
+    pub fn lcov(&self, debug: &DebugInfo) -> String {
+        let mut out = String::new();
+
+        let mut lines_by_file: BTreeMap<usize, Vec<(u32, usize)>> = BTreeMap::new();
+        for (addr, source) in debug.lines.iter() {
+            lines_by_file.entry(source.file).or_default().push((*addr, source.line));
+        }
+
+        writeln!(out, "TN:").unwrap();
+
+        for (file, lines) in lines_by_file {
+            writeln!(out, "SF:{}", debug.files[file]).unwrap();
+
+            let functions: Vec<(&str, usize, u64)> = debug.symbols.iter().filter_map(|(addr, name)| {
+                let source = debug.lines.range(addr..).next().map(|(_, source)| *source)?;
+
+                if source.file != file {
+                    return None;
+                }
+
+                let first = debug.lines.range(addr..).next().map(|(addr, _)| *addr)?;
+
+                Some((name, source.line, self.hits.get(&first).copied().unwrap_or(0)))
+            }).collect();
+
+            for (name, line, _) in functions.iter() {
+                writeln!(out, "FN:{},{}", line, name).unwrap();
+            }
+            for (name, _, count) in functions.iter() {
+                writeln!(out, "FNDA:{},{}", count, name).unwrap();
+            }
+            writeln!(out, "FNF:{}", functions.len()).unwrap();
+            writeln!(out, "FNH:{}", functions.iter().filter(|(_, _, count)| *count != 0).count()).unwrap();
+
+            let mut branches = 0;
+            let mut branches_hit = 0;
+            for (addr, line) in lines.iter().filter(|(addr, _)| debug.conditionals.contains(addr)) {
+                match self.branches.get(addr) {
+                    Some((taken, skipped)) => {
+                        writeln!(out, "BRDA:{},0,0,{}", line, taken).unwrap();
+                        writeln!(out, "BRDA:{},0,1,{}", line, skipped).unwrap();
+
+                        branches_hit += (*taken != 0) as usize + (*skipped != 0) as usize;
+                    },
+                    None => {
+                        writeln!(out, "BRDA:{},0,0,-", line).unwrap();
+                        writeln!(out, "BRDA:{},0,1,-", line).unwrap();
+                    },
+                }
+
+                branches += 2;
+            }
+            writeln!(out, "BRF:{}", branches).unwrap();
+            writeln!(out, "BRH:{}", branches_hit).unwrap();
+
+            let mut hit = 0;
+            for (addr, line) in lines.iter() {
+                let count = self.hits.get(addr).copied().unwrap_or(0);
+                writeln!(out, "DA:{},{}", line, count).unwrap();
+
+                hit += (count != 0) as usize;
+            }
+            writeln!(out, "LF:{}", lines.len()).unwrap();
+            writeln!(out, "LH:{}", hit).unwrap();
+
+            writeln!(out, "end_of_record").unwrap();
+        }
+
+        out
+    }
+}
+
+pub fn start() {
+    *COVERAGE.lock().unwrap() = Some(Coverage::new());
+    COVERING.store(true, Ordering::Relaxed);
+}
+
+pub fn finish() -> Option<Coverage> {
+    COVERING.store(false, Ordering::Relaxed);
+
+    COVERAGE.lock().unwrap().take()
+}
+
+pub fn record(ip: u32, status: Status, executed: bool) {
+    if !COVERING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Some(coverage) = COVERAGE.lock().unwrap().as_mut() {
+        coverage.record(ip, status, executed);
+    }
+}
@@ -0,0 +1,258 @@
+/*
+Cores sharing one memory and device bus
+
+Every core has its own instruction pointer and condition register in a
+window of `MEM`, its own pair of stacks, Sic and pending interrupt. All
+cores start at the entry point and tell themselves apart with the IO
+registers below. With the built-in `machine` they are laid out as
+
+| core | IP (MEM)          | condition (MEM)   | primary stack (MMU) | return stack (MMU)  |
+| ---- | ----------------- | ----------------- | ------------------- | ------------------- |
+| n    | 0x200 + 0x10 * n  | 0x204 + 0x10 * n  | 0x20ff + 0x200 * n  | 0x21ff + 0x200 * n  |
+
+| IO   | access | desc                                              |
+| ---- | ------ | ------------------------------------------------- |
+| 0x10 | read   | ID of the core doing the read                     |
+| 0x14 | read   | number of cores                                   |
+| 0x18 | write  | interrupt the core with this ID, cause `IPI_CAUSE`|
+| 0x20 | read   | cycles run by the core, see `timing`              |
+
+A halted core sleeps until another core interrupts it, the machine halts
+once every core has.
+*/
+
+use std::{
+    cell::Cell,
+    sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Mutex},
+};
+
+use crate::{sic::Sic, stack::Stack, HALTED};
+
+pub const MAX_CORES: usize = 16;
+
+/// Sic cause of an inter-processor interrupt
+pub const IPI_CAUSE: u32 = 4;
+
+pub struct Core {
+    pub primary: Mutex<Stack>,
+    pub ret: Mutex<Stack>,
+    pub sic: Mutex<Sic>,
+    pub interrupt: AtomicBool,
+    /// Inter-processor interrupt not yet raised on `sic`, see `send_ipi`
+    pub ipi: AtomicBool,
+    pub halted: AtomicBool,
+    pub cycles: AtomicU64,
+    /// Indices in `MEM` of the instruction pointer and condition register
+    registers: [AtomicUsize; 2],
+}
+
+impl Core {
+    const fn new() -> Self {
+        Self {
+            primary: Mutex::new(Stack::new(0x20ff, 0x100)),
+            ret: Mutex::new(Stack::new(0x21ff, 0x100)),
+            sic: Mutex::new(Sic::new()),
+            interrupt: AtomicBool::new(false),
+            ipi: AtomicBool::new(false),
+            halted: AtomicBool::new(false),
+            cycles: AtomicU64::new(0),
+            registers: [AtomicUsize::new(0x200), AtomicUsize::new(0x204)],
+        }
+    }
+}
+
+pub static CORES: [Core; MAX_CORES] = [const { Core::new() }; MAX_CORES];
+pub static CORE_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    static CURRENT: Cell<usize> = const { Cell::new(0) };
+}
+
+/// ID of the core running on this thread
+pub fn id() -> usize {
+    CURRENT.with(|current| current.get())
+}
+
+/// Makes this thread run core `id`
+pub fn select(id: usize) {
+    assert!(id < count(), "No core {}", id);
+
+    CURRENT.with(|current| current.set(id));
+}
+
+pub fn current() -> &'static Core {
+    &CORES[id()]
+}
+
+pub fn count() -> usize {
+    CORE_COUNT.load(Ordering::Relaxed)
+}
+
+pub fn set_count(count: usize) {
+    assert!((1..=MAX_CORES).contains(&count), "Between 1 and {} cores are supported", MAX_CORES);
+
+    CORE_COUNT.store(count, Ordering::Relaxed);
+}
+
+/// Address of the instruction pointer of the current core
+pub fn ip_addr() -> u32 {
+    crate::machine::get().ip(id())
+}
+
+/// Index in `MEM` of the instruction pointer of the current core
+pub fn ip_index() -> usize {
+    current().registers[0].load(Ordering::Relaxed)
+}
+
+/// Instruction pointer of the current core, read straight from `MEM` without
+/// going through the MMU
+pub fn ip() -> u32 {
+    crate::mem().read_u32(ip_index()).expect("Instruction pointer outside of memory")
+}
+
+/// Writes the instruction pointer of the current core straight to `MEM`
+pub fn set_ip(ip: u32) {
+    crate::mem().write_u32(ip_index(), ip).expect("Instruction pointer outside of memory")
+}
+
+/// Index in `MEM` of the condition register of the current core
+pub fn cond_index() -> usize {
+    current().registers[1].load(Ordering::Relaxed)
+}
+
+/// Points every core at its registers in the current `machine`
+pub fn map_registers() {
+    let machine = crate::machine::get();
+
+    for (id, core) in CORES.iter().enumerate() {
+        core.registers[0].store(machine.index(machine.ip(id)), Ordering::Relaxed);
+        core.registers[1].store(machine.index(machine.conditions(id)), Ordering::Relaxed);
+    }
+}
+
+/// Puts every core in its power on state, starting at `entry`
+pub fn reset(entry: u32) {
+    map_registers();
+    let machine = crate::machine::get();
+
+    for (id, core) in CORES.iter().enumerate().take(count()) {
+        let (primary, ret) = machine.stacks(id);
+
+        *core.primary.lock().unwrap() = Stack::new(primary, machine.cores.primary_stack_size);
+        *core.ret.lock().unwrap() = Stack::new(ret, machine.cores.return_stack_size);
+        *core.sic.lock().unwrap() = Sic::for_core(id);
+
+        core.interrupt.store(false, Ordering::Relaxed);
+        core.ipi.store(false, Ordering::Relaxed);
+        core.halted.store(false, Ordering::Relaxed);
+        core.cycles.store(0, Ordering::Relaxed);
+
+        crate::mem().write_u32(core.registers[0].load(Ordering::Relaxed), entry).expect("Instruction pointer outside of memory");
+    }
+
+    select(0);
+}
+
+/// Halts the current core, and the machine when it was the last one running
+pub fn halt() {
+    current().halted.store(true, Ordering::SeqCst);
+
+    // An interrupt sent before the core went to sleep still wakes it
+    if current().interrupt.load(Ordering::SeqCst) {
+        current().halted.store(false, Ordering::SeqCst);
+        return;
+    }
+
+    if CORES[..count()].iter().all(|core| core.halted.load(Ordering::SeqCst)) {
+        HALTED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Raises an inter-processor interrupt on core `target`, waking it up
+///
+/// Called with the MMU held, so it leaves the target's Sic alone and only
+/// flags the interrupt, the target raises it once it takes the interrupt.
+pub fn send_ipi(target: u32) {
+    match CORES[..count()].get(target as usize) {
+        Some(core) => {
+            log::info!("Core {} interrupting core {}", id(), target);
+
+            core.ipi.store(true, Ordering::SeqCst);
+            core.interrupt.store(true, Ordering::SeqCst);
+            core.halted.store(false, Ordering::SeqCst);
+        },
+        None => {
+            crate::int_controller().lock().unwrap().gen_exception(crate::sic::MISSING_CORE);
+            log::warn!("Interrupt sent to missing core {}", target);
+        },
+    }
+}
+
+/// Runs every core on its own thread until the machine halts or is
+/// signalled
+pub fn run_parallel() {
+    let threads: Vec<_> = (1..count()).map(|id| std::thread::spawn(move || {
+        select(id);
+        crate::cache::run(&mut crate::cache::DecodeCache::new());
+    })).collect();
+
+    select(0);
+    crate::cache::run(&mut crate::cache::DecodeCache::new());
+
+    for thread in threads {
+        thread.join().expect("Core thread panicked");
+    }
+}
+
+/// Deterministic scheduler running the cores in turn on one thread
+#[derive(Debug)]
+pub struct RoundRobin {
+    /// Instructions a core runs before the next one gets a turn
+    quantum: u32,
+    ran: u32,
+}
+
+impl RoundRobin {
+    pub fn new(quantum: u32) -> Self {
+        assert!(quantum > 0, "Quantum must be at least one instruction");
+
+        Self { quantum, ran: 0 }
+    }
+
+    /// Runs a single instruction on the core whose turn it is
+    pub fn step(&mut self) {
+        if self.ran >= self.quantum || current().halted.load(Ordering::SeqCst) {
+            self.next();
+        }
+
+        crate::step();
+        self.ran += 1;
+    }
+
+    /// Moves on to the next core that is not halted
+    fn next(&mut self) {
+        self.ran = 0;
+
+        for offset in 1..=count() {
+            let id = (id() + offset) % count();
+
+            if !CORES[id].halted.load(Ordering::SeqCst) {
+                select(id);
+                return;
+            }
+        }
+    }
+
+    /// Runs until the machine stops
+    pub fn run(&mut self) {
+        while crate::running() {
+            self.step();
+        }
+    }
+}
+
+impl Default for RoundRobin {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
@@ -0,0 +1,242 @@
+/*
+Interactive debugger, started with `--debug`
+
+| command                            | desc                                           |
+| ---------------------------------- | ---------------------------------------------- |
+| `step [n]`, `s`                    | run n instructions                             |
+| `continue`, `c`                    | run until a breakpoint or halt                 |
+| `reverse-step [n]`, `rs`           | undo n instructions                            |
+| `reverse-continue [addr]`, `rc`    | undo until a breakpoint, or until the last     |
+|                                    | instruction that wrote `addr`                  |
+| `break <addr>`, `b`                | set a breakpoint                               |
+| `delete <addr>`, `d`               | remove a breakpoint                            |
+| `watch <start>[-<end>][:r\|w\|rw]` | stop when a range is read or written           |
+| `unwatch <start>`                  | remove the watchpoints starting at `start`     |
+| `info`, `i`                        | show the instruction pointer, flags and stacks |
+| `x <addr>`                         | show the u16 at an address                     |
+| `quit`, `q`                        | stop the machine                               |
+
+Addresses are hexadecimal, with or without a `0x` prefix. Output already sent
+to devices is not taken back when stepping backwards.
+*/
+
+use std::{collections::BTreeSet, io::{BufRead, Write}};
+
+use crate::{cpu::RoundRobin, history, instructions::ConditionRegister, watch::{self, parse_addr}, MMU};
+
+pub struct Debugger {
+    breakpoints: BTreeSet<u32>,
+    scheduler: RoundRobin,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::with_scheduler(RoundRobin::default())
+    }
+
+    pub fn with_scheduler(scheduler: RoundRobin) -> Self {
+        Self { breakpoints: BTreeSet::new(), scheduler }
+    }
+
+    /// Reads commands from stdin until the machine is told to quit
+    pub fn run(&mut self) {
+        history::start(history::DEFAULT_CAPACITY);
+
+        self.where_am_i();
+
+        let stdin = std::io::stdin();
+        let mut lines = stdin.lock().lines();
+
+        loop {
+            print!("(cute) ");
+            std::io::stdout().flush().expect("Failed to flush stdout");
+
+            let line = match lines.next() {
+                Some(line) => line.expect("Failed to read command"),
+                None => return,
+            };
+
+            let mut words = line.split_whitespace();
+            let command = match words.next() {
+                Some(command) => command,
+                None => continue,
+            };
+            let arg = words.next();
+
+            match command {
+                "step" | "s" => self.step(count(arg)),
+                "continue" | "c" => self.cont(),
+                "reverse-step" | "rs" => self.reverse_step(count(arg)),
+                "reverse-continue" | "rc" => match arg {
+                    Some(addr) => match parse_addr(addr) {
+                        Some(addr) => self.reverse_to_write(addr),
+                        None => println!("Invalid address {}", addr),
+                    },
+                    None => self.reverse_continue(),
+                },
+                "break" | "b" => match arg.and_then(parse_addr) {
+                    Some(addr) => {
+                        self.breakpoints.insert(addr);
+                    },
+                    None => println!("Usage: break <addr>"),
+                },
+                "delete" | "d" => match arg.and_then(parse_addr) {
+                    Some(addr) => {
+                        self.breakpoints.remove(&addr);
+                    },
+                    None => println!("Usage: delete <addr>"),
+                },
+                "watch" => match arg.and_then(watch::Watchpoint::parse) {
+                    Some(watchpoint) => watch::add(watchpoint),
+                    None => println!("Usage: watch <start>[-<end>][:r|w|rw]"),
+                },
+                "unwatch" => match arg.and_then(parse_addr) {
+                    Some(addr) => watch::remove(addr),
+                    None => println!("Usage: unwatch <start>"),
+                },
+                "info" | "i" => info(),
+                "x" => match arg.and_then(parse_addr) {
+                    Some(addr) => match MMU.lock().unwrap().peek(addr, 16) {
+                        Some(value) => println!("0x{:x}: 0x{:04x}", addr, value),
+                        None => println!("0x{:x} is not in memory", addr),
+                    },
+                    None => println!("Usage: x <addr>"),
+                },
+                "quit" | "q" => return,
+                _ => println!("Unknown command {}", command),
+            }
+        }
+    }
+
+    fn step(&mut self, count: usize) {
+        for _ in 0..count {
+            if !crate::running() {
+                stopped();
+                break;
+            }
+
+            self.scheduler.step();
+
+            if let Some(hit) = watch::take_hit() {
+                println!("{}", hit);
+                break;
+            }
+        }
+
+        self.where_am_i();
+    }
+
+    fn cont(&mut self) {
+        while crate::running() {
+            self.scheduler.step();
+
+            if let Some(hit) = watch::take_hit() {
+                println!("{}", hit);
+                break;
+            }
+
+            if self.breakpoints.contains(&(crate::instr_ptr() as u32)) {
+                println!("Breakpoint hit");
+                break;
+            }
+        }
+
+        self.where_am_i();
+    }
+
+    fn reverse_step(&mut self, count: usize) {
+        for _ in 0..count {
+            if history::undo().is_none() {
+                println!("Reached the start of the recorded history");
+                break;
+            }
+        }
+
+        self.where_am_i();
+    }
+
+    fn reverse_continue(&mut self) {
+        loop {
+            match history::undo() {
+                Some(ip) if self.breakpoints.contains(&ip) => {
+                    println!("Breakpoint hit");
+                    break;
+                },
+                Some(_) => (),
+                None => {
+                    println!("Reached the start of the recorded history");
+                    break;
+                },
+            }
+        }
+
+        self.where_am_i();
+    }
+
+    fn reverse_to_write(&mut self, addr: u32) {
+        let base = MMU.lock().unwrap().memory_base;
+
+        let steps = match addr.checked_sub(base) {
+            Some(index) => history::HISTORY.lock().unwrap().last_write(index as usize),
+            None => None,
+        };
+
+        match steps {
+            Some(steps) => {
+                for _ in 0..=steps {
+                    history::undo();
+                }
+
+                println!("Last write to 0x{:x}", addr);
+            },
+            None => println!("No recorded write to 0x{:x}", addr),
+        }
+
+        self.where_am_i();
+    }
+
+    fn where_am_i(&self) {
+        let ip = crate::instr_ptr();
+
+        match crate::instr() {
+            Some(instruction) => println!("0x{:x}: {:?}", ip, instruction),
+            None => println!("0x{:x}: no instruction", ip),
+        }
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn stopped() {
+    match crate::limits::tripped() {
+        Some(limit) => println!("Machine stopped, {}", limit),
+        None => println!("Machine halted"),
+    }
+}
+
+fn info() {
+    if crate::cpu::count() > 1 {
+        println!("Core: {}", crate::cpu::id());
+    }
+    println!("IP: 0x{:x}", crate::instr_ptr());
+    println!("Conditions: {:?}", ConditionRegister::read());
+    println!("Primary {:?}", crate::primary_stack().lock().unwrap());
+    println!("Return {:?}", crate::return_stack().lock().unwrap());
+    println!("Recorded steps: {}", history::HISTORY.lock().unwrap().len());
+
+    let memory = crate::mem();
+    println!("Memory: 0x{:x} of 0x{:x} bytes resident", memory.resident(), memory.size());
+    drop(memory);
+
+    for watchpoint in watch::WATCHPOINTS.lock().unwrap().iter() {
+        println!("Watching {}", watchpoint);
+    }
+}
+
+fn count(arg: Option<&str>) -> usize {
+    arg.and_then(|arg| arg.parse().ok()).unwrap_or(1)
+}
@@ -0,0 +1,175 @@
+/*
+Entry points of the fuzz targets in `fuzz/`, run with `cargo fuzz run execute`
+
+Each takes the raw bytes libFuzzer hands it and panics only when the VM
+itself did, so `tests/fuzz_corpus.rs` replays the checked in corpus without
+cargo-fuzz. Guests run under `INSTRUCTIONS` so none of them can keep the host
+busy.
+
+`execute` calls an image at 0x1600 with a few arguments on the stack
+
+| bytes | desc                                  |
+| ----- | ------------------------------------- |
+| 1     | cores - 1, modulo `cpu::MAX_CORES`    |
+| 1     | quantum - 1                           |
+| 1     | number of arguments                   |
+| 2 * n | 16 bit arguments, bottom first        |
+| rest  | image                                 |
+
+`mmu` makes accesses straight through the MMU
+
+| bytes | desc                                              |
+| ----- | ------------------------------------------------- |
+| 2     | size of `MEM` above 0x1200                        |
+| 8 * n | accesses, see `Access::parse`                     |
+
+`decode` translates an image ahead of time, which decodes every instruction
+reachable from 0x1600. It then loads the image and decodes every address of
+it through the `cache::DecodeCache` the interpreter uses, which has to agree
+with the translation on every instruction both decoded.
+*/
+
+use crate::{
+    aot,
+    asm::LOAD_ADDR,
+    cache::DecodeCache,
+    cpu,
+    harness::{self, Config, ExitStatus, Outcome},
+    limits::Limits,
+    MMU,
+};
+
+/// Instructions a guest gets before it is stopped
+pub const INSTRUCTIONS: u64 = 10_000;
+
+/// Longest image loaded, the rest of the input is ignored
+const MAX_IMAGE: usize = 0x4000;
+
+fn config() -> Config {
+    Config {
+        limits: Limits { instructions: Some(INSTRUCTIONS), ..Limits::default() },
+        ..Config::default()
+    }
+}
+
+fn check(outcome: Outcome) {
+    if let ExitStatus::Panicked(message) = outcome.status {
+        panic!("VM panicked: {}", message);
+    }
+}
+
+pub fn execute(data: &[u8]) {
+    let Some(([cores, quantum, args], rest)) = data.split_first_chunk::<3>() else {
+        return;
+    };
+
+    let (args, image) = rest.split_at((*args as usize * 2).min(rest.len()));
+    let args: Vec<u16> = args.chunks_exact(2).map(|arg| u16::from_le_bytes([arg[0], arg[1]])).collect();
+    let image = &image[..image.len().min(MAX_IMAGE) & !1];
+
+    let config = Config {
+        cores: *cores as usize % cpu::MAX_CORES + 1,
+        quantum: *quantum as u32 + 1,
+        ..config()
+    };
+
+    check(harness::call(image, LOAD_ADDR, &args, &config));
+}
+
+pub fn mmu(data: &[u8]) {
+    let Some((size, accesses)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+
+    // Leaves room for the stacks
+    let config = Config { memory: 0x1200 + u16::from_le_bytes(*size) as usize, ..config() };
+
+    check(harness::with_machine(&[], &config, || {
+        let mmu = MMU.lock().unwrap();
+
+        for access in accesses.chunks_exact(8) {
+            Access::parse(access.try_into().unwrap()).run(&mmu);
+        }
+    }));
+}
+
+pub fn decode(data: &[u8]) {
+    let image = &data[..data.len().min(MAX_IMAGE) & !1];
+
+    aot::translate(image, LOAD_ADDR);
+    let blocks = aot::blocks(image, LOAD_ADDR);
+
+    check(harness::with_machine(image, &config(), || {
+        let mut cache = DecodeCache::new();
+
+        for addr in (LOAD_ADDR..LOAD_ADDR + image.len() as u32).step_by(2) {
+            cache.get(addr);
+        }
+
+        for op in blocks.values().flat_map(|block| block.ops.iter()) {
+            let decoded = cache.get(op.addr).unwrap_or_else(|| panic!("Nothing decoded at 0x{:x}", op.addr));
+
+            assert_eq!(
+                (decoded.instr, decoded.status, decoded.immediate),
+                (op.instr, op.status, op.immediate),
+                "Decoded differently at 0x{:x}", op.addr,
+            );
+        }
+    }));
+}
+
+/// A single access of the `mmu` target
+#[derive(Debug, Clone, Copy)]
+pub struct Access {
+    /// Low 3 bits pick read, write, cas, fadd, swap or peek
+    kind: u8,
+    addr: u32,
+    value: u32,
+    width: u8,
+}
+
+impl Access {
+    /// The first byte is the kind, with bit 4 for 32 bits and bit 5 to keep
+    /// the address below 0x2_0000 where IO and RAM are. Then come a 4 byte
+    /// address and 3 bytes of value.
+    pub fn parse(bytes: [u8; 8]) -> Self {
+        let [kind, addr @ .., v0, v1, v2] = bytes;
+
+        let mut addr = u32::from_le_bytes(addr);
+        if kind & 0x20 != 0 {
+            addr &= 0x1_ffff;
+        }
+
+        Self {
+            kind: kind & 0b111,
+            addr,
+            value: u32::from_le_bytes([v0, v1, v2, 0]),
+            width: if kind & 0x10 != 0 { 32 } else { 16 },
+        }
+    }
+
+    pub fn run(self, mmu: &crate::mmu::MMU) {
+        match (self.kind, self.width) {
+            (0, 32) => {
+                mmu.read_u32(self.addr);
+            },
+            (0, _) => {
+                mmu.read_u16(self.addr);
+            },
+            (1, 32) => mmu.write_u32(self.addr, self.value),
+            (1, _) => mmu.write_u16(self.addr, self.value as u16),
+            (2, width) => {
+                mmu.atomic(self.addr, width, |old| (old == 0).then_some(self.value));
+            },
+            (3, width) => {
+                mmu.atomic(self.addr, width, |old| Some(old.wrapping_add(self.value)));
+            },
+            (4, width) => {
+                mmu.atomic(self.addr, width, |_| Some(self.value));
+            },
+            (_, width) => {
+                mmu.peek(self.addr, width);
+            },
+        }
+    }
+}
@@ -0,0 +1,322 @@
+/*
+Headless runs for tests
+
+`run` loads an image on a freshly reset machine, feeds it input, runs it to
+the end with the round robin scheduler and hands back everything it printed
+along with the state it stopped in. The machine is global, memory, the MMU
+and the cores are shared statics, so runs take a lock and never overlap,
+even between tests running on parallel threads.
+
+    let outcome = harness::run_casm(source, &Config::default())?;
+    assert_eq!(outcome.output, b"hi");
+
+`call` runs a single routine instead: the arguments are pushed on the
+primary stack and the routine entered as if by `jsr`, stopping once it
+returns and the return stack is back to where it started.
+*/
+
+use std::{
+    cell::Cell,
+    panic::AssertUnwindSafe,
+    sync::{atomic::Ordering, mpsc::Receiver, Mutex, Once},
+    thread::JoinHandle,
+};
+
+use crate::{
+    asm::{self, AsmError},
+    cpu, input,
+    instructions::Status,
+    limits::{self, Limit, Limits},
+    machine::{self, Machine},
+    memory::Memory,
+    sanitize::{self, Report},
+    shadow,
+    timing, DeviceSender, IO_SEND, MEM, OUT_PUT_READY, SIGNALLED,
+};
+
+static VM_LOCK: Mutex<()> = Mutex::new(());
+static QUIET_HOOK: Once = Once::new();
+
+thread_local! {
+    /// Set while this thread runs a guest, whose panics end up in the outcome
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Bytes waiting on the input device, which ends after them
+    pub input: Vec<u8>,
+    pub limits: Limits,
+    /// Bytes of `MEM`
+    pub memory: usize,
+    pub cores: usize,
+    /// Instructions each core runs before the next one gets a turn
+    pub quantum: u32,
+    pub machine: Machine,
+    /// Run with the stack sanitizer, see `sanitize`
+    pub sanitize: bool,
+    /// Report uninitialized reads outside of these ranges, see `shadow`
+    pub shadow: Option<Vec<(u32, u32)>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            input: Vec::new(),
+            limits: Limits::default(),
+            memory: 0xFFFF,
+            cores: 1,
+            quantum: 1,
+            machine: Machine::DEFAULT,
+            sanitize: false,
+            shadow: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// Every core ran `halt`
+    Halted,
+    /// The routine given to `call` returned
+    Returned,
+    /// A resource limit stopped the machine
+    Limit(Limit),
+    /// The VM itself panicked, holding the message
+    Panicked(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    /// Bytes written to the output device
+    pub output: Vec<u8>,
+    pub status: ExitStatus,
+    /// 16 bit cells of the primary stack of core 0, bottom first
+    pub primary: Vec<u16>,
+    /// 16 bit cells of the return stack of core 0, bottom first
+    pub ret: Vec<u16>,
+    /// Instructions run over every core
+    pub instructions: u64,
+    /// `MEM` as the run left it, starting at the RAM base
+    pub ram: Memory,
+    /// Problems the stack sanitizer found, when it was enabled
+    pub reports: Vec<Report>,
+    /// Reads of uninitialized RAM, when shadow memory was enabled
+    pub uninitialized: Vec<shadow::Report>,
+}
+
+impl Outcome {
+    pub fn halted(&self) -> bool {
+        self.status == ExitStatus::Halted
+    }
+
+    /// Output as text, for assertions
+    pub fn output_str(&self) -> String {
+        String::from_utf8_lossy(&self.output).into_owned()
+    }
+
+    /// `len` bytes of RAM at MMU address `addr`
+    pub fn memory(&self, addr: u32, len: usize) -> Vec<u8> {
+        self.ram.read_bytes(machine::get().index(addr), len).expect("Range outside of memory")
+    }
+}
+
+/// Assembles `source` and runs it
+pub fn run_casm(source: &str, config: &Config) -> Result<Outcome, AsmError> {
+    let assembly = asm::assemble_at("input.casm", source, config.machine.program.load)?;
+
+    Ok(run(&assembly.image, config))
+}
+
+/// Runs `image` from the entry point until it halts, trips a limit or panics
+pub fn run(image: &[u8], config: &Config) -> Outcome {
+    execute(image, config, || {
+        cpu::RoundRobin::new(config.quantum).run();
+        false
+    })
+}
+
+/// Calls the routine at `addr` on core 0 with `args` pushed bottom first,
+/// until it returns, halts, trips a limit or panics
+///
+/// The return address `jsr$r` leaves on the primary stack is dropped, as a
+/// caller would.
+pub fn call(image: &[u8], addr: u32, args: &[u16], config: &Config) -> Outcome {
+    execute(image, config, || {
+        let core = &cpu::CORES[0];
+        let baseline = core.ret.lock().unwrap().top();
+
+        for arg in args {
+            core.primary.lock().unwrap().push(*arg as u32, Status::NONE);
+        }
+
+        // Returning to 0 would fault, but the run stops before that
+        core.ret.lock().unwrap().push(0, Status::SHORT);
+        crate::set_instr_ptr(addr);
+
+        let mut scheduler = cpu::RoundRobin::new(config.quantum);
+        while crate::running() && core.ret.lock().unwrap().top() > baseline {
+            scheduler.step();
+        }
+
+        let returned = core.ret.lock().unwrap().top() <= baseline;
+        if returned {
+            core.primary.lock().unwrap().pop(Status::SHORT);
+        }
+
+        returned
+    })
+}
+
+/// `call` on a label of an assembly, None when there is no such label
+pub fn call_label(assembly: &asm::Assembly, label: &str, args: &[u16], config: &Config) -> Option<Outcome> {
+    let addr = assembly.debug.symbols.find(label)?;
+
+    Some(call(&assembly.image, addr, args, config))
+}
+
+/// Loads `image` and runs `body` instead of the scheduler, for poking at the
+/// machine directly
+pub fn with_machine(image: &[u8], config: &Config, body: impl FnOnce()) -> Outcome {
+    execute(image, config, || {
+        body();
+        false
+    })
+}
+
+/// Loads `image` and hands over to `body`, which returns whether a called
+/// routine returned
+fn execute(image: &[u8], config: &Config, body: impl FnOnce() -> bool) -> Outcome {
+    let _vm = VM_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    // Instructions are only counted while some limit is enforced
+    let limits = Limits { instructions: config.limits.instructions.or(Some(u64::MAX)), ..config.limits };
+
+    cpu::set_count(config.cores);
+    machine::set(config.machine);
+    limits::start(limits);
+    timing::CLOCK_HZ.store(0, Ordering::Relaxed);
+    SIGNALLED.store(false, Ordering::Relaxed);
+    *crate::DEBUG_INFO.lock().unwrap() = None;
+
+    if config.sanitize {
+        sanitize::start();
+    }
+
+    if let Some(allow) = &config.shadow {
+        shadow::start(allow.clone());
+    }
+
+    input::reset();
+    input::feed(&config.input);
+    input::end();
+
+    let output = capture();
+
+    quiet_panics();
+    QUIET.with(|quiet| quiet.set(true));
+
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        if limits::memory(config.memory) {
+            crate::load_image(image, config.memory);
+            body()
+        } else {
+            false
+        }
+    }));
+
+    QUIET.with(|quiet| quiet.set(false));
+    recover();
+
+    let status = match result {
+        Err(panic) => ExitStatus::Panicked(panic_message(&*panic)),
+        Ok(returned) => match limits::tripped() {
+            Some(limit) => ExitStatus::Limit(limit),
+            None if returned => ExitStatus::Returned,
+            None => ExitStatus::Halted,
+        },
+    };
+
+    let (primary, ret) = {
+        let core = &cpu::CORES[0];
+        (cells(&core.primary), cells(&core.ret))
+    };
+
+    let ram = std::mem::take(&mut *MEM.write().unwrap());
+
+    let instructions = limits::instructions();
+    limits::start(Limits::default());
+
+    let reports = if config.sanitize { sanitize::finish() } else { Vec::new() };
+    let uninitialized = if config.shadow.is_some() { shadow::finish() } else { Vec::new() };
+
+    // Dropping the sender lets the capture thread finish
+    *IO_SEND.lock().unwrap() = DeviceSender::new();
+    let output = output.join().expect("Output capture panicked");
+
+    Outcome { output, status, primary, ret, instructions, ram, reports, uninitialized }
+}
+
+/// Collects output on a thread standing in for the terminal
+fn capture() -> JoinHandle<Vec<u8>> {
+    let (tx, rx) = std::sync::mpsc::channel::<u8>();
+
+    IO_SEND.lock().unwrap().update(tx);
+    OUT_PUT_READY.store(true, Ordering::Relaxed);
+
+    std::thread::spawn(move || collect(rx))
+}
+
+fn collect(receiver: Receiver<u8>) -> Vec<u8> {
+    let mut output = Vec::new();
+
+    while let Ok(byte) = receiver.recv() {
+        output.push(byte);
+        OUT_PUT_READY.store(true, Ordering::Relaxed);
+    }
+
+    output
+}
+
+/// Keeps the panic hook from printing panics of guests being run
+fn quiet_panics() {
+    QUIET_HOOK.call_once(|| {
+        let hook = std::panic::take_hook();
+
+        std::panic::set_hook(Box::new(move |info| {
+            if !QUIET.with(|quiet| quiet.get()) {
+                hook(info);
+            }
+        }));
+    });
+}
+
+/// Clears the poison a panic leaves on the locks of the machine
+fn recover() {
+    crate::MMU.clear_poison();
+
+    for core in cpu::CORES.iter() {
+        core.primary.clear_poison();
+        core.ret.clear_poison();
+        core.sic.clear_poison();
+    }
+
+    // A panic can leave the output device waiting forever
+    OUT_PUT_READY.store(true, Ordering::Relaxed);
+}
+
+fn cells(stack: &Mutex<crate::stack::Stack>) -> Vec<u16> {
+    let stack = stack.lock().unwrap();
+
+    (0..stack.top()).step_by(2).map(|index| stack.copy(index, Status::NONE) as u16).collect()
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
@@ -0,0 +1,151 @@
+use std::{collections::VecDeque, sync::{atomic::{AtomicBool, Ordering}, Mutex}};
+
+use crate::{cpu, sic::Sic, HALTED};
+
+pub static HISTORY: Mutex<History> = Mutex::new(History::new());
+pub static RECORDING: AtomicBool = AtomicBool::new(false);
+
+/// Steps kept before the oldest ones are forgotten
+pub const DEFAULT_CAPACITY: usize = 1_000_000;
+
+/// Everything needed to undo one step of the machine
+pub struct Entry {
+    pub ip: u32,
+    /// Core that ran the step
+    pub core: usize,
+    stacks: [(u32, u16); 2],
+    sic: Sic,
+    interrupt: bool,
+    ipi: bool,
+    /// Whether the core and the machine were halted
+    halted: (bool, bool),
+    /// `MEM` indices written during the step and the byte they held before
+    pub writes: Vec<(usize, u8)>,
+}
+
+pub struct History {
+    entries: VecDeque<Entry>,
+    capacity: usize,
+}
+
+impl History {
+    pub const fn new() -> Self {
+        Self { entries: VecDeque::new(), capacity: DEFAULT_CAPACITY }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+
+        while self.entries.len() > capacity {
+            self.entries.pop_front();
+        }
+    }
+
+    /// The newest step that wrote `index` in `MEM`, counted back from the
+    /// most recent step
+    pub fn last_write(&self, index: usize) -> Option<usize> {
+        self.entries.iter().rev().position(|entry| {
+            entry.writes.iter().any(|(addr, _)| *addr == index)
+        })
+    }
+
+    pub fn last(&self) -> Option<&Entry> {
+        self.entries.back()
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn start(capacity: usize) {
+    let mut history = HISTORY.lock().unwrap();
+    history.entries.clear();
+    history.set_capacity(capacity);
+
+    RECORDING.store(true, Ordering::Relaxed);
+}
+
+/// Saves the state that is not kept in memory before a step runs
+pub fn begin(ip: u32) {
+    if !RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let core = cpu::current();
+
+    let stacks = [&core.primary, &core.ret].map(|stack| {
+        let stack = stack.lock().unwrap();
+        (stack.location(), stack.offset())
+    });
+
+    let sic = core.sic.lock().unwrap().clone();
+
+    let entry = Entry {
+        ip,
+        core: cpu::id(),
+        stacks,
+        sic,
+        interrupt: core.interrupt.load(Ordering::Relaxed),
+        ipi: core.ipi.load(Ordering::Relaxed),
+        halted: (core.halted.load(Ordering::Relaxed), HALTED.load(Ordering::Relaxed)),
+        writes: Vec::new(),
+    };
+
+    let mut history = HISTORY.lock().unwrap();
+    if history.entries.len() == history.capacity {
+        history.entries.pop_front();
+    }
+    history.entries.push_back(entry);
+}
+
+/// Called by `Memory` before a byte is overwritten
+pub fn record_write(index: usize, old: u8) {
+    if !RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Some(entry) = HISTORY.lock().unwrap().entries.back_mut() {
+        entry.writes.push((index, old));
+    }
+}
+
+/// Undoes the most recent step, returns the instruction pointer it started at
+pub fn undo() -> Option<u32> {
+    let entry = HISTORY.lock().unwrap().entries.pop_back()?;
+
+    for (index, old) in entry.writes.iter().rev() {
+        crate::mem().restore(*index, *old).expect("Recorded write outside of memory");
+    }
+
+    cpu::select(entry.core);
+    let core = cpu::current();
+
+    for (stack, (location, offset)) in [&core.primary, &core.ret].into_iter().zip(entry.stacks) {
+        let mut stack = stack.lock().unwrap();
+
+        unsafe {
+            stack.set_pos(location);
+            stack.set_offset(offset);
+        }
+    }
+
+    *core.sic.lock().unwrap() = entry.sic;
+
+    core.interrupt.store(entry.interrupt, Ordering::Relaxed);
+    core.ipi.store(entry.ipi, Ordering::Relaxed);
+    core.halted.store(entry.halted.0, Ordering::Relaxed);
+    HALTED.store(entry.halted.1, Ordering::Relaxed);
+
+    Some(entry.ip)
+}
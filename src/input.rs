@@ -0,0 +1,75 @@
+/*
+Input device
+
+| IO    | access | desc                                                 |
+| ----- | ------ | ---------------------------------------------------- |
+| 0x104 | read   | next input byte, 0xffff when none is waiting         |
+| 0x106 | read   | 1 once the input has ended and every byte was read   |
+*/
+
+use std::{collections::VecDeque, io::Read, sync::Mutex};
+
+pub static INPUT: Mutex<Input> = Mutex::new(Input::new());
+
+#[derive(Debug)]
+pub struct Input {
+    bytes: VecDeque<u8>,
+    ended: bool,
+}
+
+impl Input {
+    pub const fn new() -> Self {
+        Self { bytes: VecDeque::new(), ended: false }
+    }
+}
+
+impl Default for Input {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Empties the device, more input can be fed in afterwards
+pub fn reset() {
+    *INPUT.lock().unwrap() = Input::new();
+}
+
+pub fn feed(bytes: &[u8]) {
+    INPUT.lock().unwrap().bytes.extend(bytes);
+}
+
+/// Marks the end of the input, after the bytes already fed in
+pub fn end() {
+    INPUT.lock().unwrap().ended = true;
+}
+
+/// Takes the next byte, or 0xffff when there is none
+pub fn read() -> u16 {
+    match INPUT.lock().unwrap().bytes.pop_front() {
+        Some(byte) => byte as u16,
+        None => 0xffff,
+    }
+}
+
+pub fn ended() -> bool {
+    let input = INPUT.lock().unwrap();
+
+    input.ended && input.bytes.is_empty()
+}
+
+/// Feeds the process stdin to the device from a thread of its own
+pub fn spawn_stdin() {
+    std::thread::spawn(|| {
+        let mut stdin = std::io::stdin();
+        let mut buf = [0; 256];
+
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => feed(&buf[..len]),
+            }
+        }
+
+        end();
+    });
+}
@@ -18,12 +18,20 @@
 | 0b1101 | `div`  | ( val2 val1 -- val1 / val2)  | divide values                   |
 
 | 0b1111 | `halt` |                              | Halt the machine                |
+
+| 0x10   | `cas`  | ( new expected addr -- old ) | compare and swap, EQUAL if set  |
+| 0x11   | `fadd` | ( value addr -- old )        | add to memory                   |
+| 0x12   | `swap` | ( value addr -- old )        | exchange with memory            |
+
+The last three read and write memory under a single lock of the MMU, so no
+other core or device sees the value in between. `SHORT` makes them work on
+32 bits instead of 16.
 */
 
 use num_derive::FromPrimitive;
-use crate::{MEM, pop, push, instr_ptr, set_instr_ptr, offset_instr_ptr, MMU};
+use crate::{pop, push, instr_ptr, set_instr_ptr, offset_instr_ptr, MMU};
 
-#[derive(FromPrimitive, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[derive(FromPrimitive, PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
 pub enum Instr {
     Nop,
     Lit,
@@ -39,7 +47,10 @@ pub enum Instr {
     Sub,
     Mul,
     Div,
-    Halt
+    Halt = 0xf,
+    Cas,
+    Fadd,
+    Swap,
 }
 
 bitflags::bitflags! {
@@ -64,56 +75,138 @@ bitflags::bitflags! {
 
 impl Instr {
     pub fn read_instr() -> Instr {
-        let instr_ptr = unsafe {super::MEM.read_u16(0x400)};
-        let instr = unsafe {super::MEM.read_u16(instr_ptr as usize)};
+        let memory = crate::mem();
+        let instr_ptr = memory.read_u16(0x400).expect("No memory at 0x400");
+        let instr = memory.read_u16(instr_ptr as usize).expect("Instruction pointer outside of memory");
 
         num::FromPrimitive::from_u16(instr).expect("Invalid opcode")
     }
 
     pub fn from_byte(byte: u8) -> Self {
-        match byte {
-            0 => Self::Nop,
-            1 => Self::Lit,
-            2 => Self::Dup,
-            3 => Self::Over,
-            4 => Self::Str,
-            5 => Self::Load,
-            6 => Self::Push,
-            7 => Self::Drop,
-            8 => Self::Jsr,
-            9 => Self::Cmp,
-            10 => Self::Add,
-            11 => Self::Sub,
-            12 => Self::Mul,
-            13 => Self::Div,
-            0xf => Self::Halt,
-            _ => panic!("Invalid instruction 0b{:b} at address 0x{:x}", byte, instr_ptr())
-        }
+        Self::decode(byte).unwrap_or_else(|| panic!("Invalid instruction 0b{:b} at address 0x{:x}", byte, instr_ptr()))
     }
 
-    pub fn execute(&self, flags: Status) {
-        let instr_ptr = instr_ptr();
+    /// The instruction with opcode `byte`, if there is one
+    pub fn decode(byte: u8) -> Option<Self> {
+        num::FromPrimitive::from_u8(byte)
+    }
 
-        //println!("Instruction {:?}\nIP 0x{:x}", self, instr_ptr);
+    pub fn execute(&self, flags: Status) {
+        //println!("Instruction {:?}\nIP 0x{:x}", self, instr_ptr());
         match self {
-            Instr::Nop => (),
             Instr::Lit => {
-                let data: u32;
-                if flags.contains(Status::SHORT) {
-                    data = MMU.lock().unwrap().read_u32((instr_ptr + 2) as u32);
+                let instr_ptr = instr_ptr();
+                let data = if flags.contains(Status::SHORT) {
+                    MMU.lock().unwrap().read_u32((instr_ptr + 2) as u32)
                 } else {
-                    data = MMU.lock().unwrap().read_u16((instr_ptr + 2) as u32) as u32;
-                }
+                    MMU.lock().unwrap().read_u16((instr_ptr + 2) as u32) as u32
+                };
 
                 log::info!("Loading immediate: 0x{:x} from 0x{:x}", data, instr_ptr + 2);
 
-                crate::push(data as u32, flags);
+                crate::push(data, flags);
+            },
+            Instr::Str => {
+                let store_addr = pop(flags | Status::SHORT);
+                let data = pop(flags);
+
+                log::info!("Storing 0x{:x} at address 0x{:x}", data, store_addr);
+
+                match flags.contains(Status::SHORT) {
+                    true => {
+                        MMU.lock().unwrap().write_u32(store_addr, data);
+                    },
+                    false => {
+                        MMU.lock().unwrap().write_u16(store_addr, data as u16);
+                    }
+                }
+            },
+            Instr::Load => {
+                let store_addr = pop(flags | Status::SHORT);
+
+                // The MMU has to be released before the push, which goes
+                // through it
+                let value = match flags.contains(Status::SHORT) {
+                    true => MMU.lock().unwrap().read_u32(store_addr),
+                    false => MMU.lock().unwrap().read_u16(store_addr) as u32,
+                };
+
+                push(value, flags);
+            },
+            Instr::Jsr => {
+                let old_ptr = (instr_ptr() + 2) as u32;
+
+                // The target comes off one stack and the return address goes
+                // on the other
+                let (from, to) = if flags.contains(Status::RETURN) {
+                    (Status::RETURN | Status::SHORT, Status::SHORT)
+                } else {
+                    (Status::SHORT, Status::RETURN | Status::SHORT)
+                };
+
+                let target = pop(from);
+
+                // Faults on the jsr itself, which is where the handler returns to
+                if target & 0b1 != 0 {
+                    log::warn!("Jump to unaligned address 0x{:x}", target);
+                    crate::int_controller().lock().unwrap().gen_int(crate::sic::ILLEGAL_INSTRUCTION, true);
+                    return;
+                }
+
+                push(old_ptr, to);
+                set_instr_ptr(target)
+            },
+            Instr::Cas => {
+                let addr = pop(flags | Status::SHORT);
+                let expected = pop(flags);
+                let new = pop(flags);
+
+                let old = MMU.lock().unwrap().atomic(addr, width(flags), |old| (old == expected).then_some(new));
+
+                if old == expected {
+                    ConditionRegister::EQUAL.add();
+                } else {
+                    ConditionRegister::EQUAL.clear();
+                }
+
+                push(old, flags);
+            },
+            Instr::Fadd => {
+                let addr = pop(flags | Status::SHORT);
+                let value = pop(flags);
+
+                let old = MMU.lock().unwrap().atomic(addr, width(flags), |old| Some(old.wrapping_add(value)));
+
+                push(old, flags);
+            },
+            Instr::Swap => {
+                let addr = pop(flags | Status::SHORT);
+                let value = pop(flags);
+
+                let old = MMU.lock().unwrap().atomic(addr, width(flags), |_| Some(value));
+
+                push(old, flags);
+            },
+            Instr::Halt => {
+                log::info!("VM Halting");
+                crate::cpu::halt();
             },
+            _ => {
+                self.execute_on(&mut CoreStacks, flags);
+            },
+        }
+    }
+
+    /// Runs an instruction that only works on the stacks and the condition
+    /// register, returns false for every other instruction
+    pub fn execute_on(&self, stacks: &mut impl Stacks, flags: Status) -> bool {
+        match self {
+            Instr::Nop => (),
             Instr::Dup => {
                 let mut tmpflags = flags;
                 tmpflags |= Status::KEEP;
 
-                let data = pop(tmpflags);
+                let data = stacks.pop(tmpflags);
 
                 if tmpflags.contains(Status::RETURN) {
                     tmpflags.set(Status::RETURN, false);
@@ -121,7 +214,7 @@ impl Instr {
                     tmpflags |= Status::RETURN;
                 }
 
-                push(data, tmpflags);
+                stacks.push(data, tmpflags);
             },
             Instr::Over => {
                 let mut tmpflags = flags;
@@ -130,9 +223,9 @@ impl Instr {
 
                 let mut pop_buf = [0; 3];
 
-                pop_buf[0] = pop(tmpflags);
-                pop_buf[1] = pop(tmpflags);
-                pop_buf[2] = pop(tmpflags);
+                pop_buf[0] = stacks.pop(tmpflags);
+                pop_buf[1] = stacks.pop(tmpflags);
+                pop_buf[2] = stacks.pop(tmpflags);
 
                 let top = pop_buf[2];
                 let bottom = pop_buf[0];
@@ -140,71 +233,29 @@ impl Instr {
                 pop_buf[0] = top;
                 pop_buf[2] = bottom;
 
-                push(pop_buf[2], tmpflags);
-                push(pop_buf[1], tmpflags);
-                push(pop_buf[0], tmpflags);
-            },
-            Instr::Str => {
-                let store_addr = pop(flags | Status::SHORT);
-                let data = pop(flags);
-
-                log::info!("Storing 0x{:x} at address 0x{:x}", data, store_addr);
-
-                match flags.contains(Status::SHORT) {
-                    true => {
-                        MMU.lock().unwrap().write_u32(store_addr, data);
-                    },
-                    false => {
-                        MMU.lock().unwrap().write_u16(store_addr, data as u16);
-                    }
-                }
-            },
-            Instr::Load => {
-                let store_addr = pop(flags | Status::SHORT);
-
-                match flags.contains(Status::SHORT) {
-                    true => {
-                        push(MMU.lock().unwrap().read_u32(store_addr), flags);
-                    },
-                    false => {
-                        push(MMU.lock().unwrap().read_u16(store_addr) as u32, flags);
-                    }
-                }
+                stacks.push(pop_buf[2], tmpflags);
+                stacks.push(pop_buf[1], tmpflags);
+                stacks.push(pop_buf[0], tmpflags);
             },
             Instr::Push => {
-                let value = pop(flags);
+                let value = stacks.pop(flags);
 
-                push(value, flags);
+                stacks.push(value, flags);
             },
             Instr::Drop => {
                 let mut tmp_flags = flags;
                 tmp_flags.set(Status::KEEP, false);
 
-                pop(flags);
-            },
-            Instr::Jsr => {
-                let old_ptr = (instr_ptr + 2) as u32;
-
-                if flags.contains(Status::RETURN) {
-                    let flag = Status::SHORT;
-                    push(old_ptr, flag);
-
-                    set_instr_ptr(pop(flag | Status::RETURN))
-                } else {
-                    let flag = Status::RETURN | Status::SHORT;
-                    push(old_ptr, flag);
-
-                    set_instr_ptr(pop(flag - Status::RETURN))
-                }
+                stacks.pop(flags);
             },
             Instr::Cmp => {
                 let mut condition_register = ConditionRegister::empty();
 
-                let val1 = pop(flags);
-                let val2 = pop(flags);
+                let val1 = stacks.pop(flags);
+                let val2 = stacks.pop(flags);
 
                 let order = val1.cmp(&val2);
-                println!("{val1} is {:?} compared to {val2}", order);
+                log::debug!("{val1} is {:?} compared to {val2}", order);
 
                 if val1 == val2 {
                     condition_register |= ConditionRegister::EQUAL;
@@ -219,39 +270,64 @@ impl Instr {
                 condition_register.write();
             },
             Instr::Add => {
-                let val2 = pop(flags);
-                let val1 = pop(flags);
+                let val2 = stacks.pop(flags);
+                let val1 = stacks.pop(flags);
 
-                push(val1 + val2, flags);
+                stacks.push(val1.wrapping_add(val2), flags);
             },
             Instr::Sub => {
-                let val2 = pop(flags);
-                let val1 = pop(flags);
+                let val2 = stacks.pop(flags);
+                let val1 = stacks.pop(flags);
 
-                push(val1 - val2, flags);
+                stacks.push(val1.wrapping_sub(val2), flags);
             },
             Instr::Mul => {
-                let val2 = pop(flags);
-                let val1 = pop(flags);
+                let val2 = stacks.pop(flags);
+                let val1 = stacks.pop(flags);
 
-                push(val1 * val2, flags);
+                stacks.push(val1.wrapping_mul(val2), flags);
             },
             Instr::Div => {
-                let val2 = pop(flags);
-                let val1 = pop(flags);
+                let val2 = stacks.pop(flags);
+                let val1 = stacks.pop(flags);
 
-                push(val1 / val2, flags);
+                // Both operands are gone and nothing is pushed
+                match val1.checked_div(val2) {
+                    Some(quotient) => stacks.push(quotient, flags),
+                    None => crate::int_controller().lock().unwrap().gen_int(crate::sic::DIVIDE_BY_ZERO, true),
+                }
             },
-            Instr::Halt => {
-                use std::sync::atomic::Ordering;
-                log::info!("VM Halting");
-                crate::HALTED.store(true, Ordering::Relaxed);
-                std::process::exit(0);
-            }
+            _ => return false,
         }
+
+        true
     }
 }
 
+/// The two stacks of a core as an instruction sees them
+pub trait Stacks {
+    fn push(&mut self, data: u32, flags: Status);
+    fn pop(&mut self, flags: Status) -> u32;
+}
+
+/// Stacks of the current core, locked for every access
+pub struct CoreStacks;
+
+impl Stacks for CoreStacks {
+    fn push(&mut self, data: u32, flags: Status) {
+        push(data, flags);
+    }
+
+    fn pop(&mut self, flags: Status) -> u32 {
+        pop(flags)
+    }
+}
+
+/// Bits of memory an instruction works on
+fn width(flags: Status) -> u8 {
+    if flags.contains(Status::SHORT) { 32 } else { 16 }
+}
+
 #[derive(Debug)]
 pub struct Instruction(Instr, Status);
 
@@ -259,27 +335,39 @@ impl Instruction {
     pub const fn new(instr: Instr, status: Status) -> Self {
         Instruction(instr, status)
     }
-    
-    pub fn execute(&self) {
-        let conditions = ConditionRegister::read();
 
-        let flags = self.1;
+    /// Decodes the instruction at `ip`, None when it is misaligned, outside
+    /// of RAM or not an instruction
+    pub fn fetch(ip: u32) -> Option<Self> {
+        if ip & 0b1 != 0 {
+            return None;
+        }
 
-        if flags.contains(Status::IF_EQUAL) && conditions.contains(ConditionRegister::EQUAL) {
-            //println!("Executing {:?}", self);
-            self.0.execute(self.1);
-        } else if flags.contains(Status::IF_GREATER) && conditions.contains(ConditionRegister::GREATER) {
-            //println!("Executing {:?}", self);
-            self.0.execute(self.1);
-        } else if flags.contains(Status::IF_LESS) && conditions.contains(ConditionRegister::LESS) {
-            //println!("Executing {:?}", self);
-            self.0.execute(self.1);
-        } else if !(flags.contains(Status::IF_GREATER) | flags.contains(Status::IF_LESS) | flags.contains(Status::IF_EQUAL)) {
+        let binary = (MMU.lock().unwrap().peek(ip, 16)? as u16).to_le_bytes();
+
+        Some(Self::new(Instr::decode(binary[0])?, Status::from_bits(binary[1])?))
+    }
+
+    pub fn instr(&self) -> &Instr {
+        &self.0
+    }
+
+    pub fn status(&self) -> Status {
+        self.1
+    }
+
+    /// Runs the instruction and advances the instruction pointer, returns
+    /// whether the condition flags let it execute
+    pub fn execute(&self) -> bool {
+        let run = self.1.condition_met(ConditionRegister::read());
+
+        if run {
             //println!("Executing {:?}", self);
             self.0.execute(self.1);
         }
 
-        if self.0 != Instr::Jsr {
+        // A jsr that ran has already moved the instruction pointer
+        if self.0 != Instr::Jsr || !run {
             offset_instr_ptr(2);
         }
 
@@ -290,53 +378,50 @@ impl Instruction {
                 offset_instr_ptr(2);
             }
         }
+
+        crate::timing::retire(self.0, run);
+
+        run
     }
 }
 
-impl ConditionRegister {
-    pub fn read() -> Self {
-        let state = unsafe {
-            MEM.read_u16(0x204)
-        };
+impl Status {
+    /// Whether an instruction with these flags runs under `conditions`
+    pub fn condition_met(self, conditions: ConditionRegister) -> bool {
+        (self.contains(Status::IF_EQUAL) && conditions.contains(ConditionRegister::EQUAL))
+            || (self.contains(Status::IF_GREATER) && conditions.contains(ConditionRegister::GREATER))
+            || (self.contains(Status::IF_LESS) && conditions.contains(ConditionRegister::LESS))
+            || !self.intersects(Status::IF_EQUAL | Status::IF_GREATER | Status::IF_LESS)
+    }
+}
 
-        Self::from_bits(state).unwrap()
+impl ConditionRegister {
+    fn load() -> u16 {
+        crate::mem().read_u16(crate::cpu::cond_index()).expect("Condition register outside of memory")
     }
 
-    pub fn add(&self) {
-        let start = unsafe {
-            MEM.read_u16(0x204)
-        };
+    fn store(state: u16) {
+        crate::mem().write_u16(crate::cpu::cond_index(), state).expect("Condition register outside of memory")
+    }
 
-        let mask = start | self.bits();
+    pub fn read() -> Self {
+        // The guest can write anything here
+        Self::from_bits_truncate(Self::load())
+    }
 
-        unsafe {
-            MEM.write_u16(0x204, mask)
-        }
+    pub fn add(&self) {
+        Self::store(Self::load() | self.bits());
     }
 
     pub fn clear(&self) {
-        let start = unsafe {
-            MEM.read_u16(0x204)
-        };
-
-        let mask = start & !self.bits();
-
-        unsafe {
-            MEM.write_u16(0x204, mask)
-        }
+        Self::store(Self::load() & !self.bits());
     }
 
     pub fn reset() {
-        unsafe {
-            MEM.write_u16(0x204, 0);
-        }
+        Self::store(0);
     }
 
     pub fn write(&self) {
-        let state = self.bits();
-
-        unsafe {
-            MEM.write_u16(0x204, state);
-        }
+        Self::store(self.bits());
     }
 }
\ No newline at end of file
@@ -5,62 +5,127 @@ pub mod memory;
 pub mod instructions;
 pub mod sic;
 pub mod mmu;
+pub mod snapshot;
+pub mod trace;
+pub mod history;
+pub mod debugger;
+pub mod watch;
+pub mod symbols;
+pub mod profile;
+pub mod asm;
+pub mod coverage;
+pub mod cache;
+pub mod aot;
+pub mod cpu;
+pub mod timing;
+pub mod limits;
+pub mod input;
+pub mod harness;
+pub mod unit;
+pub mod fuzz;
+pub mod machine;
+pub mod sanitize;
+pub mod shadow;
 
 use self::{memory::Memory, stack::Stack};
 
-use std::{sync::{Mutex, mpsc::{Sender, Receiver, SendError}, atomic::AtomicBool}, io::Write};
-pub static mut MEM: Memory = Memory::null();
-pub static MMU: Mutex<MMU> = Mutex::new(MMU::new(0, 0xfff, 0x1000, 0xffff_ffff));
-pub static PRIMARY_STACK: Mutex<Stack> = Mutex::new(Stack::new(0x10ff));
-pub static RETURN_STACK: Mutex<Stack> = Mutex::new(Stack::new(0x11ff));
-pub static INTERRUPT: Mutex<bool> = Mutex::new(false);
-pub static INT_CONTROLLER: Mutex<sic::Sic> = Mutex::new(sic::Sic::new());
+use std::{sync::{Mutex, RwLock, RwLockReadGuard, mpsc::{Sender, Receiver, SendError}, atomic::AtomicBool}, io::Write};
+/// RAM, mapped by the MMU at 0x1000. The write lock is only taken to replace
+/// it, everything else goes through `mem`
+pub static MEM: RwLock<Memory> = RwLock::new(Memory::null());
+pub static MMU: Mutex<MMU> = Mutex::new(default_mmu());
 
 pub static HALTED: AtomicBool = AtomicBool::new(false);
 pub static OUT_PUT_READY: AtomicBool = AtomicBool::new(false);
+pub static SIGNALLED: AtomicBool = AtomicBool::new(false);
 
-pub static mut IO_SEND: DeviceSender<u8> = DeviceSender::new();
+pub static IO_SEND: Mutex<DeviceSender<u8>> = Mutex::new(DeviceSender::new());
+
+/// Labels and source lines of the loaded program, when it was assembled from casm
+pub static DEBUG_INFO: Mutex<Option<asm::DebugInfo>> = Mutex::new(None);
+
+/// Shared view of `MEM`
+pub fn mem() -> RwLockReadGuard<'static, Memory> {
+    MEM.read().unwrap()
+}
+
+/// Most RAM there is room for, from 0x1000 to the end of the address space
+pub const MAX_MEMORY: usize = 0x1_0000_0000 - 0x1000;
+
+const fn default_mmu() -> MMU {
+    MMU::new(0, 0xfff, 0x1000, 0xffff_ffff)
+}
+
+/// MMU for `memory` bytes of RAM laid out like `machine`
+fn mmu_for(machine: &machine::Machine, memory: usize) -> MMU {
+    let map = machine.memory;
+
+    MMU {
+        devices: machine.devices,
+        ..MMU::new(map.io_base, map.io_max, map.ram_base, map.ram_base + (memory - 1) as u32)
+    }
+}
+
+/// Primary stack of the current core
+pub fn primary_stack() -> &'static Mutex<Stack> {
+    &cpu::current().primary
+}
+
+/// Return stack of the current core
+pub fn return_stack() -> &'static Mutex<Stack> {
+    &cpu::current().ret
+}
+
+/// Interrupt controller of the current core
+pub fn int_controller() -> &'static Mutex<sic::Sic> {
+    &cpu::current().sic
+}
+
+/// Set when the current core has an interrupt to take before its next
+/// instruction
+pub fn interrupt() -> &'static AtomicBool {
+    &cpu::current().interrupt
+}
 
 pub fn push(data: u32, flags: Status) {
     if flags.contains(Status::RETURN) {
-        RETURN_STACK.lock().unwrap().push(data, flags);
+        return_stack().lock().unwrap().push(data, flags);
     } else {
-        PRIMARY_STACK.lock().unwrap().push(data, flags);
+        primary_stack().lock().unwrap().push(data, flags);
     }
 }
 
 pub fn pop(flags: Status) -> u32 {
     if flags.contains(Status::RETURN) {
-        return RETURN_STACK.lock().unwrap().pop(flags);
+        return return_stack().lock().unwrap().pop(flags);
     } else {
-        return PRIMARY_STACK.lock().unwrap().pop(flags);
+        return primary_stack().lock().unwrap().pop(flags);
     }
 }
 
 pub fn copy(index: usize, flags: Status) -> u32 {
     if flags.contains(Status::RETURN) {
-        return RETURN_STACK.lock().unwrap().copy(index, flags);
+        return return_stack().lock().unwrap().copy(index, flags);
     } else {
-        return PRIMARY_STACK.lock().unwrap().copy(index, flags);
+        return primary_stack().lock().unwrap().copy(index, flags);
     }
 }
 
 pub fn top(ret_stack: bool) -> usize {
     if ret_stack {
-        return RETURN_STACK.lock().unwrap().top();
+        return return_stack().lock().unwrap().top();
     } else {
-        return PRIMARY_STACK.lock().unwrap().top();
+        return primary_stack().lock().unwrap().top();
     }
 }
 
 pub fn instr_ptr() -> usize {
-    MMU.try_lock().unwrap().read_u32(0x1200) as usize
+    trace::untraced(|| MMU.lock().unwrap().read_u32(cpu::ip_addr()) as usize)
 }
 
+/// Moves the instruction pointer, an odd one faults on the next fetch
 pub fn set_instr_ptr(ip: u32) {
-    assert!(ip & 0b1 == 0, "Instruction pointer unaligned");
-
-    MMU.lock().unwrap().write_u32(0x1200, ip);
+    trace::untraced(|| MMU.lock().unwrap().write_u32(cpu::ip_addr(), ip));
 }
 
 pub fn offset_instr_ptr(offset: isize) {
@@ -70,78 +135,419 @@ pub fn offset_instr_ptr(offset: isize) {
     set_instr_ptr((ip + offset) as u32);
 }
 
-pub fn instr() -> instructions::Instruction {
-    let binary = unsafe {
-        log::info!("Instrptr: 0x{:x}", instr_ptr());
-        MEM.read_u16(instr_ptr() - 0x1000).to_le_bytes()
+/// Whether the machine should keep running, it stops once halted, signalled
+/// or out of a resource limit
+pub fn running() -> bool {
+    use std::sync::atomic::Ordering;
+
+    !HALTED.load(Ordering::Relaxed) && !SIGNALLED.load(Ordering::Relaxed) && !limits::exceeded()
+}
+
+/// Runs a single instruction, taking any pending interrupt first
+pub fn step() {
+    use std::sync::atomic::Ordering;
+
+    if !limits::tick() {
+        return;
+    }
+
+    history::begin(instr_ptr() as u32);
+
+    if interrupt().load(Ordering::Relaxed) && !take_interrupt() {
+        return;
+    }
+
+    //println!("Instr ptr: 0x{:x}", instr_ptr());
+    let ip = instr_ptr() as u32;
+
+    let instruction = match instructions::Instruction::fetch(ip) {
+        Some(instruction) => instruction,
+        None => {
+            fetch_fault(ip);
+            return;
+        },
     };
 
-    let instr = instructions::Instr::from_byte(binary[0]);
-    let flag = instructions::Status::from_bits(binary[1]).unwrap();
+    log::debug!("Instruction: {:?}", instruction);
+
+    trace::begin(ip, &instruction);
+    profile::record(ip, instruction.instr());
+    sanitize::begin(ip, &instruction);
 
-    instructions::Instruction::new(instr, flag)
+    let executed = instruction.execute();
+
+    trace::end(executed);
+    coverage::record(ip, instruction.status(), executed);
+    if executed && *instruction.instr() == instructions::Instr::Jsr {
+        profile::record_call(ip, instruction.status(), instr_ptr() as u32);
+        sanitize::jsr(ip, instruction.status());
+    }
+
+    // Make sure all IO devices are ready before stopping
+    while !OUT_PUT_READY.load(Ordering::Relaxed) {
+        std::hint::spin_loop();
+    }
+}
+
+/// The instruction at the instruction pointer, None when there is none
+pub fn instr() -> Option<instructions::Instruction> {
+    instructions::Instruction::fetch(instr_ptr() as u32)
+}
+
+/// Enters the interrupt handler of the current core, returns false when no
+/// handler is installed and the core halted instead
+pub fn take_interrupt() -> bool {
+    use std::sync::atomic::Ordering;
+
+    interrupt().store(false, Ordering::Relaxed);
+
+    let (vector, cause) = {
+        let mut sic = int_controller().lock().unwrap();
+        if cpu::current().ipi.swap(false, Ordering::SeqCst) {
+            sic.gen_int(cpu::IPI_CAUSE, false);
+            interrupt().store(false, Ordering::Relaxed);
+        }
+
+        (sic.vector, sic.cause)
+    };
+
+    let handler = handler(vector);
+    if handler == 0 {
+        log::error!("Unhandled interrupt with cause 0x{:x} at 0x{:x}, halting", cause, instr_ptr());
+        cpu::halt();
+        return false;
+    }
+
+    log::info!("Interrupt generated");
+    store_ret();
+    int_jmp(handler);
+    sanitize::interrupt();
+
+    true
+}
+
+/// Address of the handler for `vector`, 0 when there is none
+pub fn handler(vector: u32) -> u32 {
+    let (jmp, table) = {
+        let sic = int_controller().lock().unwrap();
+        (sic.jmp, sic.table)
+    };
+
+    if table == 0 || vector >= sic::VECTORS {
+        return jmp;
+    }
+
+    let entry = table.checked_add(vector * 4).and_then(|addr| MMU.lock().unwrap().peek(addr, 32));
+
+    entry.filter(|handler| *handler != 0).unwrap_or(jmp)
+}
+
+/// Raises the exception for an instruction pointer with no instruction
+/// behind it, halting the core when that is the handler itself
+pub fn fetch_fault(ip: u32) {
+    if ip == handler(sic::ILLEGAL_INSTRUCTION) {
+        log::error!("No interrupt handler at 0x{:x}, halting", ip);
+        cpu::halt();
+        return;
+    }
+
+    log::warn!("No instruction at 0x{:x}", ip);
+    int_controller().lock().unwrap().gen_int(sic::ILLEGAL_INSTRUCTION, true);
 }
 
 /// Initialize memory
 /// TODO: Add custom memory sizes
-pub fn init() {
+pub fn init() -> Args {
     env_logger::init();
     let args = Args::parse();
 
-    let memory = args.memory_size.unwrap_or(0xFFFF);
+    if args.command.is_some() {
+        return args;
+    }
 
-    if memory < 0x204 {
-        panic!("Not enough memory provided for stack and instruction pointer");
+    cpu::set_count(args.cores);
+    limits::start(args.limits());
+    machine::set(args.machine());
+
+    // Writes made while loading count as initializing memory
+    if let Some(allow) = args.shadow() {
+        shadow::start(allow);
     }
 
-    unsafe {
-        MEM = Memory::new(memory as usize);
-        MEM.write_u32(0x200, 0x1600);
+    if let Some(snapshot) = &args.restore {
+        snapshot::restore_from_file(std::path::Path::new(snapshot))
+            .unwrap_or_else(|err| panic!("Error restoring snapshot: {}", err));
+
+        limits::memory(mem().size());
+    } else {
+        load(&args);
     }
 
-    let file_path = std::path::Path::new(&args.file);
-    let file = std::fs::read(file_path).expect("Error reading binary");
+    let (tx, rx) = std::sync::mpsc::channel::<u8>();
 
-    assert!(file.len() & 0b1 == 0, "File length is not aligned properly");
+    IO_SEND.lock().unwrap().update(tx);
 
-    let base = 0x600;
-    let mut offset = 0;
-    for data in file.iter() {
-        unsafe {
-            MEM[base + offset] = *data;
-        }
+    let _thread = std::thread::spawn(move || {term_out(rx)});
 
-        offset += 1;
+    // The debugger reads its commands from stdin
+    if !args.debug {
+        input::spawn_stdin();
     }
 
-    let (tx, rx) = std::sync::mpsc::channel::<u8>();
+    for desc in &args.watch {
+        let watchpoint = watch::Watchpoint::parse(desc)
+            .unwrap_or_else(|| panic!("Invalid watchpoint {}", desc));
 
-    unsafe {
-        IO_SEND.update(tx);
+        watch::add(watchpoint);
     }
 
-    let _thread = std::thread::spawn(move || {term_out(rx)});
+    if args.profile.is_some() {
+        profile::start(machine::get().program.entry);
+    }
+
+    if args.coverage.is_some() {
+        assert!(DEBUG_INFO.lock().unwrap().is_some(), "Coverage needs a program assembled from casm");
+
+        coverage::start();
+    }
+
+    if let Some(hz) = args.clock_hz {
+        timing::set_clock_hz(hz);
+    }
+
+    if let Some(path) = &args.trace {
+        trace::start(std::path::Path::new(path)).expect("Error creating trace file");
+    }
+
+    if args.sanitize_stacks {
+        sanitize::start();
+    }
+
+    args
+}
+
+fn load(args: &Args) {
+    let file = read_image(std::path::Path::new(args.file.as_ref().unwrap()));
+    let memory = args.memory_size.unwrap_or(0xFFFF) as usize;
+
+    if limits::memory(memory) {
+        load_image(&file, memory);
+    }
+}
+
+/// Reads a binary, or assembles it when it is casm source
+pub fn read_image(file_path: &std::path::Path) -> Vec<u8> {
+    if file_path.extension().is_some_and(|ext| ext == "casm") {
+        let assembly = asm::assemble_file(file_path).unwrap_or_else(|err| panic!("Error assembling: {}", err));
+
+        *DEBUG_INFO.lock().unwrap() = Some(assembly.debug);
+        assembly.image
+    } else {
+        std::fs::read(file_path).expect("Error reading binary")
+    }
 }
 
-use clap::Parser;
+/// Puts the machine in its power on state with `image` loaded where the
+/// machine description puts programs
+pub fn load_image(image: &[u8], memory: usize) {
+    use std::sync::atomic::Ordering;
+
+    let machine = machine::get();
+
+    if memory < machine.registers_end(cpu::count()) {
+        panic!("Not enough memory provided for stack and instruction pointer");
+    }
+
+    let room = (1 << 32) - machine.memory.ram_base as usize;
+    assert!(memory <= room, "More memory than fits in the address space, at most 0x{:x} bytes", room);
+
+    assert!(image.len() & 0b1 == 0, "File length is not aligned properly");
+
+    let (_, last_stack) = machine.stacks(cpu::count() - 1);
+    if cpu::count() > 1 && memory <= machine.index(last_stack) {
+        panic!("Not enough memory provided for the stacks of {} cores", cpu::count());
+    }
+
+    *MMU.lock().unwrap() = mmu_for(&machine, memory);
+
+    *MEM.write().unwrap() = Memory::new(memory);
+    shadow::reset();
+    mem().write(machine.index(machine.program.load), image).expect("Image does not fit in memory");
+
+    cpu::reset(machine.program.entry);
+    cache::reset();
+
+    HALTED.store(false, Ordering::Relaxed);
+    OUT_PUT_READY.store(true, Ordering::Relaxed);
+}
+
+use clap::{Parser, Subcommand};
 use instructions::Status;
 use mmu::MMU;
 #[derive(Parser,Default,Debug)]
-#[clap(author="Lilly, & Arc", version, about="A simple stack machine")]
-struct Args {
-    #[clap(short, long)]
-    memory_size: Option<u32>,
+#[clap(author="Lilly, & Arc", version, about="A simple stack machine", subcommand_negates_reqs = true)]
+pub struct Args {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
 
+    /// Bytes of RAM from 0x1000, pages are only allocated once written
     #[clap(short, long)]
-    file: String
+    pub memory_size: Option<u32>,
+
+    #[clap(short, long, required_unless_present = "restore")]
+    pub file: Option<String>,
+
+    /// Write a snapshot of the machine here when it halts or is signalled
+    #[clap(long)]
+    pub snapshot_out: Option<String>,
+
+    /// Resume from a snapshot instead of loading a binary
+    #[clap(long)]
+    pub restore: Option<String>,
+
+    /// Record every executed instruction as JSON lines
+    #[clap(long)]
+    pub trace: Option<String>,
+
+    /// Start an interactive debugger that can also step backwards
+    #[clap(long)]
+    pub debug: bool,
+
+    /// Stop when an address range is accessed, as `start[-end][:r|w|rw]`
+    #[clap(long)]
+    pub watch: Vec<String>,
+
+    /// Report values popped at another width than they were pushed, stack
+    /// underflows and unbalanced returns
+    #[clap(long)]
+    pub sanitize_stacks: bool,
+
+    /// Report reads of RAM that nothing has written to
+    #[clap(long)]
+    pub shadow_memory: bool,
+
+    /// Range of addresses `--shadow-memory` lets be read uninitialized, as
+    /// `start[-end]`
+    #[clap(long)]
+    pub shadow_allow: Vec<String>,
+
+    /// Count executed instructions, writing a report here and folded call
+    /// stacks next to it with a `.folded` extension
+    #[clap(long)]
+    pub profile: Option<String>,
+
+    /// Labels used to make reports readable, programs assembled from casm
+    /// bring their own
+    #[clap(long)]
+    pub symbols: Option<String>,
+
+    /// Write an lcov report of the casm lines and conditional instructions
+    /// that ran
+    #[clap(long)]
+    pub coverage: Option<String>,
+
+    /// Translate the program to a Rust module written here instead of
+    /// running it
+    #[clap(long)]
+    pub translate: Option<String>,
+
+    /// Number of cores sharing memory and devices
+    #[clap(long, default_value_t = 1)]
+    pub cores: usize,
+
+    /// Run the cores in turn on one thread for reproducible runs, instead of
+    /// a thread per core
+    #[clap(long)]
+    pub round_robin: bool,
+
+    /// Instructions each core runs before the round robin scheduler moves on
+    #[clap(long, default_value_t = 1)]
+    pub quantum: u32,
+
+    /// Slow every core down to this many cycles a second
+    #[clap(long)]
+    pub clock_hz: Option<u64>,
+
+    /// Stop after running this many instructions over all cores
+    #[clap(long)]
+    pub max_instructions: Option<u64>,
+
+    /// Stop after running for this many seconds
+    #[clap(long)]
+    pub max_time: Option<f64>,
+
+    /// Stop when the guest tries to output more than this many bytes
+    #[clap(long)]
+    pub max_output: Option<u64>,
+
+    /// Refuse to run with more memory than this many bytes
+    #[clap(long)]
+    pub max_memory: Option<usize>,
+
+    /// TOML description of the memory map and devices, see `machine`
+    #[clap(long)]
+    pub machine: Option<String>,
+
+    /// Bytes the primary stack of every core holds
+    #[clap(long)]
+    pub primary_stack_size: Option<u16>,
+
+    /// Bytes the return stack of every core holds
+    #[clap(long)]
+    pub return_stack_size: Option<u16>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the routines annotated with `;test` in casm files
+    Test {
+        #[clap(required = true)]
+        files: Vec<String>,
+    },
+}
+
+impl Args {
+    pub fn limits(&self) -> limits::Limits {
+        limits::Limits {
+            instructions: self.max_instructions,
+            wall_time: self.max_time.map(std::time::Duration::from_secs_f64),
+            output_bytes: self.max_output,
+            memory: self.max_memory,
+        }
+    }
+
+    /// The ranges that may be read uninitialized, when `--shadow-memory`
+    /// is given
+    pub fn shadow(&self) -> Option<Vec<(u32, u32)>> {
+        self.shadow_memory.then(|| self.shadow_allow.iter().map(|desc| {
+            shadow::parse_range(desc).unwrap_or_else(|| panic!("Invalid shadow memory range {}", desc))
+        }).collect())
+    }
+
+    /// The machine given with `--machine`, or the built-in one, with the
+    /// stack sizes from the command line
+    pub fn machine(&self) -> machine::Machine {
+        let machine = match &self.machine {
+            Some(path) => machine::Machine::load(std::path::Path::new(path))
+                .unwrap_or_else(|err| panic!("Error reading machine description: {}", err)),
+            None => machine::Machine::DEFAULT,
+        };
+
+        machine.with_stack_sizes(self.primary_stack_size, self.return_stack_size)
+            .unwrap_or_else(|err| panic!("Invalid stack size: {}", err))
+    }
 }
 
 pub fn store_ret() {
-    INT_CONTROLLER.lock().unwrap().store_ret();
+    // Read before locking the Sic, which the MMU locks while held
+    let ip = cpu::ip();
+    int_controller().lock().unwrap().store_ret(ip);
 }
 
-pub fn int_jmp() {
-    INT_CONTROLLER.lock().unwrap().jmp();
+/// Jumps to the handler at `addr` for an interrupt
+pub fn int_jmp(addr: u32) {
+    log::info!("Int jumping to 0x{:x}", addr);
+    cpu::set_ip(addr);
 }
 
 fn term_out(receiver: Receiver<u8>) -> ! {
@@ -158,6 +564,12 @@ fn term_out(receiver: Receiver<u8>) -> ! {
 
 pub struct DeviceSender<T>(Option<Sender<T>>);
 
+impl<T> Default for DeviceSender<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> DeviceSender<T> {
     pub const fn new() -> DeviceSender<T> {
         DeviceSender(None)
@@ -174,6 +586,3 @@ impl<T> DeviceSender<T> {
         Ok(())
     }
 }
-
-unsafe impl<T> Sync for DeviceSender<T> {}
-unsafe impl<T> Send for DeviceSender<T> {}
\ No newline at end of file
@@ -0,0 +1,168 @@
+/*
+Resource limits for untrusted guests
+
+Instructions are counted over every core, wall time is measured from
+`start`, output bytes are counted by the output device and memory is checked
+when an image is loaded. The first limit that trips stops the machine and is
+kept for the report.
+*/
+
+use std::{
+    fmt,
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Limits {
+    pub instructions: Option<u64>,
+    pub wall_time: Option<Duration>,
+    pub output_bytes: Option<u64>,
+    pub memory: Option<usize>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_none() && self.wall_time.is_none()
+            && self.output_bytes.is_none() && self.memory.is_none()
+    }
+}
+
+/// A limit that was tripped, holding the configured maximum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Instructions(u64),
+    WallTime(Duration),
+    OutputBytes(u64),
+    Memory(usize),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Instructions(max) => write!(f, "instruction limit of {} reached", max),
+            Limit::WallTime(max) => write!(f, "wall time limit of {:?} reached", max),
+            Limit::OutputBytes(max) => write!(f, "output limit of {} bytes reached", max),
+            Limit::Memory(max) => write!(f, "memory limit of {} bytes exceeded", max),
+        }
+    }
+}
+
+static LIMITS: Mutex<Limits> = Mutex::new(Limits {
+    instructions: None,
+    wall_time: None,
+    output_bytes: None,
+    memory: None,
+});
+static ENFORCING: AtomicBool = AtomicBool::new(false);
+static EXCEEDED: AtomicBool = AtomicBool::new(false);
+static TRIPPED: Mutex<Option<Limit>> = Mutex::new(None);
+static START: Mutex<Option<Instant>> = Mutex::new(None);
+
+static INSTRUCTIONS: AtomicU64 = AtomicU64::new(0);
+/// `LIMITS.instructions` for `tick`, `u64::MAX` when there is none
+static MAX_INSTRUCTIONS: AtomicU64 = AtomicU64::new(u64::MAX);
+static OUTPUT_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Wall time is only looked at once every this many instructions
+const CLOCK_INTERVAL: u64 = 1024;
+
+pub fn start(limits: Limits) {
+    *LIMITS.lock().unwrap() = limits;
+    *TRIPPED.lock().unwrap() = None;
+    *START.lock().unwrap() = Some(Instant::now());
+
+    MAX_INSTRUCTIONS.store(limits.instructions.unwrap_or(u64::MAX), Ordering::Relaxed);
+    INSTRUCTIONS.store(0, Ordering::Relaxed);
+    OUTPUT_BYTES.store(0, Ordering::Relaxed);
+    EXCEEDED.store(false, Ordering::Relaxed);
+    ENFORCING.store(!limits.is_empty(), Ordering::Relaxed);
+}
+
+/// Whether a limit has stopped the machine
+pub fn exceeded() -> bool {
+    EXCEEDED.load(Ordering::Relaxed)
+}
+
+/// The limit that stopped the machine
+pub fn tripped() -> Option<Limit> {
+    *TRIPPED.lock().unwrap()
+}
+
+fn trip(limit: Limit) {
+    let mut tripped = TRIPPED.lock().unwrap();
+
+    if tripped.is_none() {
+        log::info!("Stopping, {}", limit);
+        *tripped = Some(limit);
+    }
+
+    EXCEEDED.store(true, Ordering::Relaxed);
+}
+
+/// Counts an instruction about to run, returns whether it may
+///
+/// Only takes a lock once every `CLOCK_INTERVAL` instructions.
+pub fn tick() -> bool {
+    if !ENFORCING.load(Ordering::Relaxed) {
+        return true;
+    }
+
+    let count = INSTRUCTIONS.fetch_add(1, Ordering::Relaxed) + 1;
+    let max = MAX_INSTRUCTIONS.load(Ordering::Relaxed);
+
+    let tripped = if count > max {
+        Some(Limit::Instructions(max))
+    } else if count.is_multiple_of(CLOCK_INTERVAL) {
+        LIMITS.lock().unwrap().wall_time
+            .filter(|max| START.lock().unwrap().is_some_and(|start| start.elapsed() > *max))
+            .map(Limit::WallTime)
+    } else {
+        None
+    };
+
+    match tripped {
+        Some(limit) => {
+            // The instruction never runs
+            INSTRUCTIONS.fetch_sub(1, Ordering::Relaxed);
+            trip(limit);
+            false
+        },
+        None => true,
+    }
+}
+
+/// Instructions run since `start`, only counted while a limit is enforced
+pub fn instructions() -> u64 {
+    INSTRUCTIONS.load(Ordering::Relaxed)
+}
+
+/// Called by the output device for every byte, returns whether it may be
+/// sent
+pub fn output() -> bool {
+    if !ENFORCING.load(Ordering::Relaxed) {
+        return true;
+    }
+
+    let count = OUTPUT_BYTES.fetch_add(1, Ordering::Relaxed) + 1;
+
+    match LIMITS.lock().unwrap().output_bytes {
+        Some(max) if count > max => {
+            trip(Limit::OutputBytes(max));
+            false
+        },
+        _ => true,
+    }
+}
+
+/// Checks the size of memory a guest asked for, returns whether it may
+/// have it
+pub fn memory(size: usize) -> bool {
+    match LIMITS.lock().unwrap().memory {
+        Some(max) if size > max => {
+            trip(Limit::Memory(max));
+            false
+        },
+        _ => true,
+    }
+}
@@ -0,0 +1,409 @@
+/*
+Memory map of the machine, read from the TOML file given to `--machine`
+
+Every address is as the MMU sees it and every field can be left out, the
+built-in layout is
+
+    [memory]
+    io_base = 0x0
+    io_max = 0xfff
+    ram_base = 0x1000
+
+    [cores]
+    ip = 0x1200             # instruction pointer of core 0
+    conditions = 0x1204     # condition register of core 0
+    stride = 0x10           # between the registers of consecutive cores
+    primary_stack = 0x20ff  # stacks of core 0, they grow down from here
+    return_stack = 0x21ff
+    primary_stack_size = 0x100  # bytes each stack holds, even and at least 4
+    return_stack_size = 0x100
+    stack_stride = 0x200    # between the stacks of consecutive cores
+
+    [program]
+    load = 0x1600           # where the image is placed
+    entry = 0x1600          # where every core starts
+
+    [devices]
+    stacks = 0x0
+    swi = 0xc
+    cpu = 0x10
+    timer = 0x20
+    context = 0x40
+    output = 0x100
+    input = 0x104
+    sic = 0x300
+
+Stacks start at an odd address so their 16 bit cells stay aligned. A
+`[devices]` table replaces the whole set, a device left out of it is not
+attached and its registers fault like any unknown IO address. Devices are
+placed by their base, their registers are
+
+| device    | offset | desc                                                    |
+| --------- | ------ | ------------------------------------------------------- |
+| `stacks`  | 0x0    | primary stack position                                  |
+|           | 0x4    | return stack position                                   |
+|           | 0x8    | primary stack offset, or both as a u32                  |
+|           | 0xa    | return stack offset                                     |
+| `swi`     | 0x0    | raise a software interrupt                              |
+| `cpu`     | 0x0    | ID of the core doing the read                           |
+|           | 0x4    | number of cores                                         |
+|           | 0x8    | interrupt the core with this ID                         |
+| `timer`   | 0x0    | cycles run by the core, read 16 bits at a time          |
+| `context` | 0x0    | save both stacks to the context block written here      |
+|           | 0x4    | restore both stacks from the context block written here |
+| `output`  | 0x0    | byte to send to the output device                       |
+| `input`   | 0x0    | next byte of input, see `input`                         |
+|           | 0x2    | input has ended                                         |
+| `sic`     | 0x0    | interrupt handler                                       |
+|           | 0x4    | cause of the last interrupt                             |
+|           | 0x8    | return address of the last interrupt                    |
+|           | 0xc    | vector of the last interrupt, see `sic`                 |
+|           | 0x10   | vector table, 0 to leave vectored mode                  |
+
+The `stacks` registers read back what was last written to them. A context
+block is 12 bytes of 4 byte aligned RAM laid out like those registers, both
+positions as u32 then both offsets as u16, so a scheduler can switch stacks
+with a single write. A block that is not in RAM, or holds stacks that do not
+fit, raises cause 3 with vector `BAD_CONTEXT` and leaves the stacks alone.
+*/
+
+use std::{fmt, path::Path, sync::RwLock};
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Machine {
+    pub memory: MemoryMap,
+    pub cores: CoreMap,
+    pub program: Program,
+    pub devices: Devices,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryMap {
+    pub io_base: u32,
+    pub io_max: u32,
+    pub ram_base: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CoreMap {
+    pub ip: u32,
+    pub conditions: u32,
+    pub stride: u32,
+    pub primary_stack: u32,
+    pub return_stack: u32,
+    pub primary_stack_size: u16,
+    pub return_stack_size: u16,
+    pub stack_stride: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Program {
+    pub load: u32,
+    pub entry: u32,
+}
+
+/// Base address of every attached device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Devices {
+    pub stacks: Option<u32>,
+    pub swi: Option<u32>,
+    pub cpu: Option<u32>,
+    pub timer: Option<u32>,
+    pub context: Option<u32>,
+    pub output: Option<u32>,
+    pub input: Option<u32>,
+    pub sic: Option<u32>,
+}
+
+/// Base of a device, the bytes it takes up and its registers by offset
+type Layout = (Option<u32>, u32, &'static [(u32, Register)]);
+
+/// A device register, see the table at the top
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    PrimaryPosition,
+    ReturnPosition,
+    PrimaryOffset,
+    ReturnOffset,
+    SoftwareInterrupt,
+    CoreId,
+    CoreCount,
+    Ipi,
+    /// Bits of the cycle counter starting at this one
+    Cycles(u32),
+    SaveContext,
+    RestoreContext,
+    Output,
+    Input,
+    InputEnded,
+    Jmp,
+    Cause,
+    ReturnAddr,
+    Vector,
+    VectorTable,
+}
+
+impl Machine {
+    pub const DEFAULT: Machine = Machine {
+        memory: MemoryMap::DEFAULT,
+        cores: CoreMap::DEFAULT,
+        program: Program::DEFAULT,
+        devices: Devices::DEFAULT,
+    };
+
+    pub fn parse(source: &str) -> Result<Machine, MachineError> {
+        let machine: Machine = toml::from_str(source)?;
+        machine.check()?;
+
+        Ok(machine)
+    }
+
+    pub fn load(path: &Path) -> Result<Machine, MachineError> {
+        Machine::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// The same machine with the stack sizes that are given replaced
+    pub fn with_stack_sizes(mut self, primary: Option<u16>, ret: Option<u16>) -> Result<Machine, MachineError> {
+        self.cores.primary_stack_size = primary.unwrap_or(self.cores.primary_stack_size);
+        self.cores.return_stack_size = ret.unwrap_or(self.cores.return_stack_size);
+        self.check()?;
+
+        Ok(self)
+    }
+
+    /// Index in `MEM` of a RAM address
+    pub fn index(&self, addr: u32) -> usize {
+        (addr - self.memory.ram_base) as usize
+    }
+
+    /// Address of the instruction pointer of core `id`
+    pub fn ip(&self, id: usize) -> u32 {
+        self.cores.ip + self.cores.stride * id as u32
+    }
+
+    /// Address of the condition register of core `id`
+    pub fn conditions(&self, id: usize) -> u32 {
+        self.cores.conditions + self.cores.stride * id as u32
+    }
+
+    /// Addresses of the primary and return stack of core `id`
+    pub fn stacks(&self, id: usize) -> (u32, u32) {
+        let offset = self.cores.stack_stride * id as u32;
+
+        (self.cores.primary_stack + offset, self.cores.return_stack + offset)
+    }
+
+    /// Bytes of RAM needed for the registers of `cores` cores
+    pub fn registers_end(&self, cores: usize) -> usize {
+        let last = cores as u32 - 1;
+
+        self.index(self.ip(last as usize) + 4).max(self.index(self.conditions(last as usize) + 2))
+    }
+
+    fn check(&self) -> Result<(), MachineError> {
+        let invalid = |message: String| Err(MachineError::Invalid(message));
+        let (memory, cores, program) = (self.memory, self.cores, self.program);
+
+        if memory.io_base > memory.io_max || memory.io_max >= memory.ram_base {
+            return invalid("the IO window has to come before RAM".to_string());
+        }
+
+        let last = cores.stride as u64 * (crate::cpu::MAX_CORES as u64 - 1);
+        for (name, addr, align) in [("ip", cores.ip, 4), ("conditions", cores.conditions, 2)] {
+            if addr < memory.ram_base || addr % align != 0 || addr as u64 + last + align as u64 > u32::MAX as u64 {
+                return invalid(format!("{} has to be {} byte aligned RAM for every core", name, align));
+            }
+        }
+
+        let (start, end) = (cores.ip.min(cores.conditions), (cores.ip + 4).max(cores.conditions + 2));
+        if cores.ip < cores.conditions + 2 && cores.conditions < cores.ip + 4 {
+            return invalid("ip and conditions overlap".to_string());
+        }
+
+        if cores.stride % 4 != 0 || cores.stride < end - start {
+            return invalid(format!("stride has to be a multiple of 4 of at least 0x{:x}", end - start));
+        }
+
+        let last = cores.stack_stride as u64 * (crate::cpu::MAX_CORES as u64 - 1);
+        if cores.stack_stride % 2 != 0 {
+            return invalid("stack_stride has to be even".to_string());
+        }
+
+        let stacks = [
+            ("primary_stack", cores.primary_stack, cores.primary_stack_size),
+            ("return_stack", cores.return_stack, cores.return_stack_size),
+        ];
+        for (name, addr, size) in stacks {
+            if size < 4 || size % 2 != 0 {
+                return invalid(format!("{}_size has to be even and at least 4", name));
+            }
+
+            if addr % 2 == 0 || (addr as u64) < memory.ram_base as u64 + size as u64 - 1 || addr as u64 + last > u32::MAX as u64 {
+                return invalid(format!("{} has to be odd and leave room for a stack in RAM", name));
+            }
+        }
+
+        if program.load < memory.ram_base || program.load % 2 != 0 || program.entry % 2 != 0 {
+            return invalid("the program has to be loaded into RAM at an even address".to_string());
+        }
+
+        let mut spans = self.devices.spans();
+        spans.sort();
+
+        for (start, end) in spans.iter() {
+            if *start < memory.io_base || *end > memory.io_max {
+                return invalid(format!("device at 0x{:x} is outside of the IO window", start));
+            }
+        }
+
+        for pair in spans.windows(2) {
+            if pair[0].1 >= pair[1].0 {
+                return invalid(format!("devices at 0x{:x} and 0x{:x} overlap", pair[0].0, pair[1].0));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::DEFAULT
+    }
+}
+
+impl MemoryMap {
+    pub const DEFAULT: MemoryMap = MemoryMap { io_base: 0, io_max: 0xfff, ram_base: 0x1000 };
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        MemoryMap::DEFAULT
+    }
+}
+
+impl CoreMap {
+    pub const DEFAULT: CoreMap = CoreMap {
+        ip: 0x1200,
+        conditions: 0x1204,
+        stride: 0x10,
+        primary_stack: 0x20ff,
+        return_stack: 0x21ff,
+        primary_stack_size: 0x100,
+        return_stack_size: 0x100,
+        stack_stride: 0x200,
+    };
+}
+
+impl Default for CoreMap {
+    fn default() -> Self {
+        CoreMap::DEFAULT
+    }
+}
+
+impl Program {
+    pub const DEFAULT: Program = Program { load: crate::asm::LOAD_ADDR, entry: crate::asm::LOAD_ADDR };
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Program::DEFAULT
+    }
+}
+
+impl Devices {
+    pub const DEFAULT: Devices = Devices {
+        stacks: Some(0x0),
+        swi: Some(0xc),
+        cpu: Some(0x10),
+        timer: Some(0x20),
+        context: Some(0x40),
+        output: Some(0x100),
+        input: Some(0x104),
+        sic: Some(0x300),
+    };
+
+    fn layout(&self) -> [Layout; 8] {
+        use Register::*;
+
+        [
+            (self.stacks, 0xc, &[(0x0, PrimaryPosition), (0x4, ReturnPosition), (0x8, PrimaryOffset), (0xa, ReturnOffset)]),
+            (self.swi, 0x2, &[(0x0, SoftwareInterrupt)]),
+            (self.cpu, 0xc, &[(0x0, CoreId), (0x4, CoreCount), (0x8, Ipi)]),
+            (self.timer, 0x8, &[(0x0, Cycles(0)), (0x2, Cycles(16)), (0x4, Cycles(32)), (0x6, Cycles(48))]),
+            (self.context, 0x8, &[(0x0, SaveContext), (0x4, RestoreContext)]),
+            (self.output, 0x2, &[(0x0, Output)]),
+            (self.input, 0x4, &[(0x0, Input), (0x2, InputEnded)]),
+            (self.sic, 0x14, &[(0x0, Jmp), (0x4, Cause), (0x8, ReturnAddr), (0xc, Vector), (0x10, VectorTable)]),
+        ]
+    }
+
+    /// The register at IO address `addr`, if a device has one there
+    pub fn register(&self, addr: u32) -> Option<Register> {
+        self.layout().into_iter()
+            .filter_map(|(base, _, registers)| Some((addr.checked_sub(base?)?, registers)))
+            .find_map(|(offset, registers)| registers.iter().find(|(at, _)| *at == offset))
+            .map(|(_, register)| *register)
+    }
+
+    /// First and last address of every attached device
+    fn spans(&self) -> Vec<(u32, u32)> {
+        self.layout().into_iter()
+            .filter_map(|(base, len, _)| Some((base?, base?.checked_add(len - 1)?)))
+            .collect()
+    }
+}
+
+impl Default for Devices {
+    fn default() -> Self {
+        Devices::DEFAULT
+    }
+}
+
+#[derive(Debug)]
+pub enum MachineError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MachineError::Io(err) => write!(f, "{}", err),
+            MachineError::Parse(err) => write!(f, "{}", err),
+            MachineError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<std::io::Error> for MachineError {
+    fn from(err: std::io::Error) -> Self {
+        MachineError::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for MachineError {
+    fn from(err: toml::de::Error) -> Self {
+        MachineError::Parse(err)
+    }
+}
+
+static MACHINE: RwLock<Machine> = RwLock::new(Machine::DEFAULT);
+
+/// The machine being emulated
+pub fn get() -> Machine {
+    *MACHINE.read().unwrap()
+}
+
+/// Describes the machine for the next `load_image`
+pub fn set(machine: Machine) {
+    *MACHINE.write().unwrap() = machine;
+}
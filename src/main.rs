@@ -1,37 +1,130 @@
-use cute_vm::{INTERRUPT, store_ret, int_jmp, OUT_PUT_READY, HALTED};
+use cute_vm::{cpu::RoundRobin, harness::Config, limits, symbols::Symbols, Command, DEBUG_INFO, SIGNALLED};
 use std::sync::atomic::Ordering;
 
+/// Exit status when a resource limit stopped the guest
+const LIMIT_EXIT: i32 = 3;
+
 fn main() {
-    cute_vm::init();
+    let args = cute_vm::init();
+
+    if let Some(Command::Test { files }) = &args.command {
+        let config = Config {
+            limits: args.limits(),
+            memory: args.memory_size.unwrap_or(0xFFFF) as usize,
+            machine: args.machine(),
+            sanitize: args.sanitize_stacks,
+            shadow: args.shadow(),
+            ..Config::default()
+        };
+
+        std::process::exit(if cute_vm::unit::run_files(files, &config) { 0 } else { 1 });
+    }
+
     println!("Initialized");
 
-    while !HALTED.load(Ordering::Relaxed) {
-        if *INTERRUPT.lock().unwrap() {
-            log::info!("Interrupt generated");
-            //println!("{:x?}", INT_CONTROLLER.lock().unwrap());
-            store_ret();
-            int_jmp();
+    if args.snapshot_out.is_some() {
+        ctrlc::set_handler(|| SIGNALLED.store(true, Ordering::Relaxed))
+            .expect("Failed to install signal handler");
+    }
+
+    if let Some(path) = &args.translate {
+        let image = cute_vm::read_image(std::path::Path::new(args.file.as_ref().expect("Nothing to translate")));
+
+        let entry = cute_vm::machine::get().program.entry;
+
+        std::fs::write(path, cute_vm::aot::translate(&image, entry)).expect("Error writing translation");
+        return;
+    }
+
+    // Nothing can run when the image did not fit in the memory limit
+    if let Some(limit) = limits::tripped() {
+        eprintln!("Stopped: {}", limit);
+        std::process::exit(LIMIT_EXIT);
+    }
+
+    let mut stopped = false;
+
+    let instrumented = args.trace.is_some() || args.profile.is_some()
+        || args.coverage.is_some() || !args.watch.is_empty() || args.sanitize_stacks;
 
-            *INTERRUPT.lock().unwrap() = false;
+    let mut scheduler = RoundRobin::new(args.quantum);
+
+    if args.debug {
+        cute_vm::debugger::Debugger::with_scheduler(scheduler).run();
+    } else if !instrumented && !args.round_robin {
+        cute_vm::cpu::run_parallel();
+    } else if !instrumented {
+        scheduler.run();
+    } else {
+        while cute_vm::running() {
+            scheduler.step();
+            //std::thread::sleep(std::time::Duration::from_secs(1));
+
+            if let Some(hit) = cute_vm::watch::take_hit() {
+                eprintln!("{}", hit);
+                stopped = true;
+                break;
+            }
         }
+    }
 
-        log::debug!("Instruction: {:?}", cute_vm::instr());
+    cute_vm::trace::finish();
 
-        //println!("Instr ptr: 0x{:x}", cute_vm::instr_ptr());
-        let instruction = cute_vm::instr();
+    if args.sanitize_stacks {
+        let reports = cute_vm::sanitize::finish();
+        eprintln!("Stack sanitizer: {} problem{} found", reports.len(), if reports.len() == 1 { "" } else { "s" });
+    }
 
-        //println!("Running instruction {:?} at 0x{:x}", instruction, cute_vm::instr_ptr());
-        instruction.execute();
+    if args.shadow_memory {
+        let reports = cute_vm::shadow::finish();
+        eprintln!("Shadow memory: {} uninitialized read{}", reports.len(), if reports.len() == 1 { "" } else { "s" });
+    }
+
+    let memory = cute_vm::mem();
+    log::info!("0x{:x} of 0x{:x} bytes of memory resident", memory.resident(), memory.size());
+    drop(memory);
 
-        // Make sure all IO devices are ready before stopping
-        while !io_ready() {}
-        //std::thread::sleep(std::time::Duration::from_secs(1));
+    if let Some(path) = &args.profile {
+        write_profile(&args, std::path::Path::new(path));
     }
-}
 
-fn io_ready() -> bool {
-    if OUT_PUT_READY.load(Ordering::Relaxed) {
-        return true;
+    if let Some(path) = &args.coverage {
+        let coverage = cute_vm::coverage::finish().expect("Coverage was not running");
+        let debug = DEBUG_INFO.lock().unwrap();
+
+        std::fs::write(path, coverage.lcov(debug.as_ref().unwrap())).expect("Error writing coverage");
     }
-    false
-}
\ No newline at end of file
+
+    if let Some(path) = &args.snapshot_out {
+        cute_vm::snapshot::save_to_file(std::path::Path::new(path))
+            .unwrap_or_else(|err| panic!("Error writing snapshot: {}", err));
+    }
+
+    if SIGNALLED.load(Ordering::Relaxed) {
+        std::process::exit(130);
+    }
+
+    if stopped {
+        std::process::exit(1);
+    }
+
+    if let Some(limit) = limits::tripped() {
+        eprintln!("Stopped: {}", limit);
+        std::process::exit(LIMIT_EXIT);
+    }
+}
+
+fn write_profile(args: &cute_vm::Args, path: &std::path::Path) {
+    let symbols = match &args.symbols {
+        Some(symbols) => Symbols::load(std::path::Path::new(symbols))
+            .unwrap_or_else(|err| panic!("Error reading symbols: {}", err)),
+        None => DEBUG_INFO.lock().unwrap().as_ref()
+            .map(|debug| debug.symbols.clone())
+            .unwrap_or_default(),
+    };
+
+    let profiler = cute_vm::profile::finish().expect("Profiler was not running");
+
+    std::fs::write(path, profiler.report(&symbols)).expect("Error writing profile");
+    std::fs::write(path.with_extension("folded"), profiler.folded(&symbols)).expect("Error writing folded stacks");
+}
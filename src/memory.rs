@@ -1,175 +1,278 @@
-pub struct Memory {
-    base: *mut u8,
-    size: usize
+/*
+RAM of the machine, shared by every core and device thread
+
+Memory is split into pages of `PAGE_SIZE` bytes that are only allocated the
+first time they are written, reads of an untouched page see zeros. Pages are
+found through a directory per `DIRECTORY_SIZE` pages, so even the whole 4 GiB
+address space only costs a small table until it is used.
+
+The bytes are atomics so any number of threads can read and write through a
+shared reference, `MEM` only needs its write lock to swap in a new `Memory`.
+Accesses that do not fit are returned as a `Fault` for the caller to raise,
+the `vm_` accessors also fault on addresses that are not aligned to their
+width like the guest expects.
+*/
+
+use std::{fmt, sync::{atomic::{AtomicU8, AtomicUsize, Ordering}, OnceLock}};
+
+pub const PAGE_SIZE: usize = 0x1000;
+/// Pages covered by one directory
+const DIRECTORY_SIZE: usize = 0x400;
+
+type Page = Box<[AtomicU8]>;
+type Directory = Box<[OnceLock<Page>]>;
+
+/// Why an access could not be made
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Part of the access is past the end of memory
+    OutOfBounds,
+    /// The address is not a multiple of the width of the access
+    Misaligned,
 }
 
-use alloc::alloc::Layout;
+#[derive(Default)]
+pub struct Memory {
+    directories: Vec<OnceLock<Directory>>,
+    size: usize,
+    /// Pages allocated so far
+    resident: AtomicUsize,
+}
 
 impl Memory {
     pub const fn null() -> Memory {
-        Memory { base: core::ptr::null_mut(), size: 0 }
+        Memory { directories: Vec::new(), size: 0, resident: AtomicUsize::new(0) }
     }
 
     pub fn new(size: usize) -> Memory {
-        unsafe {
-            Memory { 
-                base: alloc::alloc::alloc_zeroed(Layout::from_size_align_unchecked(size, 0)), 
-                size 
-            }
+        let directories = size.div_ceil(PAGE_SIZE * DIRECTORY_SIZE);
+
+        Memory {
+            directories: (0..directories).map(|_| OnceLock::new()).collect(),
+            size,
+            resident: AtomicUsize::new(0),
+        }
+    }
+
+    /// Memory holding a copy of `bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Memory {
+        Memory::from_pages(bytes.len(), [(0, bytes)]).expect("Bytes fit in memory made for them")
+    }
+
+    /// Memory of `size` bytes holding each run of bytes at its index, later
+    /// runs overwrite earlier ones. Nothing is recorded, so this is for
+    /// building memory before it goes into `MEM`.
+    pub fn from_pages<'a>(size: usize, pages: impl IntoIterator<Item = (usize, &'a [u8])>) -> Result<Memory, Fault> {
+        let memory = Memory::new(size);
+
+        for (index, bytes) in pages {
+            memory.fill(index, bytes)?;
         }
+
+        Ok(memory)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
     }
 
-    pub fn vm_read_u16(&self, index: usize) -> u16 {
-        //println!("Reading 0x{:x}", index);
+    /// Bytes of host memory actually backing the guest
+    pub fn resident(&self) -> usize {
+        self.resident.load(Ordering::Relaxed) * PAGE_SIZE
+    }
 
-        if index & 0b1 != 0 {
-            INT_CONTROLLER.lock().unwrap().gen_int(0, true);
+    /// Numbers of the pages that have been allocated, in order
+    pub fn resident_pages(&self) -> Vec<usize> {
+        let mut pages = Vec::new();
 
-            log::warn!("VM u16 address not aligned 0x{:x}", index + 0x1000);
+        for (number, directory) in self.directories.iter().enumerate() {
+            let Some(directory) = directory.get() else { continue };
 
-            return 0;
+            for (offset, page) in directory.iter().enumerate() {
+                if page.get().is_some() {
+                    pages.push(number * DIRECTORY_SIZE + offset);
+                }
+            }
         }
 
-        self.read_u16(index)
+        pages
     }
 
-    pub fn read_u16(&self, index: usize) -> u16 {
-        let bytes = [
-            self[index],
-            self[index + 1],
-        ];
+    /// The page holding `index`, if it has been allocated
+    fn page(&self, index: usize) -> Option<&Page> {
+        let page = index / PAGE_SIZE;
 
-        u16::from_le_bytes(bytes)
+        self.directories[page / DIRECTORY_SIZE].get()?[page % DIRECTORY_SIZE].get()
     }
 
-    pub fn vm_read_u32(&self, index: usize) -> u32 {
-        //println!("Reading 0x{:x}", index);
+    /// The page holding `index`, allocating it on first touch
+    fn page_or_alloc(&self, index: usize) -> &Page {
+        let page = index / PAGE_SIZE;
 
-        if index & 0b11 != 0 {
-            INT_CONTROLLER.lock().unwrap().gen_int(0, true);
+        let directory = self.directories[page / DIRECTORY_SIZE]
+            .get_or_init(|| (0..DIRECTORY_SIZE).map(|_| OnceLock::new()).collect());
 
-            log::warn!("VM u32 address not aligned 0x{:x}", index + 0x1000);
+        directory[page % DIRECTORY_SIZE].get_or_init(|| {
+            self.resident.fetch_add(1, Ordering::Relaxed);
+            (0..PAGE_SIZE).map(|_| AtomicU8::new(0)).collect()
+        })
+    }
 
-            return 0;
+    /// Checks that the `len` bytes at `index` are all in memory
+    fn range(&self, index: usize, len: usize) -> Result<(), Fault> {
+        match index.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(Fault::OutOfBounds),
         }
-
-        self.read_u32(index)
     }
 
-    pub fn read_u32(&self, index: usize) -> u32 {
+    fn load(&self, index: usize) -> u8 {
+        self.page(index).map_or(0, |page| page[index % PAGE_SIZE].load(Ordering::Relaxed))
+    }
 
-        let bytes = [
-            self[index],
-            self[index + 1],
-            self[index + 2],
-            self[index + 3],
-        ];
+    pub fn get(&self, index: usize) -> Result<u8, Fault> {
+        self.range(index, 1)?;
 
-        u32::from_le_bytes(bytes)
+        Ok(self.load(index))
     }
 
-    pub fn read_u64(&self, index: usize) -> u64 {
-        assert!(index & 0b111 == 0, "Address for long not aligned");
+    pub fn set(&self, index: usize, value: u8) -> Result<(), Fault> {
+        self.range(index, 1)?;
+
+        let old = self.page_or_alloc(index)[index % PAGE_SIZE].swap(value, Ordering::Relaxed);
 
-        let bytes = [
-            self[index],
-            self[index + 1],
-            self[index + 2],
-            self[index + 3],
-            self[index + 4],
-            self[index + 5],
-            self[index + 6],
-            self[index + 7],
-        ];
+        crate::history::record_write(index, old);
+        crate::cache::invalidate(index);
+        crate::shadow::written(index, 1);
 
-        u64::from_le_bytes(bytes)
+        Ok(())
     }
 
-    pub fn vm_write_u16(&mut self, index: usize, num: u16) {
-        //println!("Writing 0x{:x} to 0x{:x}", num, index);
+    /// Puts back a byte without recording the write, for undoing a step
+    pub fn restore(&self, index: usize, value: u8) -> Result<(), Fault> {
+        self.range(index, 1)?;
 
-        if index & 0b1 != 0 {
-            log::warn!("u16 address not aligned");
+        self.page_or_alloc(index)[index % PAGE_SIZE].store(value, Ordering::Relaxed);
+        crate::cache::invalidate(index);
 
-            INT_CONTROLLER.lock().unwrap().gen_int(1, true);
+        Ok(())
+    }
+
+    /// Copies `bytes` in without recording the writes, zeros are only
+    /// stored on pages that are already allocated so the rest can stay
+    /// unallocated
+    fn fill(&self, index: usize, bytes: &[u8]) -> Result<(), Fault> {
+        self.range(index, bytes.len())?;
+
+        for (index, byte) in (index..).zip(bytes) {
+            match self.page(index) {
+                Some(page) => page[index % PAGE_SIZE].store(*byte, Ordering::Relaxed),
+                None if *byte != 0 => self.page_or_alloc(index)[index % PAGE_SIZE].store(*byte, Ordering::Relaxed),
+                None => (),
+            }
         }
 
-        self.write_u16(index, num);
+        Ok(())
     }
 
-    pub fn write_u16(&mut self, index: usize, num: u16) {
-        let bytes = num.to_le_bytes();
+    /// Copy of the `len` bytes at `index`
+    pub fn read_bytes(&self, index: usize, len: usize) -> Result<Vec<u8>, Fault> {
+        self.range(index, len)?;
 
-        self[index] = bytes[0];
-        self[index + 1] = bytes[1];
+        Ok((index..index + len).map(|index| self.load(index)).collect())
     }
 
-    pub fn vm_write_u32(&mut self, index: usize, num: u32) {
-        //println!("Writing 0x{:x} to 0x{:x}", num, index);
+    fn read<const N: usize>(&self, index: usize) -> Result<[u8; N], Fault> {
+        self.range(index, N)?;
 
-        if index & 0b11 != 0 {
-            log::warn!("u32 address not aligned");
+        Ok(core::array::from_fn(|i| self.load(index + i)))
+    }
+
+    /// Copies `bytes` in starting at `index`, nothing is written if they do
+    /// not all fit
+    pub fn write(&self, index: usize, bytes: &[u8]) -> Result<(), Fault> {
+        self.range(index, bytes.len())?;
 
-            INT_CONTROLLER.lock().unwrap().gen_int(1, true);
+        for (offset, byte) in bytes.iter().enumerate() {
+            self.set(index + offset, *byte)?;
         }
 
-        self.write_u32(index, num);
+        Ok(())
     }
 
-    pub fn write_u32(&mut self, index: usize, num: u32) {
-        let bytes = num.to_le_bytes();
+    pub fn vm_read_u16(&self, index: usize) -> Result<u16, Fault> {
+        aligned(index, 2)?;
+        self.read_u16(index)
+    }
 
-        self[index] = bytes[0];
-        self[index + 1] = bytes[1];
-        self[index + 2] = bytes[2];
-        self[index + 3] = bytes[3];
+    pub fn read_u16(&self, index: usize) -> Result<u16, Fault> {
+        self.read(index).map(u16::from_le_bytes)
     }
 
-    pub fn write_u64(&mut self, index: usize, num: u64) {
-        assert!(index & 0b111 == 0, "Address for long not aligned");
+    pub fn vm_read_u32(&self, index: usize) -> Result<u32, Fault> {
+        aligned(index, 4)?;
+        self.read_u32(index)
+    }
 
-        let bytes = num.to_le_bytes();
+    pub fn read_u32(&self, index: usize) -> Result<u32, Fault> {
+        self.read(index).map(u32::from_le_bytes)
+    }
 
-        self[index] = bytes[0];
-        self[index + 1] = bytes[1];
-        self[index + 2] = bytes[2];
-        self[index + 3] = bytes[3];
-        self[index + 4] = bytes[4];
-        self[index + 5] = bytes[5];
-        self[index + 6] = bytes[6];
-        self[index + 7] = bytes[7];
+    pub fn read_u64(&self, index: usize) -> Result<u64, Fault> {
+        aligned(index, 8)?;
+        self.read(index).map(u64::from_le_bytes)
     }
-}
 
-use core::ops::{ Index, IndexMut };
+    pub fn vm_write_u16(&self, index: usize, num: u16) -> Result<(), Fault> {
+        aligned(index, 2)?;
+        self.write_u16(index, num)
+    }
 
-use crate::INT_CONTROLLER;
+    pub fn write_u16(&self, index: usize, num: u16) -> Result<(), Fault> {
+        self.write(index, &num.to_le_bytes())
+    }
 
-impl Index<usize> for Memory {
-    type Output = u8;
+    pub fn vm_write_u32(&self, index: usize, num: u32) -> Result<(), Fault> {
+        aligned(index, 4)?;
+        self.write_u32(index, num)
+    }
 
-    fn index(&self, rhs: usize) -> &u8 {
-        if rhs >= self.size {
-            INT_CONTROLLER.lock().unwrap().gen_int(2, true);
+    pub fn write_u32(&self, index: usize, num: u32) -> Result<(), Fault> {
+        self.write(index, &num.to_le_bytes())
+    }
 
-            panic!("index out of bounds: the len is {} but the index is {}", self.size, rhs);
-        }
+    pub fn write_u64(&self, index: usize, num: u64) -> Result<(), Fault> {
+        aligned(index, 8)?;
+        self.write(index, &num.to_le_bytes())
+    }
+}
 
-        unsafe { return &*self.base.add(rhs); }
+fn aligned(index: usize, width: usize) -> Result<(), Fault> {
+    if !index.is_multiple_of(width) {
+        return Err(Fault::Misaligned);
     }
+
+    Ok(())
 }
 
-impl IndexMut<usize> for Memory {
-    fn index_mut(&mut self, rhs: usize) -> &mut u8 {
-        if rhs > self.size {
-            INT_CONTROLLER.lock().unwrap().gen_int(2, true);
+impl Clone for Memory {
+    fn clone(&self) -> Self {
+        // The last page is cut short when memory ends inside it
+        let pages: Vec<_> = self.resident_pages().into_iter().map(|page| {
+            let start = page * PAGE_SIZE;
+            (start, self.read_bytes(start, PAGE_SIZE.min(self.size - start)).expect("Resident page is in memory"))
+        }).collect();
 
-            panic!("index out of bounds: the len is {} but the index is {}", self.size, rhs);
-        }
-        
-        unsafe { return &mut *self.base.add(rhs); }
+        Memory::from_pages(self.size, pages.iter().map(|(start, bytes)| (*start, &bytes[..])))
+            .expect("Clone has the same size")
     }
 }
 
-unsafe impl Send for Memory {} 
-unsafe impl Sync for Memory {}
\ No newline at end of file
+impl fmt::Debug for Memory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Memory")
+            .field("size", &self.size)
+            .field("resident", &self.resident())
+            .finish()
+    }
+}
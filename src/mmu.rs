@@ -1,173 +1,479 @@
-use crate::{INT_CONTROLLER, OUT_PUT_READY};
+use std::sync::Mutex;
+
+use crate::{
+    int_controller,
+    machine::{Devices, Register},
+    memory::Fault,
+    stack::Stack,
+    OUT_PUT_READY,
+};
 
 pub struct MMU {
-    io_base: u32,
-    io_max: u32,
-    memory_base: u32,
-    memory_max: u32
+    pub io_base: u32,
+    pub io_max: u32,
+    pub memory_base: u32,
+    pub memory_max: u32,
+    /// Devices attached to the IO window
+    pub devices: Devices,
 }
 
 impl MMU {
     pub const fn new(io_base: u32, io_max: u32, memory_base: u32, memory_max: u32) -> Self {
-        Self { io_base, io_max, memory_base, memory_max }
+        Self { io_base, io_max, memory_base, memory_max, devices: Devices::DEFAULT }
+    }
+
+    pub fn is_io(&self, index: u32) -> bool {
+        (index <= self.io_max) && (index >= self.io_base)
+    }
+
+    /// Index in `MEM` of a RAM address
+    fn ram_index(&self, index: u32) -> Result<usize, Fault> {
+        if self.is_io(index) || index < self.memory_base || index > self.memory_max {
+            return Err(Fault::OutOfBounds);
+        }
+
+        Ok((index - self.memory_base) as usize)
+    }
+
+    /// Reads memory without faulting or side effects, `None` for IO and
+    /// unmapped addresses
+    pub fn peek(&self, index: u32, width: u8) -> Option<u32> {
+        let index = self.ram_index(index).ok()?;
+        let memory = crate::mem();
+
+        match width {
+            32 => memory.read_u32(index).ok(),
+            _ => memory.read_u16(index).ok().map(u32::from),
+        }
+    }
+
+    /// Replaces the value at `index` with what `update` returns for it, and
+    /// returns the old value. Only memory can be updated, the caller holds
+    /// the lock of the MMU for the whole access.
+    pub fn atomic(&self, index: u32, width: u8, update: impl FnOnce(u32) -> Option<u32>) -> u32 {
+        if self.is_io(index) {
+            int_controller().lock().unwrap().gen_exception(crate::sic::IO_ATOMIC);
+            log::warn!("Atomic access to IO address: 0x{:x}", index);
+            return 0;
+        }
+
+        let old = match width {
+            32 => self.read_u32(index),
+            _ => self.read_u16(index) as u32,
+        };
+
+        // The read has already raised a fault for anything it could not reach
+        if self.peek(index, width).is_none() || index & (width as u32 / 8 - 1) != 0 {
+            return old;
+        }
+
+        if let Some(new) = update(old) {
+            match width {
+                32 => self.write_u32(index, new),
+                _ => self.write_u16(index, new as u16),
+            }
+        }
+
+        old
     }
 
     pub fn read_u16(&self, index: u32) -> u16 {
-        if (index <= self.io_max) && (index >= self.io_base) {
-            match index {
-                0x300 => {
-                    crate::INT_CONTROLLER.lock().unwrap().jmp as u16
+        crate::timing::access(self.is_io(index));
+        self.read_cell(index)
+    }
+
+    /// Reads 16 bits for a stack, like `read_u16` without the access penalty
+    /// as stack traffic is part of the cost of every instruction
+    pub fn read_cell(&self, index: u32) -> u16 {
+        let value = if (index <= self.io_max) && (index >= self.io_base) {
+            match self.devices.register(index) {
+                Some(Register::CoreId) => {
+                    crate::cpu::id() as u16
+                },
+                Some(Register::CoreCount) => {
+                    crate::cpu::count() as u16
+                },
+                Some(Register::PrimaryPosition) => {
+                    crate::primary_stack().lock().unwrap().location() as u16
+                },
+                Some(Register::ReturnPosition) => {
+                    crate::return_stack().lock().unwrap().location() as u16
+                },
+                Some(Register::PrimaryOffset) => {
+                    crate::primary_stack().lock().unwrap().offset()
+                },
+                Some(Register::ReturnOffset) => {
+                    crate::return_stack().lock().unwrap().offset()
+                },
+                Some(Register::Cycles(shift)) => {
+                    (crate::timing::cycles() >> shift) as u16
+                },
+                Some(Register::Input) => {
+                    crate::input::read()
+                },
+                Some(Register::InputEnded) => {
+                    crate::input::ended() as u16
+                },
+                Some(Register::Jmp) => {
+                    crate::int_controller().lock().unwrap().jmp as u16
                 },
-                0x304 => {
-                    crate::INT_CONTROLLER.lock().unwrap().cause as u16
+                Some(Register::Cause) => {
+                    crate::int_controller().lock().unwrap().cause as u16
                 },
-                0x308 => {
-                    crate::INT_CONTROLLER.lock().unwrap().return_addr as u16
+                Some(Register::ReturnAddr) => {
+                    crate::int_controller().lock().unwrap().return_addr as u16
+                },
+                Some(Register::Vector) => {
+                    crate::int_controller().lock().unwrap().vector as u16
+                },
+                Some(Register::VectorTable) => {
+                    crate::int_controller().lock().unwrap().table as u16
                 },
                 _ => {
-                    INT_CONTROLLER.lock().unwrap().gen_int(2, true);
-                    println!("Invaled IO read address: 0x{:x}", index);
+                    int_controller().lock().unwrap().gen_exception(crate::sic::UNKNOWN_IO);
+                    log::warn!("Invaled IO read address: 0x{:x}", index);
                     0
                 }
             }
-        } else if (index <= self.memory_max) && (index >= self.memory_base) {
-            unsafe {
-                crate::MEM.vm_read_u16((index - self.memory_base) as usize)
-            }
         } else {
-            INT_CONTROLLER.lock().unwrap().gen_int(0, true);
-            println!("Unknown memory address: 0x{:x}", index);
-            0
-        }
+            let value = self.ram_index(index).and_then(|ram| {
+                let value = crate::mem().vm_read_u16(ram)?;
+                crate::shadow::read(index, ram, 16);
+
+                Ok(value)
+            });
+
+            match value {
+                Ok(value) => value,
+                Err(fault) => {
+                    raise(fault, false, index);
+                    0
+                }
+            }
+        };
+
+        crate::trace::access(false, self.is_io(index), index, 16, value as u32);
+        crate::watch::read(index, 16, value as u32);
+
+        value
     }
 
     pub fn read_u32(&self, index: u32) -> u32 {
-        if (index <= self.io_max) && (index >= self.io_base) {
-            match index {
-                0x300 => {
-                    crate::INT_CONTROLLER.lock().unwrap().jmp
+        let value = if (index <= self.io_max) && (index >= self.io_base) {
+            match self.devices.register(index) {
+                Some(Register::CoreId) => {
+                    crate::cpu::id() as u32
+                },
+                Some(Register::CoreCount) => {
+                    crate::cpu::count() as u32
+                },
+                Some(Register::PrimaryPosition) => {
+                    crate::primary_stack().lock().unwrap().location()
+                },
+                Some(Register::ReturnPosition) => {
+                    crate::return_stack().lock().unwrap().location()
                 },
-                0x304 => {
-                    crate::INT_CONTROLLER.lock().unwrap().cause
+                Some(Register::PrimaryOffset) => {
+                    let primary = crate::primary_stack().lock().unwrap().offset() as u32;
+                    primary << 16 | crate::return_stack().lock().unwrap().offset() as u32
                 },
-                0x308 => {
-                    crate::INT_CONTROLLER.lock().unwrap().return_addr
+                Some(Register::ReturnOffset) => {
+                    crate::return_stack().lock().unwrap().offset() as u32
+                },
+                Some(Register::Cycles(shift)) => {
+                    (crate::timing::cycles() >> shift) as u32
+                },
+                Some(Register::Input) => {
+                    crate::input::read() as u32
+                },
+                Some(Register::InputEnded) => {
+                    crate::input::ended() as u32
+                },
+                Some(Register::Jmp) => {
+                    crate::int_controller().lock().unwrap().jmp
+                },
+                Some(Register::Cause) => {
+                    crate::int_controller().lock().unwrap().cause
+                },
+                Some(Register::ReturnAddr) => {
+                    crate::int_controller().lock().unwrap().return_addr
+                },
+                Some(Register::Vector) => {
+                    crate::int_controller().lock().unwrap().vector
+                },
+                Some(Register::VectorTable) => {
+                    crate::int_controller().lock().unwrap().table
                 },
                 _ => {
-                    INT_CONTROLLER.lock().unwrap().gen_int(2, true);
-                    println!("Invaled IO read address: 0x{:x}", index);
+                    int_controller().lock().unwrap().gen_exception(crate::sic::UNKNOWN_IO);
+                    log::warn!("Invaled IO read address: 0x{:x}", index);
                     0
                 }
             }
-        } else if (index <= self.memory_max) && (index >= self.memory_base) {
-            unsafe {
-                crate::MEM.vm_read_u32((index - self.memory_base) as usize)
-            }
         } else {
-            INT_CONTROLLER.lock().unwrap().gen_int(0, true);
-            println!("Unknown memory address: 0x{:x}", index);
-            0
-        }
+            let value = self.ram_index(index).and_then(|ram| {
+                let value = crate::mem().vm_read_u32(ram)?;
+                crate::shadow::read(index, ram, 32);
+
+                Ok(value)
+            });
+
+            match value {
+                Ok(value) => value,
+                Err(fault) => {
+                    raise(fault, false, index);
+                    0
+                }
+            }
+        };
+
+        crate::trace::access(false, self.is_io(index), index, 32, value);
+        crate::timing::access(self.is_io(index));
+        crate::watch::read(index, 32, value);
+
+        value
     }
 
     pub fn write_u16(&self, index: u32, num: u16) {
+        crate::timing::access(self.is_io(index));
+        self.write_cell(index, num)
+    }
+
+    /// Writes 16 bits for a stack, like `write_u16` without the access penalty
+    pub fn write_cell(&self, index: u32, num: u16) {
+        crate::trace::access(true, self.is_io(index), index, 16, num as u32);
+        if crate::watch::WATCHING.load(std::sync::atomic::Ordering::Relaxed) {
+            crate::watch::write(index, 16, self.peek(index, 16), num as u32);
+        }
+
         if (index <= self.io_max) && (index >= self.io_base) {
-            match index {
-                0x00 => {
-                    unsafe {
-                        crate::PRIMARY_STACK.lock().unwrap().set_pos(num as u32)
-                    }
+            match self.devices.register(index) {
+                Some(Register::PrimaryPosition) => {
+                    self.set_stack_pos(crate::primary_stack(), num as u32);
                 },
-                0x04 => {
-                    unsafe {
-                        crate::RETURN_STACK.lock().unwrap().set_pos(num as u32)
-                    }
+                Some(Register::ReturnPosition) => {
+                    self.set_stack_pos(crate::return_stack(), num as u32);
                 },
-                0x08 => {
-                    unsafe {
-                        crate::PRIMARY_STACK.lock().unwrap().set_offset(num)
-                    }
+                Some(Register::PrimaryOffset) => {
+                    set_stack_offset(crate::primary_stack(), num);
                 },
-                0xA => {
-                    unsafe {
-                        crate::RETURN_STACK.lock().unwrap().set_offset(num)
-                    }
+                Some(Register::ReturnOffset) => {
+                    set_stack_offset(crate::return_stack(), num);
+                },
+                Some(Register::SaveContext) => {
+                    self.save_context(num as u32);
+                },
+                Some(Register::RestoreContext) => {
+                    self.restore_context(num as u32);
+                },
+                Some(Register::SoftwareInterrupt) => {
+                    int_controller().lock().unwrap().gen_int(0, false);
                 },
-                0x0C => {
-                    INT_CONTROLLER.lock().unwrap().gen_int(0, false);
+                Some(Register::Ipi) => {
+                    crate::cpu::send_ipi(num as u32);
                 },
-                0x100 => {
-                    unsafe {
+                Some(Register::Output) => {
+                    if crate::limits::output() {
                         use std::sync::atomic::Ordering;
-                        
+
                         log::info!("Giving data to output device");
-                        crate::IO_SEND.send_data(num as u8).expect("Failed to send to io");
                         OUT_PUT_READY.store(false, Ordering::Relaxed);
+                        crate::IO_SEND.lock().unwrap().send_data(num as u8).expect("Failed to send to io");
                     }
                 },
-                0x300 => {
+                Some(Register::Jmp) => {
                     log::info!("Writing jump for SIC");
-                    crate::INT_CONTROLLER.lock().unwrap().jmp = num as u32
+                    crate::int_controller().lock().unwrap().jmp = num as u32
+                },
+                Some(Register::VectorTable) => {
+                    log::info!("Writing vector table for SIC");
+                    crate::int_controller().lock().unwrap().table = num as u32
                 },
                 _ => {
-                    INT_CONTROLLER.lock().unwrap().gen_int(3, true);
+                    int_controller().lock().unwrap().gen_exception(crate::sic::UNKNOWN_IO);
                     log::warn!("Invaled IO write address: 0x{:x}", index)
                 }
             }
-        } else if (index <= self.memory_max) && (index >= self.memory_base) {
-            unsafe {
-                crate::MEM.vm_write_u16((index - self.memory_base) as usize , num)
-            }
-        } else {
-            INT_CONTROLLER.lock().unwrap().gen_int(1, true);
-            println!("Unknown memory address: 0x{:x}", index);
+        } else if let Err(fault) = self.ram_index(index).and_then(|ram| crate::mem().vm_write_u16(ram, num)) {
+            raise(fault, true, index);
         }
     }
 
     pub fn write_u32(&self, index: u32, num: u32) {
+        crate::trace::access(true, self.is_io(index), index, 32, num);
+        crate::timing::access(self.is_io(index));
+        crate::watch::write(index, 32, self.peek(index, 32), num);
+
         if (index <= self.io_max) && (index >= self.io_base) {
-            match index {
-                0x00 => {
-                    unsafe {
-                        crate::PRIMARY_STACK.lock().unwrap().set_pos(num)
-                    }
+            match self.devices.register(index) {
+                Some(Register::PrimaryPosition) => {
+                    self.set_stack_pos(crate::primary_stack(), num);
                 },
-                0x04 => {
-                    unsafe {
-                        crate::RETURN_STACK.lock().unwrap().set_pos(num)
-                    }
+                Some(Register::ReturnPosition) => {
+                    self.set_stack_pos(crate::return_stack(), num);
                 },
-                0x08 => {
-                    unsafe {
-                        crate::PRIMARY_STACK.lock().unwrap().set_offset((num >> 16) as u16);
-                        crate::RETURN_STACK.lock().unwrap().set_offset(num as u16);
-                    }
+                Some(Register::PrimaryOffset) => {
+                    set_stack_offset(crate::primary_stack(), (num >> 16) as u16);
+                    set_stack_offset(crate::return_stack(), num as u16);
+                },
+                Some(Register::ReturnOffset) => {
+                    set_stack_offset(crate::return_stack(), num as u16);
+                },
+                Some(Register::SaveContext) => {
+                    self.save_context(num);
+                },
+                Some(Register::RestoreContext) => {
+                    self.restore_context(num);
                 },
-                0x0C => {
-                    INT_CONTROLLER.lock().unwrap().gen_int(0, false);
+                Some(Register::SoftwareInterrupt) => {
+                    int_controller().lock().unwrap().gen_int(0, false);
                 },
-                0x100 => {
-                    unsafe {
+                Some(Register::Ipi) => {
+                    crate::cpu::send_ipi(num);
+                },
+                Some(Register::Output) => {
+                    if crate::limits::output() {
                         log::info!("Giving data to output device");
-                        crate::IO_SEND.send_data(num as u8).expect("Failed to send to io");
+                        crate::IO_SEND.lock().unwrap().send_data(num as u8).expect("Failed to send to io");
                     }
                 },
-                0x300 => {
+                Some(Register::Jmp) => {
                     log::info!("Writing jump for SIC");
-                    crate::INT_CONTROLLER.lock().unwrap().jmp = num
+                    crate::int_controller().lock().unwrap().jmp = num
+                },
+                Some(Register::VectorTable) => {
+                    log::info!("Writing vector table for SIC");
+                    crate::int_controller().lock().unwrap().table = num
                 },
                 _ => {
-                    INT_CONTROLLER.lock().unwrap().gen_int(3, true);
+                    int_controller().lock().unwrap().gen_exception(crate::sic::UNKNOWN_IO);
                     log::warn!("Invaled IO write address: 0x{:x}", index)
                 }
             }
-        } else if (index <= self.memory_max) && (index >= self.memory_base) {
+        } else if let Err(fault) = self.ram_index(index).and_then(|ram| crate::mem().vm_write_u32(ram, num)) {
+            raise(fault, true, index);
+        }
+    }
+
+    /// Whether a stack of `size` bytes fits below `location`, it has to keep
+    /// its cells aligned and stay clear of the registers that reach into the
+    /// stacks, which are written while the stack is held
+    pub fn stack_fits(&self, location: u32, size: u16) -> bool {
+        let Some(bottom) = location.checked_sub(size as u32 - 1) else {
+            return false;
+        };
+
+        let stack_registers = (bottom.max(self.io_base)..=location.min(self.io_max)).any(|addr| {
+            matches!(
+                self.devices.register(addr),
+                Some(
+                    Register::PrimaryPosition | Register::ReturnPosition | Register::PrimaryOffset
+                        | Register::ReturnOffset | Register::SaveContext | Register::RestoreContext
+                )
+            )
+        });
+
+        location % 2 == 1 && !stack_registers
+    }
+
+    /// Moves the base of a stack, faulting when the stack would not fit
+    fn set_stack_pos(&self, stack: &Mutex<Stack>, location: u32) {
+        let mut stack = stack.lock().unwrap();
+
+        if !self.stack_fits(location, stack.size()) {
+            int_controller().lock().unwrap().gen_exception(crate::sic::BAD_STACK);
+            log::warn!("Stack does not fit at 0x{:x}", location);
+            return;
+        }
+
+        unsafe { stack.set_pos(location) }
+    }
+
+    /// Whether the 12 byte context block at `addr` is aligned RAM
+    fn context_fits(&self, addr: u32) -> bool {
+        addr.is_multiple_of(4)
+            && addr.checked_add(8).is_some_and(|last| self.peek(addr, 32).is_some() && self.peek(last, 32).is_some())
+    }
+
+    /// Stores the position and offset of both stacks to the context block
+    /// at `addr`
+    fn save_context(&self, addr: u32) {
+        if !self.context_fits(addr) {
+            int_controller().lock().unwrap().gen_exception(crate::sic::BAD_CONTEXT);
+            log::warn!("Context block does not fit at 0x{:x}", addr);
+            return;
+        }
+
+        let [primary, ret] = [crate::primary_stack(), crate::return_stack()]
+            .map(|stack| {
+                let stack = stack.lock().unwrap();
+                (stack.location(), stack.offset())
+            });
+
+        self.write_u32(addr, primary.0);
+        self.write_u32(addr + 4, ret.0);
+        self.write_u16(addr + 8, primary.1);
+        self.write_u16(addr + 10, ret.1);
+    }
+
+    /// Moves both stacks to the positions and offsets in the context block
+    /// at `addr`, only once both of them are known to fit
+    fn restore_context(&self, addr: u32) {
+        if !self.context_fits(addr) {
+            int_controller().lock().unwrap().gen_exception(crate::sic::BAD_CONTEXT);
+            log::warn!("Context block does not fit at 0x{:x}", addr);
+            return;
+        }
+
+        let locations = [self.read_u32(addr), self.read_u32(addr + 4)];
+        let offsets = [self.read_u16(addr + 8), self.read_u16(addr + 10)];
+
+        let mut stacks = [crate::primary_stack().lock().unwrap(), crate::return_stack().lock().unwrap()];
+
+        let fits = stacks.iter().zip(locations).zip(offsets)
+            .all(|((stack, location), offset)| offset <= stack.size() && self.stack_fits(location, stack.size()));
+
+        if !fits {
+            int_controller().lock().unwrap().gen_exception(crate::sic::BAD_CONTEXT);
+            log::warn!("Context block at 0x{:x} holds stacks that do not fit", addr);
+            return;
+        }
+
+        for ((stack, location), offset) in stacks.iter_mut().zip(locations).zip(offsets) {
             unsafe {
-                crate::MEM.vm_write_u32((index - self.memory_base) as usize , num)
+                stack.set_pos(location);
+                stack.set_offset(offset);
             }
-        } else {
-            INT_CONTROLLER.lock().unwrap().gen_int(1, true);
-            println!("Unknown memory address: 0x{:x}", index);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Raises the exception for a RAM access that did not reach memory
+fn raise(fault: Fault, write: bool, index: u32) {
+    let vector = match (fault, write) {
+        (Fault::Misaligned, false) => crate::sic::MISALIGNED_READ,
+        (Fault::OutOfBounds, false) => crate::sic::UNMAPPED_READ,
+        (Fault::Misaligned, true) => crate::sic::MISALIGNED_WRITE,
+        (Fault::OutOfBounds, true) => crate::sic::UNMAPPED_WRITE,
+    };
+
+    int_controller().lock().unwrap().gen_exception(vector);
+
+    match fault {
+        Fault::Misaligned => log::warn!("VM address not aligned 0x{:x}", index),
+        Fault::OutOfBounds => log::warn!("Unknown memory address: 0x{:x}", index),
+    }
+}
+
+/// Moves the top of a stack, faulting when it is past the end of the stack
+fn set_stack_offset(stack: &Mutex<Stack>, offset: u16) {
+    let mut stack = stack.lock().unwrap();
+
+    if offset > stack.size() {
+        int_controller().lock().unwrap().gen_exception(crate::sic::BAD_STACK);
+        log::warn!("Stack offset out of range: 0x{:x}", offset);
+        return;
+    }
+
+    unsafe { stack.set_offset(offset) }
+}
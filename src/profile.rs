@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    sync::{atomic::{AtomicBool, Ordering}, Mutex},
+};
+
+use crate::{instructions::{Instr, Status}, symbols::Symbols};
+
+pub static PROFILER: Mutex<Option<Profiler>> = Mutex::new(None);
+pub static PROFILING: AtomicBool = AtomicBool::new(false);
+
+pub struct Profiler {
+    total: u64,
+    by_ip: HashMap<u32, u64>,
+    by_opcode: [u64; 256],
+    /// Call stacks as the entry of every active routine, outermost first
+    stacks: HashMap<Vec<u32>, u64>,
+    /// Routine entered by the `jsr` that pushed each return address
+    calls: HashMap<u32, u32>,
+    entry: u32,
+}
+
+impl Profiler {
+    pub fn new(entry: u32) -> Self {
+        Self {
+            total: 0,
+            by_ip: HashMap::new(),
+            by_opcode: [0; 256],
+            stacks: HashMap::new(),
+            calls: HashMap::new(),
+            entry,
+        }
+    }
+
+    /// Sorted text report of where instructions were spent
+    pub fn report(&self, symbols: &Symbols) -> String {
+        let mut out = String::new();
+
+        writeln!(out, "Total instructions: {}", self.total).unwrap();
+        writeln!(out).unwrap();
+        writeln!(out, "Instructions by address").unwrap();
+        writeln!(out, "{:>12} {:>8}  {:<10}  location", "count", "%", "address").unwrap();
+
+        let mut by_ip: Vec<_> = self.by_ip.iter().collect();
+        by_ip.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        for (ip, count) in by_ip {
+            writeln!(
+                out, "{:>12} {:>7.2}%  {:<10}  {}",
+                count, self.percent(*count), format!("0x{:x}", ip), symbols.describe(*ip)
+            ).unwrap();
+        }
+
+        writeln!(out).unwrap();
+        writeln!(out, "Instructions by opcode").unwrap();
+        writeln!(out, "{:>12} {:>8}  opcode", "count", "%").unwrap();
+
+        let mut by_opcode: Vec<_> = self.by_opcode.iter().enumerate().filter(|(_, count)| **count != 0).collect();
+        by_opcode.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(&b.0)));
+
+        for (opcode, count) in by_opcode {
+            writeln!(
+                out, "{:>12} {:>7.2}%  {:?}",
+                count, self.percent(*count), Instr::from_byte(opcode as u8)
+            ).unwrap();
+        }
+
+        out
+    }
+
+    /// Call stacks in the folded format read by flamegraph tools
+    pub fn folded(&self, symbols: &Symbols) -> String {
+        let mut lines: Vec<String> = self.stacks.iter().map(|(stack, count)| {
+            let frames: Vec<String> = stack.iter().map(|addr| frame_name(symbols, *addr)).collect();
+
+            format!("{} {}", frames.join(";"), count)
+        }).collect();
+
+        lines.sort();
+
+        let mut out = lines.join("\n");
+        out.push('\n');
+        out
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        count as f64 * 100.0 / self.total.max(1) as f64
+    }
+}
+
+pub fn start(entry: u32) {
+    *PROFILER.lock().unwrap() = Some(Profiler::new(entry));
+    PROFILING.store(true, Ordering::Relaxed);
+}
+
+pub fn finish() -> Option<Profiler> {
+    PROFILING.store(false, Ordering::Relaxed);
+
+    PROFILER.lock().unwrap().take()
+}
+
+/// Counts an instruction about to run at `ip`
+pub fn record(ip: u32, instr: &Instr) {
+    if !PROFILING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let return_addrs = return_addresses();
+
+    if let Some(profiler) = PROFILER.lock().unwrap().as_mut() {
+        profiler.total += 1;
+        *profiler.by_ip.entry(ip).or_insert(0) += 1;
+        profiler.by_opcode[*instr as usize] += 1;
+
+        let mut stack = vec![profiler.entry];
+        stack.extend(return_addrs.iter().filter_map(|ret| profiler.calls.get(ret)));
+
+        *profiler.stacks.entry(stack).or_insert(0) += 1;
+    }
+}
+
+/// Remembers where a `jsr` went so its return address can be turned back
+/// into a routine later
+pub fn record_call(ip: u32, status: Status, target: u32) {
+    if !PROFILING.load(Ordering::Relaxed) || status.contains(Status::RETURN) {
+        return;
+    }
+
+    if let Some(profiler) = PROFILER.lock().unwrap().as_mut() {
+        profiler.calls.insert(ip + 2, target);
+    }
+}
+
+fn return_addresses() -> Vec<u32> {
+    let stack = crate::return_stack().lock().unwrap();
+
+    (0..stack.top() / 4 * 4)
+        .step_by(4)
+        .map(|index| stack.copy(index, Status::SHORT))
+        .collect()
+}
+
+fn frame_name(symbols: &Symbols, addr: u32) -> String {
+    match symbols.get(addr) {
+        Some(name) => name.to_string(),
+        None => format!("0x{:x}", addr),
+    }
+}
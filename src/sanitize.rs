@@ -0,0 +1,259 @@
+/*
+Stack sanitizer, enabled with `--sanitize-stacks`
+
+Every cell pushed is tagged with the width of the value it belongs to, so a
+pop that takes a value apart or glues two together is caught. Cells the
+sanitizer did not see pushed, such as after a stack is moved through IO,
+are not checked.
+
+| problem   | desc                                                      |
+| --------- | --------------------------------------------------------- |
+| mismatch  | a value popped at another width than it was pushed with   |
+| underflow | a pop from a stack holding less than the value            |
+| imbalance | a `jsr$r` returning with the return stack at another      |
+|           | depth than the `jsr` that called it left it at            |
+
+An interrupt opens a frame of its own, which the return of its handler
+closes without a check. Every problem is printed as it is found along with
+the last `HISTORY` instructions of the core, and kept for `finish`. Only
+`crate::step` is instrumented, the predecoded loop and translated programs
+run unchecked.
+*/
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{atomic::{AtomicBool, Ordering}, Mutex},
+};
+
+use crate::{
+    cpu,
+    instructions::{Instr, Instruction, Status},
+};
+
+pub static SANITIZING: AtomicBool = AtomicBool::new(false);
+static CORES: Mutex<Vec<CoreState>> = Mutex::new(Vec::new());
+static REPORTS: Mutex<Vec<Report>> = Mutex::new(Vec::new());
+
+/// Instructions kept for every report
+pub const HISTORY: usize = 16;
+/// Calls remembered per core, the oldest are forgotten past this
+const MAX_FRAMES: usize = 0x1000;
+
+/// Width of the value a cell was pushed as part of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    Unknown,
+    /// A whole 16 bit value
+    Cell,
+    /// Low half of a 32 bit value
+    Low,
+    /// High half of a 32 bit value
+    High,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    Mismatch { ret: bool, popped: u8, pushed: u8 },
+    Underflow { ret: bool, width: u8 },
+    Imbalance { call: u32, expected: u16, found: u16 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub core: usize,
+    /// Address of the instruction that ran into the problem
+    pub ip: u32,
+    pub problem: Problem,
+    /// Instructions that led up to it, oldest first
+    pub history: Vec<(u32, Instr, Status)>,
+}
+
+#[derive(Debug, Default)]
+struct CoreState {
+    history: VecDeque<(u32, Instr, Status)>,
+    frames: Vec<Frame>,
+    /// Return stack offset before the current instruction
+    ret_depth: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Frame {
+    Call { call: u32, depth: u16 },
+    Interrupt,
+}
+
+pub fn start() {
+    *CORES.lock().unwrap() = (0..cpu::MAX_CORES).map(|_| CoreState::default()).collect();
+    REPORTS.lock().unwrap().clear();
+    SANITIZING.store(true, Ordering::Relaxed);
+}
+
+/// Stops checking and hands back every problem found
+pub fn finish() -> Vec<Report> {
+    SANITIZING.store(false, Ordering::Relaxed);
+
+    std::mem::take(&mut *REPORTS.lock().unwrap())
+}
+
+pub fn enabled() -> bool {
+    SANITIZING.load(Ordering::Relaxed)
+}
+
+/// Notes an instruction about to run at `ip`
+pub fn begin(ip: u32, instruction: &Instruction) {
+    if !enabled() {
+        return;
+    }
+
+    let ret_depth = crate::top(true) as u16;
+
+    if let Some(core) = CORES.lock().unwrap().get_mut(cpu::id()) {
+        if core.history.len() == HISTORY {
+            core.history.pop_front();
+        }
+
+        core.history.push_back((ip, *instruction.instr(), instruction.status()));
+        core.ret_depth = ret_depth;
+    }
+}
+
+/// Follows the calls and returns of an executed `jsr` at `ip`
+pub fn jsr(ip: u32, status: Status) {
+    if !enabled() {
+        return;
+    }
+
+    let depth = crate::top(true) as u16;
+    let mut cores = CORES.lock().unwrap();
+    let Some(core) = cores.get_mut(cpu::id()) else { return };
+
+    if !status.contains(Status::RETURN) {
+        // A jsr that faulted never pushed its return address
+        if depth == core.ret_depth + 4 {
+            if core.frames.len() == MAX_FRAMES {
+                core.frames.remove(0);
+            }
+
+            core.frames.push(Frame::Call { call: ip, depth });
+        }
+
+        return;
+    }
+
+    let found = core.ret_depth;
+    if let Some(Frame::Call { call, depth: expected }) = core.frames.pop() {
+        if found != expected {
+            drop(cores);
+            report(Problem::Imbalance { call, expected, found });
+        }
+    }
+}
+
+/// Opens a frame for an interrupt being taken
+pub fn interrupt() {
+    if !enabled() {
+        return;
+    }
+
+    if let Some(core) = CORES.lock().unwrap().get_mut(cpu::id()) {
+        core.frames.push(Frame::Interrupt);
+    }
+}
+
+/// Tags the cells of a value pushed at `offset`
+pub fn pushed(tags: &mut Vec<Tag>, offset: u16, flags: Status) {
+    tags.resize(offset as usize / 2, Tag::Unknown);
+
+    if flags.contains(Status::SHORT) {
+        tags.extend([Tag::Low, Tag::High]);
+    } else {
+        tags.push(Tag::Cell);
+    }
+}
+
+/// Checks the cells of a value popped from `offset`, which has to hold it
+pub fn popped(tags: &mut Vec<Tag>, offset: u16, flags: Status) {
+    let cells = offset as usize / 2;
+    let tag = |cell: usize| tags.get(cell).copied().unwrap_or(Tag::Unknown);
+    let ret = flags.contains(Status::RETURN);
+
+    let (top, remaining) = if flags.contains(Status::SHORT) {
+        let (high, low) = (tag(cells - 1), tag(cells - 2));
+        let whole = matches!(high, Tag::High | Tag::Unknown) && matches!(low, Tag::Low | Tag::Unknown);
+
+        if !whole {
+            report(Problem::Mismatch { ret, popped: 32, pushed: width(high) });
+        }
+
+        (high, cells - 2)
+    } else {
+        (tag(cells - 1), cells - 1)
+    };
+
+    if !flags.contains(Status::SHORT) && matches!(top, Tag::Low | Tag::High) {
+        report(Problem::Mismatch { ret, popped: 16, pushed: 32 });
+    }
+
+    tags.truncate(remaining);
+}
+
+/// Reports a pop of `flags` from a stack that does not hold it
+pub fn underflow(flags: Status) {
+    let width = if flags.contains(Status::SHORT) { 32 } else { 16 };
+
+    report(Problem::Underflow { ret: flags.contains(Status::RETURN), width });
+}
+
+fn width(tag: Tag) -> u8 {
+    match tag {
+        Tag::Cell | Tag::Unknown => 16,
+        Tag::Low | Tag::High => 32,
+    }
+}
+
+fn report(problem: Problem) {
+    let core = cpu::id();
+    let history: Vec<_> = CORES.lock().unwrap().get(core)
+        .map(|state| state.history.iter().copied().collect())
+        .unwrap_or_default();
+
+    let report = Report { core, ip: history.last().map_or(0, |(ip, _, _)| *ip), problem, history };
+
+    eprintln!("{}", report);
+    REPORTS.lock().unwrap().push(report);
+}
+
+fn stack_name(ret: bool) -> &'static str {
+    if ret { "return" } else { "primary" }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Mismatch { ret, popped, pushed } => write!(
+                f, "popped {} bits from the {} stack where {} bits were pushed", popped, stack_name(*ret), pushed
+            ),
+            Problem::Underflow { ret, width } => write!(
+                f, "popped {} bits from the {} stack, which holds less", width, stack_name(*ret)
+            ),
+            Problem::Imbalance { call, expected, found } => write!(
+                f, "returned from the call at 0x{:x} with the return stack at offset 0x{:x} instead of 0x{:x}",
+                call, found, expected
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Stack sanitizer: {} at 0x{:x} on core {}", self.problem, self.ip, self.core)?;
+        write!(f, "Recent instructions:")?;
+
+        for (ip, instr, status) in &self.history {
+            write!(f, "\n    0x{:x}  {:?} {:?}", ip, instr, status)?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+/*
+Shadow memory for reads of uninitialized RAM, enabled with `--shadow-memory`
+
+`MEM` reads zeros wherever nothing was written, which hides a program that
+forgot to initialize something. The shadow keeps a bit for every byte of
+`MEM`, set by the loader, snapshots and guest writes, and a RAM read through
+the MMU that covers a clear bit is reported with the instruction pointer and
+address. Each instruction and address pair is only reported once.
+
+Regions that are fine to read as zeros, like BSS, are allowed with
+`--shadow-allow start[-end]` in MMU addresses. Accesses the machine makes
+itself, such as instruction fetch and the instruction pointer, are not
+checked.
+*/
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::{atomic::{AtomicBool, Ordering}, Mutex},
+};
+
+use crate::memory::PAGE_SIZE;
+
+pub static SHADOW: Mutex<Option<Shadow>> = Mutex::new(None);
+pub static SHADOWING: AtomicBool = AtomicBool::new(false);
+
+type Bits = [u64; PAGE_SIZE / 64];
+
+pub struct Shadow {
+    /// Written bits of every page of `MEM` with a write in it
+    pages: HashMap<usize, Box<Bits>>,
+    /// Inclusive ranges of MMU addresses that are never reported
+    allow: Vec<(u32, u32)>,
+    reported: HashSet<(u32, u32)>,
+    reports: Vec<Report>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    /// Instruction that made the read
+    pub ip: u32,
+    pub addr: u32,
+    pub width: u8,
+}
+
+impl Shadow {
+    fn written(&self, index: usize) -> bool {
+        self.pages.get(&(index / PAGE_SIZE))
+            .is_some_and(|bits| bits[index % PAGE_SIZE / 64] & 1 << (index % 64) != 0)
+    }
+
+    fn mark(&mut self, index: usize) {
+        let bits = self.pages.entry(index / PAGE_SIZE).or_insert_with(|| Box::new([0; PAGE_SIZE / 64]));
+
+        bits[index % PAGE_SIZE / 64] |= 1 << (index % 64);
+    }
+
+    fn allowed(&self, addr: u32, bytes: u32) -> bool {
+        let last = addr.saturating_add(bytes - 1);
+
+        self.allow.iter().any(|(start, end)| addr >= *start && last <= *end)
+    }
+}
+
+/// Starts tracking writes, `allow` holds the ranges that may be read
+/// uninitialized
+pub fn start(allow: Vec<(u32, u32)>) {
+    *SHADOW.lock().unwrap() = Some(Shadow {
+        pages: HashMap::new(),
+        allow,
+        reported: HashSet::new(),
+        reports: Vec::new(),
+    });
+
+    SHADOWING.store(true, Ordering::Relaxed);
+}
+
+/// Stops tracking and hands back every uninitialized read
+pub fn finish() -> Vec<Report> {
+    SHADOWING.store(false, Ordering::Relaxed);
+
+    SHADOW.lock().unwrap().take().map(|shadow| shadow.reports).unwrap_or_default()
+}
+
+pub fn enabled() -> bool {
+    SHADOWING.load(Ordering::Relaxed)
+}
+
+/// Forgets every write, for a fresh `MEM`
+pub fn reset() {
+    if let Some(shadow) = SHADOW.lock().unwrap().as_mut() {
+        shadow.pages.clear();
+    }
+}
+
+/// Marks `len` bytes of `MEM` at `index` as written
+pub fn written(index: usize, len: usize) {
+    if !enabled() {
+        return;
+    }
+
+    if let Some(shadow) = SHADOW.lock().unwrap().as_mut() {
+        for index in index..index + len {
+            shadow.mark(index);
+        }
+    }
+}
+
+/// Checks a read of `width` bits at MMU address `addr`, which is `index` in
+/// `MEM`
+pub fn read(addr: u32, index: usize, width: u8) {
+    if !enabled() || crate::trace::suspended() {
+        return;
+    }
+
+    let bytes = width as usize / 8;
+
+    let mut slot = SHADOW.lock().unwrap();
+    let Some(shadow) = slot.as_mut() else { return };
+
+    if (index..index + bytes).all(|index| shadow.written(index)) || shadow.allowed(addr, bytes as u32) {
+        return;
+    }
+
+    // The MMU is locked while we are here so the instruction pointer has to
+    // be read straight from memory
+    let report = Report { ip: crate::cpu::ip(), addr, width };
+
+    if shadow.reported.insert((report.ip, addr)) {
+        eprintln!("{}", report);
+        shadow.reports.push(report);
+    }
+}
+
+/// Parses `start[-end]`, addresses are hexadecimal
+pub fn parse_range(desc: &str) -> Option<(u32, u32)> {
+    let (start, end) = match desc.split_once('-') {
+        Some((start, end)) => (crate::watch::parse_addr(start)?, crate::watch::parse_addr(end)?),
+        None => (crate::watch::parse_addr(desc)?, crate::watch::parse_addr(desc)?),
+    };
+
+    (start <= end).then_some((start, end))
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Uninitialized read at IP 0x{:x}: {} bits from 0x{:x}", self.ip, self.width, self.addr)
+    }
+}
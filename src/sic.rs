@@ -1,10 +1,76 @@
-use crate::MEM;
+/*
+Interrupt causes, bit 31 of `cause` is set for exceptions
 
-#[derive(Debug)]
+| cause | desc                                                          |
+| ----- | ------------------------------------------------------------- |
+| 0     | misaligned or unmapped read, also the software interrupt      |
+| 1     | misaligned write, or a write to an unmapped address           |
+| 2     | reserved, never raised                                        |
+| 3     | unknown IO register, or an atomic on IO                       |
+| 4     | inter-processor interrupt, see `cpu`                          |
+| 5     | no instruction at the instruction pointer, or a jump to an    |
+|       | odd address                                                   |
+| 6     | division by zero                                              |
+| 7     | push past the size of a stack                                 |
+| 8     | pop from a stack holding less than the value                  |
+
+Every interrupt also has a vector, which is its cause for causes 4 to 8 and
+tells apart what shares cause 0, 1 or 3
+
+| vector | cause | desc                                                  |
+| ------ | ----- | ----------------------------------------------------- |
+| 0-8    | 0-8   | as above, 0 to 3 are never raised                     |
+| 9      | 0     | software interrupt                                    |
+| 10     | 3     | atomic on IO                                          |
+| 11     | 3     | stack position or offset that does not fit            |
+| 12     | 3     | bad context block                                     |
+| 13     | 3     | inter-processor interrupt to a missing core           |
+| 14     | 0     | misaligned read                                       |
+| 15     | 0     | read of an unmapped address                           |
+| 16     | 1     | misaligned write                                      |
+| 17     | 1     | write to an unmapped address                          |
+| 18     | 3     | read or write of an unknown IO register               |
+
+Writing a vector table to 0x310 enables vectored mode, where the handler is
+the u32 at `table + 4 * vector`. Vectors with a zero or unreadable entry,
+and every vector while the table is 0, go to the handler at 0x300. An
+interrupt taken while no handler is installed for it halts the core.
+*/
+
+pub const ILLEGAL_INSTRUCTION: u32 = 5;
+pub const DIVIDE_BY_ZERO: u32 = 6;
+pub const STACK_OVERFLOW: u32 = 7;
+pub const STACK_UNDERFLOW: u32 = 8;
+pub const SOFTWARE_INTERRUPT: u32 = 9;
+pub const IO_ATOMIC: u32 = 10;
+pub const BAD_STACK: u32 = 11;
+pub const BAD_CONTEXT: u32 = 12;
+pub const MISSING_CORE: u32 = 13;
+pub const MISALIGNED_READ: u32 = 14;
+pub const UNMAPPED_READ: u32 = 15;
+pub const MISALIGNED_WRITE: u32 = 16;
+pub const UNMAPPED_WRITE: u32 = 17;
+pub const UNKNOWN_IO: u32 = 18;
+/// Entries in a vector table
+pub const VECTORS: u32 = 19;
+
+#[derive(Debug, Clone)]
 pub struct Sic {
     pub jmp: u32,
     pub cause: u32,
     pub return_addr: u32,
+    /// Vector of the last interrupt
+    pub vector: u32,
+    /// Base of the vector table, 0 when not vectored
+    pub table: u32,
+    /// Core the controller belongs to
+    pub core: usize,
+}
+
+impl Default for Sic {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Sic {
@@ -12,20 +78,19 @@ impl Sic {
         Self {
             jmp: 0,
             cause: 0,
-            return_addr: 0
+            return_addr: 0,
+            vector: 0,
+            table: 0,
+            core: 0,
         }
     }
 
-    /// Stores the location to jump to for an interrupt
-    pub fn jmp(&self) {
-        unsafe {
-            log::info!("Int jumping to 0x{:x}", self.jmp);
-            MEM.write_u32(0x200, self.jmp);
-        }
+    pub const fn for_core(core: usize) -> Self {
+        Self { core, ..Self::new() }
     }
 
-    pub fn store_ret(&mut self) {
-        self.return_addr = crate::instr_ptr() as u32;
+    pub fn store_ret(&mut self, ip: u32) {
+        self.return_addr = ip;
     }
 
     pub fn gen_int(&mut self, cause: u32, exception: bool) {
@@ -35,7 +100,24 @@ impl Sic {
         let store = cause | excep_store;
         
         self.cause = store;
+        self.vector = if cause == 0 && !exception { SOFTWARE_INTERRUPT } else { cause };
+
+        crate::cpu::CORES[self.core].interrupt.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
 
-        *crate::INTERRUPT.lock().unwrap() = true;
+    /// Raises the exception for `vector` along with the cause it falls under
+    pub fn gen_exception(&mut self, vector: u32) {
+        self.gen_int(cause(vector), true);
+        self.vector = vector;
     }
-}
\ No newline at end of file
+}
+
+/// Cause of the interrupts with `vector`
+pub const fn cause(vector: u32) -> u32 {
+    match vector {
+        SOFTWARE_INTERRUPT | MISALIGNED_READ | UNMAPPED_READ => 0,
+        MISALIGNED_WRITE | UNMAPPED_WRITE => 1,
+        IO_ATOMIC..=MISSING_CORE | UNKNOWN_IO => 3,
+        _ => vector,
+    }
+}
@@ -0,0 +1,298 @@
+/*
+Snapshot file layout, every integer is little endian
+
+| field          | size        | desc                                    |
+| -------------- | ----------- | --------------------------------------- |
+| magic          | 8           | `CUTESNAP`                              |
+| version        | u32         | `VERSION`                               |
+| mmu            | 4 * u32     | io base, io max, memory base, memory max|
+| core count     | u32         | number of cores that follow             |
+| cores          | core count  | see below                               |
+| output ready   | u8          | output device state                     |
+| memory size    | u64         | size of `MEM` in bytes                  |
+| page count     | u32         | number of pages that follow             |
+| pages          | page count  | see below                               |
+
+Every core is stored as
+
+| field          | size        | desc                                    |
+| -------------- | ----------- | --------------------------------------- |
+| primary stack  | u32 + u16   | MMU address, offset                     |
+| return stack   | u32 + u16   | MMU address, offset                     |
+| sic            | 5 * u32     | jmp, cause, return address, vector,     |
+|                |             | vector table                            |
+| interrupt      | u8          | pending interrupt, bit 1 for a pending  |
+|                |             | inter-processor interrupt               |
+| halted         | u8          | core is halted                          |
+| cycles         | u64         | cycles run, see `timing`                |
+
+Every page of `MEM` that has been allocated is stored as its page number, a
+u32, followed by its `PAGE_SIZE` bytes, cut short for a last page that ends
+with memory.
+
+Only snapshots of the current `VERSION` can be restored.
+*/
+
+use std::{fmt, path::Path, sync::atomic::Ordering};
+
+use crate::{cpu, memory::{Memory, PAGE_SIZE}, mmu, stack::Stack, MMU, MEM, OUT_PUT_READY};
+
+pub const MAGIC: &[u8; 8] = b"CUTESNAP";
+pub const VERSION: u32 = 6;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    BadCoreCount(usize),
+    BadPage(u32),
+    BadMemorySize(u64),
+    BadStack(u32, u16),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "{}", err),
+            SnapshotError::BadMagic => write!(f, "not a cute-vm snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::BadCoreCount(count) => write!(f, "snapshot has {} cores", count),
+            SnapshotError::BadMemorySize(size) => write!(f, "snapshot has {} bytes of memory", size),
+            SnapshotError::BadPage(page) => write!(f, "snapshot has page {} outside of memory", page),
+            SnapshotError::BadStack(location, offset) => {
+                write!(f, "snapshot has a stack at 0x{:x} with offset 0x{:x} that does not fit", location, offset)
+            },
+        }
+    }
+}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(err: std::io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+/// Serializes the whole machine into a byte buffer
+pub fn save() -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+
+    let mmu = MMU.lock().unwrap();
+    for value in [mmu.io_base, mmu.io_max, mmu.memory_base, mmu.memory_max] {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+    drop(mmu);
+
+    buf.extend_from_slice(&(cpu::count() as u32).to_le_bytes());
+
+    for core in cpu::CORES.iter().take(cpu::count()) {
+        for stack in [&core.primary, &core.ret] {
+            let stack = stack.lock().unwrap();
+
+            buf.extend_from_slice(&stack.location().to_le_bytes());
+            buf.extend_from_slice(&stack.offset().to_le_bytes());
+        }
+
+        let sic = core.sic.lock().unwrap();
+        for value in [sic.jmp, sic.cause, sic.return_addr, sic.vector, sic.table] {
+            buf.extend_from_slice(&value.to_le_bytes());
+        }
+        drop(sic);
+
+        buf.push(core.interrupt.load(Ordering::Relaxed) as u8 | (core.ipi.load(Ordering::Relaxed) as u8) << 1);
+        buf.push(core.halted.load(Ordering::Relaxed) as u8);
+        buf.extend_from_slice(&core.cycles.load(Ordering::Relaxed).to_le_bytes());
+    }
+
+    buf.push(OUT_PUT_READY.load(Ordering::Relaxed) as u8);
+
+    let memory = crate::mem();
+    let pages = memory.resident_pages();
+
+    buf.extend_from_slice(&(memory.size() as u64).to_le_bytes());
+    buf.extend_from_slice(&(pages.len() as u32).to_le_bytes());
+
+    for page in pages {
+        let start = page * PAGE_SIZE;
+
+        buf.extend_from_slice(&(page as u32).to_le_bytes());
+        buf.extend_from_slice(&memory.read_bytes(start, PAGE_SIZE.min(memory.size() - start)).unwrap());
+    }
+
+    buf
+}
+
+/// Replaces the whole machine with the one stored in `data`
+pub fn restore(data: &[u8]) -> Result<(), SnapshotError> {
+    let mut reader = Reader(data);
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    // Devices and the registers of the cores come from the machine description,
+    // which has to be the one the snapshot was taken on
+    let machine = crate::machine::get();
+    let mmu = mmu::MMU {
+        devices: machine.devices,
+        ..mmu::MMU::new(reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?)
+    };
+
+    let count = reader.u32()? as usize;
+    if count == 0 || count > cpu::MAX_CORES {
+        return Err(SnapshotError::BadCoreCount(count));
+    }
+
+    let mut cores = Vec::new();
+    for _ in 0..count {
+        cores.push(SavedCore {
+            stacks: [(reader.u32()?, reader.u16()?), (reader.u32()?, reader.u16()?)],
+            sic: [reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?],
+            interrupt: reader.u8()?,
+            halted: reader.u8()? != 0,
+            cycles: reader.u64()?,
+        });
+    }
+
+    let output_ready = reader.u8()? != 0;
+
+    // The registers of every core live in memory
+    let size = reader.u64()?;
+    if size > crate::MAX_MEMORY as u64 || size < machine.registers_end(count) as u64 {
+        return Err(SnapshotError::BadMemorySize(size));
+    }
+
+    let size = size as usize;
+
+    let mut pages = Vec::new();
+    for _ in 0..reader.u32()? {
+        let page = reader.u32()?;
+        let start = page as usize * PAGE_SIZE;
+        if start >= size {
+            return Err(SnapshotError::BadPage(page));
+        }
+
+        pages.push((start, reader.take(PAGE_SIZE.min(size - start))?));
+    }
+
+    let memory = Memory::from_pages(size, pages.iter().copied()).unwrap();
+
+    // Stack sizes are not saved, they come from the machine description too
+    let sizes = [machine.cores.primary_stack_size, machine.cores.return_stack_size];
+    for ((location, offset), size) in cores.iter().flat_map(|core| core.stacks.iter().zip(sizes)) {
+        if *offset > size || !mmu.stack_fits(*location, size) {
+            return Err(SnapshotError::BadStack(*location, *offset));
+        }
+    }
+
+    // Only touch the machine once the whole file is known to be valid
+    *MMU.lock().unwrap() = mmu;
+
+    // Everything the snapshot holds counts as written
+    crate::shadow::reset();
+    for (start, bytes) in pages {
+        crate::shadow::written(start, bytes.len());
+    }
+
+    cpu::set_count(count);
+    cpu::map_registers();
+
+    for (id, (core, saved)) in cpu::CORES.iter().zip(cores).enumerate() {
+        for ((stack, size), (location, offset)) in [&core.primary, &core.ret].into_iter().zip(sizes).zip(saved.stacks) {
+            let mut stack = stack.lock().unwrap();
+            *stack = Stack::new(location, size);
+
+            unsafe { stack.set_offset(offset) }
+        }
+
+        let mut int_controller = core.sic.lock().unwrap();
+        *int_controller = crate::sic::Sic::for_core(id);
+        int_controller.jmp = saved.sic[0];
+        int_controller.cause = saved.sic[1];
+        int_controller.return_addr = saved.sic[2];
+        int_controller.vector = saved.sic[3];
+        int_controller.table = saved.sic[4];
+        drop(int_controller);
+
+        core.interrupt.store(saved.interrupt & 0b01 != 0, Ordering::Relaxed);
+        core.ipi.store(saved.interrupt & 0b10 != 0, Ordering::Relaxed);
+        core.halted.store(saved.halted, Ordering::Relaxed);
+        core.cycles.store(saved.cycles, Ordering::Relaxed);
+    }
+
+    cpu::select(0);
+    crate::HALTED.store(cpu::CORES[..count].iter().all(|core| core.halted.load(Ordering::Relaxed)), Ordering::Relaxed);
+    OUT_PUT_READY.store(output_ready, Ordering::Relaxed);
+
+    *MEM.write().unwrap() = memory;
+    crate::cache::reset();
+
+    Ok(())
+}
+
+pub fn save_to_file(path: &Path) -> Result<(), SnapshotError> {
+    std::fs::write(path, save())?;
+
+    log::info!("Wrote snapshot to {}", path.display());
+
+    Ok(())
+}
+
+pub fn restore_from_file(path: &Path) -> Result<(), SnapshotError> {
+    let data = std::fs::read(path)?;
+
+    restore(&data)?;
+
+    log::info!("Restored snapshot from {}", path.display());
+
+    Ok(())
+}
+
+struct SavedCore {
+    stacks: [(u32, u16); 2],
+    sic: [u32; 5],
+    interrupt: u8,
+    halted: bool,
+    cycles: u64,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.0.len() < len {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
@@ -1,19 +1,34 @@
 pub struct Stack {
     location: u32,
-    offset: u16
+    offset: u16,
+    /// Bytes the stack can hold
+    size: u16,
+    /// Width of every cell as pushed, only kept while sanitizing
+    tags: Vec<Tag>,
 }
 
 impl Stack {
-    pub const fn new(location: u32) -> Stack {
-        Stack { location, offset: 0}
+    pub const fn new(location: u32, size: u16) -> Stack {
+        Stack { location, offset: 0, size, tags: Vec::new() }
     }
 
+    /// Moves the base of the stack
+    ///
+    /// # Safety
+    /// The new location must be odd and leave room for the whole stack below
+    /// it, see `MMU::stack_fits`
     pub unsafe fn set_pos(&mut self, location: u32) {
         self.location = location;
+        self.tags.clear();
     }
 
+    /// Moves the top of the stack
+    ///
+    /// # Safety
+    /// The offset must stay within the bounds of the stack
     pub unsafe fn set_offset(&mut self, offset: u16) {
         self.offset = offset;
+        self.tags.clear();
     }
 
     pub fn offset(&self) -> u16 {
@@ -24,134 +39,132 @@ impl Stack {
         self.location
     }
 
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
     pub fn push(&mut self, data: u32, flags: Status) {
-        if self.offset >= 0x100 {
-            panic!("Stack overflow, attempted to push with offset {}", self.offset);
-        }
+        self.push_with(&crate::MMU.lock().unwrap(), data, flags);
+    }
 
-        let index = self.offset as usize;
+    pub fn pop(&mut self, flags: Status) -> u32 {
+        self.pop_with(&crate::MMU.lock().unwrap(), flags)
+    }
 
-        let bytes: [u8; 4] = data.to_le_bytes();
+    /// `push` through an MMU the caller already holds
+    pub fn push_with(&mut self, mmu: &MMU, data: u32, flags: Status) {
+        let width = if flags.contains(Status::SHORT) { 4 } else { 2 };
 
-        self[index] = bytes[0];
-        self[index + 1] = bytes[1];
+        if self.offset as u32 + width > self.size as u32 {
+            log::warn!("Stack overflow, attempted to push with offset {}", self.offset);
+            crate::int_controller().lock().unwrap().gen_int(crate::sic::STACK_OVERFLOW, true);
+            return;
+        }
 
+        if sanitize::enabled() {
+            sanitize::pushed(&mut self.tags, self.offset, flags);
+        }
+
+        self.set(mmu, self.offset as usize, data as u16);
         self.offset += 2;
 
         if flags.contains(Status::SHORT) {
-            let index = self.offset as usize;
-
-            self[index] = bytes[2];
-            self[index + 1] = bytes[3];
-    
+            self.set(mmu, self.offset as usize, (data >> 16) as u16);
             self.offset += 2;
         }
     }
 
-    pub fn pop(&mut self, flags: Status) -> u32 {
-        if self.offset < 2 {
-            self.offset = 2
-        }
-        self.offset -= 2;
+    /// `pop` through an MMU the caller already holds
+    pub fn pop_with(&mut self, mmu: &MMU, flags: Status) -> u32 {
+        let width = if flags.contains(Status::SHORT) { 4 } else { 2 };
 
-        if flags.contains(Status::SHORT) {
-            if self.offset < 2 {
-                self.offset = 2
+        if self.offset < width {
+            log::warn!("Stack underflow, attempted to pop with offset {}", self.offset);
+            crate::int_controller().lock().unwrap().gen_int(crate::sic::STACK_UNDERFLOW, true);
+
+            if sanitize::enabled() {
+                sanitize::underflow(flags);
             }
-            self.offset -= 2;
+
+            return 0;
         }
 
-        let index = self.offset as usize;
+        if sanitize::enabled() {
+            sanitize::popped(&mut self.tags, self.offset, flags);
+        }
 
-        let bytes: [u8; 4];
+        self.offset -= width;
 
+        let index = self.offset as usize;
 
-        if flags.contains(Status::SHORT) {
-            let mbytes = [self[index + 2], self[index + 3]];
-            let lbytes = [self[index], self[index + 1]];
-
-            bytes = [mbytes[1], mbytes[0], lbytes[1], lbytes[0]];
-            self[index] = 0;
-            self[index + 1] = 0;
-            self[index + 2] = 0;
-            self[index + 3] = 0;
-        } else {
-            let lbytes = [self[index], self[index + 1]];
+        let ret = if flags.contains(Status::SHORT) {
+            let ret = self.get(mmu, index) as u32 | (self.get(mmu, index + 2) as u32) << 16;
+            self.set(mmu, index, 0);
+            self.set(mmu, index + 2, 0);
 
-            bytes = [0, 0, lbytes[1], lbytes[0]];
-            self[index] = 0;
-            self[index + 1] = 0;
-        }
+            ret
+        } else {
+            let ret = self.get(mmu, index) as u32;
+            self.set(mmu, index, 0);
 
-        let ret = u32::from_be_bytes(bytes);
+            ret
+        };
 
         if flags.contains(Status::KEEP) {
-            self.push(ret, flags);
+            self.push_with(mmu, ret, flags);
         }
 
         ret
     }
 
+    /// Reads a value without popping it, this is not guest traffic so it is
+    /// invisible to watchpoints and faults
     pub fn copy(&self, index: usize, flags: Status) -> u32 {
-        let bytes: [u8; 4];
-
-
         if flags.contains(Status::SHORT) {
-            let mbytes = [self[index + 2], self[index + 3]];
-            let lbytes = [self[index], self[index + 1]];
-
-            bytes = [mbytes[1], mbytes[0], lbytes[1], lbytes[0]];
+            self.peek(index) as u32 | (self.peek(index + 2) as u32) << 16
         } else {
-            let lbytes = [self[index], self[index + 1]];
-
-            bytes = [0, 0, lbytes[1], lbytes[0]];
+            self.peek(index) as u32
         }
-
-        let ret = u32::from_be_bytes(bytes);
-
-        ret
     }
 
     pub fn top(&self) -> usize {
         self.offset as usize
     }
-}
 
-use core::ops::{ Index, IndexMut };
+    /// MMU address of the cell at byte `index` of the stack
+    ///
+    /// The stack grows down from `location` and a cell keeps its low byte at
+    /// the higher address, so every cell is an aligned big endian u16
+    fn address(&self, index: usize) -> u32 {
+        if index + 2 > self.size as usize {
+            panic!("index out of bounds: the len is {} but the index is {}", self.size, index);
+        }
 
-use crate::instructions::Status;
+        self.location - index as u32 - 1
+    }
 
-impl Index<usize> for Stack {
-    type Output = u8;
+    fn peek(&self, index: usize) -> u16 {
+        let value = crate::MMU.lock().unwrap().peek(self.address(index), 16).unwrap_or(0);
 
-    fn index(&self, rhs: usize) -> &u8 {
-        if rhs >= 256 {
-            panic!("index out of bounds: the len is 256 but the index is {}", rhs);
-        }
-
-        unsafe {
-            return &super::MEM[(self.location as usize) - rhs];
-        }
+        (value as u16).swap_bytes()
     }
-}
 
-impl IndexMut<usize> for Stack {
-    fn index_mut(&mut self, rhs: usize) -> &mut u8 {
-        if rhs >= 256 {
-            panic!("index out of bounds: the len is 256 but the index is {}", rhs);
-        }
+    fn get(&self, mmu: &MMU, index: usize) -> u16 {
+        mmu.read_cell(self.address(index)).swap_bytes()
+    }
 
-        unsafe{
-            return &mut super::MEM[(self.location as usize) - rhs];
-        }
+    fn set(&self, mmu: &MMU, index: usize, value: u16) {
+        mmu.write_cell(self.address(index), value.swap_bytes());
     }
 }
 
+use crate::{instructions::Status, mmu::MMU, sanitize::{self, Tag}};
+
 use std::fmt;
 
 impl fmt::Debug for Stack {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Stack:\n")?;
+        writeln!(f, "Stack:")?;
 
         if f.alternate() {
             let range = 0..self.offset;
@@ -159,7 +172,7 @@ impl fmt::Debug for Stack {
             for i in range.step_by(4) {
                 write!(f, "0x{:x}", self.copy(i as usize, Status::SHORT))?;
                 if i != self.offset - 4 {
-                    write!(f, "\n")?;
+                    writeln!(f)?;
                 }
             }
         } else {
@@ -168,7 +181,7 @@ impl fmt::Debug for Stack {
             for i in range.step_by(2) {
                 write!(f, "0x{:x}", self.copy(i as usize, Status::NONE))?;
                 if i != self.offset - 2 {
-                    write!(f, "\n")?;
+                    writeln!(f)?;
                 }
             }
         }
@@ -0,0 +1,90 @@
+/*
+Symbol files list one label per line as a hexadecimal address followed by the
+label name, a leading `#` on the name is dropped
+
+    0x1600 start
+    0x160e #printchar
+
+Blank lines and lines starting with `;` are ignored.
+*/
+
+use std::{collections::{BTreeMap, HashMap}, path::Path};
+
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    /// First label defined at each address, used when naming addresses
+    labels: BTreeMap<u32, String>,
+    names: HashMap<String, u32>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self { labels: BTreeMap::new(), names: HashMap::new() }
+    }
+
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut symbols = Self::new();
+
+        for (line_no, line) in source.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let (addr, name) = match (words.next(), words.next()) {
+                (Some(addr), Some(name)) => (addr, name),
+                _ => return Err(format!("line {}: expected an address and a label", line_no + 1)),
+            };
+
+            let addr = u32::from_str_radix(addr.trim_start_matches("0x"), 16)
+                .map_err(|_| format!("line {}: invalid address {}", line_no + 1, addr))?;
+
+            symbols.insert(addr, name.trim_start_matches('#'));
+        }
+
+        Ok(symbols)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let source = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+
+        Self::parse(&source)
+    }
+
+    pub fn insert(&mut self, addr: u32, name: &str) {
+        self.labels.entry(addr).or_insert_with(|| name.to_string());
+        self.names.insert(name.to_string(), addr);
+    }
+
+    pub fn get(&self, addr: u32) -> Option<&str> {
+        self.labels.get(&addr).map(|name| name.as_str())
+    }
+
+    pub fn find(&self, name: &str) -> Option<u32> {
+        self.names.get(name.trim_start_matches('#')).copied()
+    }
+
+    /// The closest label at or before `addr`
+    pub fn lookup(&self, addr: u32) -> Option<(&str, u32)> {
+        self.labels.range(..=addr).next_back().map(|(start, name)| (name.as_str(), addr - start))
+    }
+
+    /// `label+offset` when a label is known, otherwise the bare address
+    pub fn describe(&self, addr: u32) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => format!("#{}", name),
+            Some((name, offset)) => format!("#{}+0x{:x}", name, offset),
+            None => format!("0x{:x}", addr),
+        }
+    }
+
+    /// Every label with its address, sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &str)> {
+        let mut names: Vec<_> = self.names.iter().map(|(name, addr)| (*addr, name.as_str())).collect();
+        names.sort_by(|a, b| a.1.cmp(b.1));
+
+        names.into_iter()
+    }
+}
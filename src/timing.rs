@@ -0,0 +1,105 @@
+/*
+Cycle costs and clock throttling
+
+| instr                  | cycles |
+| ---------------------- | ------ |
+| `nop`, `push`, `drop`  | 1      |
+| `dup`, `cmp`           | 1      |
+| `add`, `sub`           | 1      |
+| `lit`, `str`, `load`   | 2      |
+| `over`, `jsr`, `mul`   | 3      |
+| `cas`, `fadd`, `swap`  | 4      |
+| `div`                  | 10     |
+| `halt`                 | 1      |
+
+An instruction skipped by its condition flags costs 1 cycle. Every access
+through the MMU adds `MEMORY_PENALTY` cycles, or `IO_PENALTY` for the IO
+window, apart from stack traffic which the costs above already cover.
+
+Each core counts its own cycles, readable from IO 0x20 (low 32 bits) and
+0x24 (high 32 bits), or in 16 bit halves at 0x20 to 0x26.
+
+With a clock set, a core that gets ahead of it sleeps until wall time
+catches up.
+*/
+
+use std::{
+    sync::{atomic::{AtomicU64, Ordering}, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{cpu, instructions::Instr};
+
+pub const MEMORY_PENALTY: u64 = 2;
+pub const IO_PENALTY: u64 = 8;
+
+/// Target frequency, 0 runs as fast as the host can
+pub static CLOCK_HZ: AtomicU64 = AtomicU64::new(0);
+static CLOCK_START: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Cycles `instr` takes, not counting its memory accesses
+pub fn cost(instr: Instr, executed: bool) -> u64 {
+    if !executed {
+        return 1;
+    }
+
+    match instr {
+        Instr::Nop | Instr::Push | Instr::Drop | Instr::Dup | Instr::Cmp | Instr::Add | Instr::Sub | Instr::Halt => 1,
+        Instr::Lit | Instr::Str | Instr::Load => 2,
+        Instr::Over | Instr::Jsr | Instr::Mul => 3,
+        Instr::Cas | Instr::Fadd | Instr::Swap => 4,
+        Instr::Div => 10,
+    }
+}
+
+/// Cycles run by the current core
+pub fn cycles() -> u64 {
+    cpu::current().cycles.load(Ordering::Relaxed)
+}
+
+/// Called by the MMU for every access a guest makes
+pub fn access(io: bool) {
+    if crate::trace::suspended() {
+        return;
+    }
+
+    let penalty = if io { IO_PENALTY } else { MEMORY_PENALTY };
+
+    cpu::current().cycles.fetch_add(penalty, Ordering::Relaxed);
+}
+
+/// Whether a clock is set, so cores may have to wait for it
+pub fn throttled() -> bool {
+    CLOCK_HZ.load(Ordering::Relaxed) != 0
+}
+
+/// Counts a finished instruction, then waits for the clock if the core is
+/// ahead of it
+pub fn retire(instr: Instr, executed: bool) {
+    let cycles = cpu::current().cycles.fetch_add(cost(instr, executed), Ordering::Relaxed);
+
+    let hz = CLOCK_HZ.load(Ordering::Relaxed);
+    if hz != 0 {
+        throttle(cycles, hz);
+    }
+}
+
+pub fn set_clock_hz(hz: u64) {
+    *CLOCK_START.lock().unwrap() = Some(Instant::now());
+    CLOCK_HZ.store(hz, Ordering::Relaxed);
+}
+
+fn throttle(cycles: u64, hz: u64) {
+    let start = match *CLOCK_START.lock().unwrap() {
+        Some(start) => start,
+        None => return,
+    };
+
+    let target = Duration::from_nanos((cycles as u128 * 1_000_000_000 / hz as u128) as u64);
+    let elapsed = start.elapsed();
+
+    // Sleeping for less than this costs more than it gains
+    if target > elapsed + Duration::from_millis(1) {
+        std::thread::sleep(target - elapsed);
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    cell::Cell,
+    fs::File,
+    io::{BufWriter, Write},
+    sync::{atomic::{AtomicBool, Ordering}, Mutex},
+};
+
+use crate::instructions::Instruction;
+
+pub static TRACER: Mutex<Option<Tracer>> = Mutex::new(None);
+pub static TRACING: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Accesses made by the machine itself, such as instruction pointer
+    /// updates, are not part of the trace and do not trigger watchpoints.
+    /// Every core runs on its own thread, so one core never hides the
+    /// accesses of another.
+    static SUSPENDED: Cell<bool> = const { Cell::new(false) };
+}
+
+pub struct Tracer {
+    out: BufWriter<File>,
+    step: u64,
+    ip: u32,
+    instruction: String,
+    status: u8,
+    flags: String,
+    accesses: Vec<Access>,
+}
+
+pub struct Access {
+    write: bool,
+    io: bool,
+    addr: u32,
+    width: u8,
+    value: u32,
+}
+
+/// Starts writing one JSON record per executed instruction to `path`
+pub fn start(path: &std::path::Path) -> std::io::Result<()> {
+    let tracer = Tracer {
+        out: BufWriter::new(File::create(path)?),
+        step: 0,
+        ip: 0,
+        instruction: String::new(),
+        status: 0,
+        flags: String::new(),
+        accesses: Vec::new(),
+    };
+
+    *TRACER.lock().unwrap() = Some(tracer);
+    TRACING.store(true, Ordering::Relaxed);
+
+    Ok(())
+}
+
+pub fn finish() {
+    TRACING.store(false, Ordering::Relaxed);
+
+    if let Some(mut tracer) = TRACER.lock().unwrap().take() {
+        tracer.out.flush().expect("Failed to flush trace");
+    }
+}
+
+pub fn begin(ip: u32, instruction: &Instruction) {
+    if !TRACING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if let Some(tracer) = TRACER.lock().unwrap().as_mut() {
+        tracer.ip = ip;
+        tracer.instruction = format!("{:?}", instruction.instr());
+        tracer.status = instruction.status().bits();
+        tracer.flags = format!("{:?}", instruction.status());
+        tracer.accesses.clear();
+    }
+}
+
+pub fn end(executed: bool) {
+    if !TRACING.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let primary = stack_top(false);
+    let ret = stack_top(true);
+
+    if let Some(tracer) = TRACER.lock().unwrap().as_mut() {
+        let mut line = format!(
+            "{{\"step\":{},\"ip\":{},\"instr\":\"{}\",\"status\":{},\"flags\":\"{}\",\"executed\":{},\"primary\":{},\"return\":{},\"accesses\":[",
+            tracer.step, tracer.ip, tracer.instruction, tracer.status, tracer.flags, executed, primary, ret
+        );
+
+        for (i, access) in tracer.accesses.iter().enumerate() {
+            if i != 0 {
+                line.push(',');
+            }
+
+            line.push_str(&format!(
+                "{{\"kind\":\"{}\",\"space\":\"{}\",\"addr\":{},\"width\":{},\"value\":{}}}",
+                if access.write { "write" } else { "read" },
+                if access.io { "io" } else { "mem" },
+                access.addr, access.width, access.value
+            ));
+        }
+
+        line.push_str("]}");
+
+        writeln!(tracer.out, "{}", line).expect("Failed to write trace");
+        tracer.step += 1;
+    }
+}
+
+pub fn access(write: bool, io: bool, addr: u32, width: u8, value: u32) {
+    if !TRACING.load(Ordering::Relaxed) || suspended() {
+        return;
+    }
+
+    if let Some(tracer) = TRACER.lock().unwrap().as_mut() {
+        tracer.accesses.push(Access { write, io, addr, width, value });
+    }
+}
+
+/// Whether the current accesses are made by the machine itself
+pub fn suspended() -> bool {
+    SUSPENDED.with(Cell::get)
+}
+
+/// Runs `f` without recording the memory accesses it makes
+pub fn untraced<T>(f: impl FnOnce() -> T) -> T {
+    let was = SUSPENDED.with(|suspended| suspended.replace(true));
+    let ret = f();
+    SUSPENDED.with(|suspended| suspended.set(was));
+
+    ret
+}
+
+fn stack_top(ret_stack: bool) -> String {
+    let stack = if ret_stack {
+        crate::return_stack().lock().unwrap()
+    } else {
+        crate::primary_stack().lock().unwrap()
+    };
+
+    let offset = stack.offset();
+    let top = if offset >= 2 {
+        stack.copy(offset as usize - 2, crate::instructions::Status::NONE).to_string()
+    } else {
+        "null".to_string()
+    };
+
+    format!("{{\"offset\":{},\"top\":{}}}", offset, top)
+}
@@ -0,0 +1,186 @@
+/*
+Unit tests for casm routines
+
+A label annotated with `;test` is a test of the routine there, which
+`cute test` calls on its own, see `harness::call`. The annotation lists the
+arguments to push, bottom first, then after `->` the primary stack the
+routine should leave behind, bottom first. Each `mem <addr> = <bytes>`
+after the stack gives bytes RAM should hold from `addr`. A quoted string at
+the end is the output it should write.
+
+    #double ;test 3 -> 6
+    #greet  ;test -> "hi"
+    #store  ;test 0x2a -> mem 0x3000 = 0x2a 0
+    #setup  ;test
+
+A test passes when the routine returns and leaves what was expected, one
+that halts, trips a limit or panics fails. Every test gets a freshly loaded
+image, and `DEFAULT_INSTRUCTIONS` when no instruction limit was given.
+*/
+
+use std::path::Path;
+
+use crate::{
+    asm::{self, Assembly, SourceLine},
+    harness::{self, Config, ExitStatus},
+    limits::Limits,
+};
+
+/// Instruction limit of a test, so a routine that never returns fails
+pub const DEFAULT_INSTRUCTIONS: u64 = 1_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Case {
+    pub label: String,
+    pub source: SourceLine,
+    pub args: Vec<u16>,
+    /// Primary stack after returning, None when it is not checked
+    pub stack: Option<Vec<u16>>,
+    /// Output written, None when it is not checked
+    pub output: Option<Vec<u8>>,
+    /// Bytes RAM should hold at each address
+    pub memory: Vec<(u32, Vec<u8>)>,
+}
+
+impl Case {
+    /// Parses the annotation following `;test`
+    pub fn parse(label: &str, source: SourceLine, annotation: &str) -> Result<Self, String> {
+        let (values, output) = match annotation.split_once('"') {
+            Some((values, output)) => {
+                let output = output.trim_end().strip_suffix('"').ok_or("unterminated output string")?;
+
+                (values, Some(output.as_bytes().to_vec()))
+            },
+            None => (annotation, None),
+        };
+
+        let (args, expected) = match values.split_once("->") {
+            Some((args, expected)) => (args, Some(expected)),
+            None => (values, None),
+        };
+
+        let (stack, memory) = match expected {
+            Some(expected) => {
+                let mut clauses = expected.split("mem ");
+                let stack = clauses.next().unwrap_or_default();
+
+                (Some(stack), clauses.map(memory).collect::<Result<Vec<_>, _>>()?)
+            },
+            None => (None, Vec::new()),
+        };
+
+        let stack = match stack.map(cells).transpose()? {
+            // Only the output or memory was given
+            Some(stack) if stack.is_empty() && (output.is_some() || !memory.is_empty()) => None,
+            stack => stack,
+        };
+
+        Ok(Self { label: label.to_string(), source, args: cells(args)?, stack, output, memory })
+    }
+
+    /// Runs the test on a fresh copy of `assembly`, returning why it failed
+    pub fn run(&self, assembly: &Assembly, config: &Config) -> Result<(), String> {
+        let outcome = harness::call_label(assembly, &self.label, &self.args, config)
+            .ok_or_else(|| format!("no label #{}", self.label))?;
+
+        match &outcome.status {
+            ExitStatus::Returned => {},
+            ExitStatus::Halted => return Err("halted instead of returning".to_string()),
+            ExitStatus::Limit(limit) => return Err(limit.to_string()),
+            ExitStatus::Panicked(message) => return Err(format!("VM panicked: {}", message)),
+        }
+
+        if let Some(stack) = &self.stack {
+            if outcome.primary != *stack {
+                return Err(format!("left {:x?} on the stack, expected {:x?}", outcome.primary, stack));
+            }
+        }
+
+        let ram_base = config.machine.memory.ram_base;
+        for (addr, bytes) in &self.memory {
+            let held = addr.checked_sub(ram_base)
+                .and_then(|index| outcome.ram.read_bytes(index as usize, bytes.len()).ok())
+                .ok_or_else(|| format!("mem 0x{:x} is outside of RAM", addr))?;
+
+            if held != *bytes {
+                return Err(format!("memory at 0x{:x} holds {:x?}, expected {:x?}", addr, held, bytes));
+            }
+        }
+
+        if let Some(output) = &self.output {
+            if outcome.output != *output {
+                return Err(format!("wrote {:?}, expected {:?}", outcome.output_str(), String::from_utf8_lossy(output)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn cells(values: &str) -> Result<Vec<u16>, String> {
+    values.split_whitespace().map(|value| match asm::parse_number(value) {
+        Some(number) if number <= 0xffff => Ok(number as u16),
+        _ => Err(format!("invalid test value {}", value)),
+    }).collect()
+}
+
+/// Parses `<addr> = <bytes>` of a `mem` clause
+fn memory(clause: &str) -> Result<(u32, Vec<u8>), String> {
+    let (addr, bytes) = clause.split_once('=').ok_or("expected mem <addr> = <bytes>")?;
+
+    let addr = asm::parse_number(addr.trim()).ok_or_else(|| format!("invalid address {}", addr.trim()))?;
+    let bytes = bytes.split_whitespace().map(|value| match asm::parse_number(value) {
+        Some(number) if number <= 0xff => Ok(number as u8),
+        _ => Err(format!("invalid byte {}", value)),
+    }).collect::<Result<Vec<_>, _>>()?;
+
+    if bytes.is_empty() {
+        return Err(format!("no bytes for mem 0x{:x}", addr));
+    }
+
+    Ok((addr, bytes))
+}
+
+/// Runs the tests of every file, printing a line for each, returns whether
+/// they all passed
+pub fn run_files(files: &[String], config: &Config) -> bool {
+    let config = Config {
+        limits: Limits { instructions: config.limits.instructions.or(Some(DEFAULT_INSTRUCTIONS)), ..config.limits },
+        ..config.clone()
+    };
+
+    let (mut passed, mut failed) = (0, 0);
+
+    // The files are assembled for the machine they will run on
+    crate::machine::set(config.machine);
+
+    for file in files {
+        let assembly = match asm::assemble_file(Path::new(file)) {
+            Ok(assembly) => assembly,
+            Err(err) => {
+                println!("error assembling {}", err);
+                failed += 1;
+                continue;
+            },
+        };
+
+        for case in &assembly.debug.tests {
+            let name = format!("{}:{} #{}", assembly.debug.files[case.source.file], case.source.line, case.label);
+
+            match case.run(&assembly, &config) {
+                Ok(()) => {
+                    println!("test {} ... ok", name);
+                    passed += 1;
+                },
+                Err(reason) => {
+                    println!("test {} ... FAILED, {}", name, reason);
+                    failed += 1;
+                },
+            }
+        }
+    }
+
+    println!("\n{} passed, {} failed", passed, failed);
+
+    failed == 0
+}
@@ -0,0 +1,140 @@
+use std::{fmt, sync::{atomic::{AtomicBool, Ordering}, Mutex}};
+
+pub static WATCHPOINTS: Mutex<Vec<Watchpoint>> = Mutex::new(Vec::new());
+pub static WATCHING: AtomicBool = AtomicBool::new(false);
+pub static HIT: Mutex<Option<Hit>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u32,
+    /// Last watched address, inclusive
+    pub end: u32,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    /// Parses `start[-end][:r|w|rw]`, addresses are hexadecimal and the
+    /// default is to watch both reads and writes of a single byte
+    pub fn parse(desc: &str) -> Option<Self> {
+        let (range, kind) = match desc.split_once(':') {
+            Some((range, kind)) => (range, kind),
+            None => (desc, "rw"),
+        };
+
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (parse_addr(start)?, parse_addr(end)?),
+            None => (parse_addr(range)?, parse_addr(range)?),
+        };
+
+        if end < start {
+            return None;
+        }
+
+        let (read, write) = match kind {
+            "r" => (true, false),
+            "w" => (false, true),
+            "rw" | "wr" => (true, true),
+            _ => return None,
+        };
+
+        Some(Self { start, end, read, write })
+    }
+
+    fn overlaps(&self, addr: u32, bytes: u32) -> bool {
+        let last = addr.saturating_add(bytes - 1);
+
+        addr <= self.end && last >= self.start
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match (self.read, self.write) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            _ => "w",
+        };
+
+        write!(f, "0x{:x}-0x{:x}:{}", self.start, self.end, kind)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hit {
+    pub ip: u32,
+    pub addr: u32,
+    pub width: u8,
+    pub write: bool,
+    pub old: Option<u32>,
+    pub new: u32,
+}
+
+impl fmt::Display for Hit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.write {
+            write!(f, "Watchpoint hit at IP 0x{:x}: write of 0x{:x} to 0x{:x}", self.ip, self.new, self.addr)?;
+
+            if let Some(old) = self.old {
+                write!(f, ", old value 0x{:x}", old)?;
+            }
+
+            Ok(())
+        } else {
+            write!(f, "Watchpoint hit at IP 0x{:x}: read of 0x{:x} from 0x{:x}", self.ip, self.new, self.addr)
+        }
+    }
+}
+
+pub fn add(watchpoint: Watchpoint) {
+    WATCHPOINTS.lock().unwrap().push(watchpoint);
+    WATCHING.store(true, Ordering::Relaxed);
+}
+
+pub fn remove(start: u32) {
+    let mut watchpoints = WATCHPOINTS.lock().unwrap();
+    watchpoints.retain(|watchpoint| watchpoint.start != start);
+
+    WATCHING.store(!watchpoints.is_empty(), Ordering::Relaxed);
+}
+
+/// Takes the first watchpoint hit since the last call
+pub fn take_hit() -> Option<Hit> {
+    HIT.lock().unwrap().take()
+}
+
+pub fn read(addr: u32, width: u8, value: u32) {
+    check(addr, width, false, None, value);
+}
+
+pub fn write(addr: u32, width: u8, old: Option<u32>, new: u32) {
+    check(addr, width, true, old, new);
+}
+
+fn check(addr: u32, width: u8, write: bool, old: Option<u32>, new: u32) {
+    if !WATCHING.load(Ordering::Relaxed) || crate::trace::suspended() {
+        return;
+    }
+
+    let hit = WATCHPOINTS.lock().unwrap().iter().any(|watchpoint| {
+        (if write { watchpoint.write } else { watchpoint.read })
+            && watchpoint.overlaps(addr, width as u32 / 8)
+    });
+
+    if !hit {
+        return;
+    }
+
+    let mut slot = HIT.lock().unwrap();
+    if slot.is_none() {
+        // The MMU is locked while we are here so the instruction pointer has
+        // to be read straight from memory
+        let ip = crate::cpu::ip();
+
+        *slot = Some(Hit { ip, addr, width, write, old, new });
+    }
+}
+
+pub fn parse_addr(arg: &str) -> Option<u32> {
+    u32::from_str_radix(arg.trim_start_matches("0x"), 16).ok()
+}
@@ -0,0 +1,59 @@
+//! Translated programs against the interpreter
+//!
+//! `aot/faults.rs` is the translation of `aot/faults.casm`, regenerate it
+//! with `cute-vm --translate tests/aot/faults.rs -f tests/aot/faults.casm`.
+
+use std::cell::Cell;
+
+use cute_vm::{
+    aot, asm, cpu,
+    harness::{self, Config, Outcome},
+};
+
+mod faults {
+    include!("aot/faults.rs");
+}
+
+fn image() -> Vec<u8> {
+    let source = include_str!("aot/faults.casm");
+
+    asm::assemble_at("faults.casm", source, Config::default().machine.program.load).unwrap().image
+}
+
+#[test]
+fn up_to_date() {
+    let entry = Config::default().machine.program.entry;
+
+    assert_eq!(aot::translate(&image(), entry), include_str!("aot/faults.rs"));
+    assert_eq!(faults::IMAGE[..], image());
+}
+
+/// Runs `image` with `body`, along with where it left the instruction
+/// pointer
+fn run(image: &[u8], body: impl FnOnce()) -> (Outcome, u32) {
+    let ip = Cell::new(0);
+
+    let outcome = harness::with_machine(image, &Config::default(), || {
+        body();
+        ip.set(cpu::ip());
+    });
+
+    (outcome, ip.get())
+}
+
+#[test]
+fn faults_match_interpreter() {
+    let (interpreted, interpreted_ip) = run(&image(), || cpu::RoundRobin::new(1).run());
+    let (translated, translated_ip) = run(&faults::IMAGE, faults::run);
+
+    assert!(interpreted.halted());
+    // Division by zero, stack underflow, then the letter after both
+    assert_eq!(interpreted.output, [6, 8, b'A']);
+
+    assert_eq!(translated_ip, interpreted_ip);
+    assert_eq!(translated.status, interpreted.status);
+    assert_eq!(translated.output, interpreted.output);
+    assert_eq!(translated.primary, interpreted.primary);
+    assert_eq!(translated.ret, interpreted.ret);
+    assert_eq!(translated.instructions, interpreted.instructions);
+}
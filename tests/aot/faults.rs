@@ -0,0 +1,209 @@
+// Translated from a cute image by `cute-vm --translate`
+
+use std::sync::atomic::Ordering;
+
+#[allow(unused_imports)]
+use cute_vm::instructions::{ConditionRegister, Instr, Instruction, Status};
+
+/// Load with `cute_vm::load_image` before calling `run`
+pub static IMAGE: [u8; 84] = [
+    0x00, 0x00, 0x01, 0x04, 0x38, 0x16, 0x00, 0x00, 0x00, 0x00, 0x01, 0x04, 0x00, 0x03, 0x00, 0x00,
+    0x04, 0x04, 0x01, 0x00, 0x07, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x0d, 0x00,
+    0x01, 0x00, 0x02, 0x00, 0x0a, 0x00, 0x07, 0x04, 0x01, 0x00, 0x41, 0x00, 0x00, 0x00, 0x01, 0x04,
+    0x00, 0x01, 0x00, 0x00, 0x04, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x01, 0x04, 0x0c, 0x03, 0x00, 0x00,
+    0x05, 0x00, 0x01, 0x04, 0x00, 0x01, 0x00, 0x00, 0x04, 0x00, 0x01, 0x04, 0x08, 0x03, 0x00, 0x00,
+    0x05, 0x04, 0x08, 0x00,
+];
+
+fn set_ip(ip: u32) {
+    cute_vm::cpu::set_ip(ip)
+}
+
+fn block_1600() {
+    // 0x1600: Nop NONE
+    set_ip(0x1600);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    cute_vm::timing::retire(Instr::Nop, true);
+    // 0x1602: Lit SHORT
+    set_ip(0x1602);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    cute_vm::timing::access(false);
+    cute_vm::push(0x1638, Status::from_bits_truncate(0x4));
+    cute_vm::timing::retire(Instr::Lit, true);
+    if cute_vm::interrupt().load(Ordering::Relaxed) {
+        return set_ip(0x1608);
+    }
+    // 0x1608: Nop NONE
+    set_ip(0x1608);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    cute_vm::timing::retire(Instr::Nop, true);
+    // 0x160a: Lit SHORT
+    set_ip(0x160a);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    cute_vm::timing::access(false);
+    cute_vm::push(0x300, Status::from_bits_truncate(0x4));
+    cute_vm::timing::retire(Instr::Lit, true);
+    if cute_vm::interrupt().load(Ordering::Relaxed) {
+        return set_ip(0x1610);
+    }
+    // 0x1610: Str SHORT
+    set_ip(0x1610);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    Instruction::new(Instr::Str, Status::from_bits_truncate(0x4)).execute();
+}
+
+fn block_1612() {
+    // 0x1612: Lit NONE
+    set_ip(0x1612);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    cute_vm::timing::access(false);
+    cute_vm::push(0x7, Status::from_bits_truncate(0x0));
+    cute_vm::timing::retire(Instr::Lit, true);
+    if cute_vm::interrupt().load(Ordering::Relaxed) {
+        return set_ip(0x1616);
+    }
+    // 0x1616: Lit NONE
+    set_ip(0x1616);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    cute_vm::timing::access(false);
+    cute_vm::push(0x1, Status::from_bits_truncate(0x0));
+    cute_vm::timing::retire(Instr::Lit, true);
+    if cute_vm::interrupt().load(Ordering::Relaxed) {
+        return set_ip(0x161a);
+    }
+    // 0x161a: Lit NONE
+    set_ip(0x161a);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    cute_vm::timing::access(false);
+    cute_vm::push(0x0, Status::from_bits_truncate(0x0));
+    cute_vm::timing::retire(Instr::Lit, true);
+    if cute_vm::interrupt().load(Ordering::Relaxed) {
+        return set_ip(0x161e);
+    }
+    // 0x161e: Div NONE
+    set_ip(0x161e);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    Instr::Div.execute(Status::from_bits_truncate(0x0));
+    cute_vm::timing::retire(Instr::Div, true);
+    if cute_vm::interrupt().load(Ordering::Relaxed) {
+        return set_ip(0x1620);
+    }
+    // 0x1620: Lit NONE
+    set_ip(0x1620);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    cute_vm::timing::access(false);
+    cute_vm::push(0x2, Status::from_bits_truncate(0x0));
+    cute_vm::timing::retire(Instr::Lit, true);
+    if cute_vm::interrupt().load(Ordering::Relaxed) {
+        return set_ip(0x1624);
+    }
+    // 0x1624: Add NONE
+    set_ip(0x1624);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    Instr::Add.execute(Status::from_bits_truncate(0x0));
+    cute_vm::timing::retire(Instr::Add, true);
+    if cute_vm::interrupt().load(Ordering::Relaxed) {
+        return set_ip(0x1626);
+    }
+    // 0x1626: Drop SHORT
+    set_ip(0x1626);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    Instr::Drop.execute(Status::from_bits_truncate(0x4));
+    cute_vm::timing::retire(Instr::Drop, true);
+    if cute_vm::interrupt().load(Ordering::Relaxed) {
+        return set_ip(0x1628);
+    }
+    // 0x1628: Lit NONE
+    set_ip(0x1628);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    cute_vm::timing::access(false);
+    cute_vm::push(0x41, Status::from_bits_truncate(0x0));
+    cute_vm::timing::retire(Instr::Lit, true);
+    if cute_vm::interrupt().load(Ordering::Relaxed) {
+        return set_ip(0x162c);
+    }
+    // 0x162c: Nop NONE
+    set_ip(0x162c);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    cute_vm::timing::retire(Instr::Nop, true);
+    // 0x162e: Lit SHORT
+    set_ip(0x162e);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    cute_vm::timing::access(false);
+    cute_vm::push(0x100, Status::from_bits_truncate(0x4));
+    cute_vm::timing::retire(Instr::Lit, true);
+    if cute_vm::interrupt().load(Ordering::Relaxed) {
+        return set_ip(0x1634);
+    }
+    // 0x1634: Str NONE
+    set_ip(0x1634);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    Instruction::new(Instr::Str, Status::from_bits_truncate(0x0)).execute();
+}
+
+fn block_1636() {
+    // 0x1636: Halt NONE
+    set_ip(0x1636);
+    if !cute_vm::limits::tick() {
+        return;
+    }
+    Instr::Halt.execute(Status::from_bits_truncate(0x0));
+    cute_vm::timing::retire(Instr::Halt, true);
+    if cute_vm::interrupt().load(Ordering::Relaxed) {
+        return set_ip(0x1638);
+    }
+    set_ip(0x1638);
+}
+
+/// Runs the current core until the machine stops
+pub fn run() {
+    while cute_vm::running() {
+        if cute_vm::interrupt().load(Ordering::Relaxed) {
+            cute_vm::step();
+            continue;
+        }
+
+        match cute_vm::cpu::ip() {
+            0x1600 => block_1600(),
+            0x1612 => block_1612(),
+            0x1636 => block_1636(),
+            _ => cute_vm::step(),
+        }
+
+        while !cute_vm::OUT_PUT_READY.load(Ordering::Relaxed) {
+            std::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Images, labels and line information produced by the casm assembler
+
+use std::fs;
+
+use cute_vm::asm;
+
+#[test]
+fn encoding() {
+    let assembly = asm::assemble_at("input.casm", "
+    #start
+        lit 0x41
+        lit$s #start
+        add$sk
+        halt
+    ", 0x1600).unwrap();
+
+    // Opcode then flags, with a nop to align the long immediate
+    assert_eq!(assembly.image, [
+        0x1, 0x0, 0x41, 0x0,
+        0x0, 0x0,
+        0x1, 0x4, 0x0, 0x16, 0x0, 0x0,
+        0xa, 0x5,
+        0xf, 0x0,
+    ]);
+
+    assert_eq!(assembly.debug.symbols.find("start"), Some(0x1600));
+
+    let lines: Vec<_> = assembly.debug.lines.iter().map(|(addr, source)| (*addr, source.line)).collect();
+    assert_eq!(lines, [(0x1600, 3), (0x1606, 4), (0x160c, 5), (0x160e, 6)]);
+}
+
+#[test]
+fn include() {
+    let dir = std::env::temp_dir().join(format!("cute-vm-asm-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    fs::write(dir.join("main.casm"), "lit$s #lib\njsr\nhalt\n.include \"lib.casm\"\n").unwrap();
+    fs::write(dir.join("lib.casm"), "#lib\n    jsr$r\n").unwrap();
+
+    let assembly = asm::assemble_file(&dir.join("main.casm"));
+    fs::remove_dir_all(&dir).unwrap();
+
+    let assembly = assembly.unwrap();
+    let lib = assembly.debug.symbols.find("lib").unwrap();
+    let source = assembly.debug.lines[&lib];
+
+    assert!(assembly.debug.files[source.file].ends_with("lib.casm"));
+    assert_eq!(source.line, 2);
+}
+
+#[test]
+fn errors() {
+    let err = asm::assemble_at("input.casm", "lit 1\nfrob\n", 0x1600).unwrap_err();
+    assert_eq!((err.file.as_str(), err.line), ("input.casm", 2));
+
+    let err = asm::assemble_at("input.casm", "lit$s #missing\n", 0x1600).unwrap_err();
+    assert_eq!(err.line, 1);
+}
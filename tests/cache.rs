@@ -0,0 +1,182 @@
+//! The predecoded loop against the stepping interpreter
+
+use cute_vm::{
+    asm, cpu,
+    cache::{self, DecodeCache},
+    harness::{self, Config, Outcome},
+    limits::Limits,
+};
+
+/// Runs `source` stepping and predecoded
+fn both(source: &str) -> (Outcome, Outcome) {
+    let config = Config::default();
+    let image = asm::assemble_at("input.casm", source, config.machine.program.load).unwrap().image;
+
+    let stepped = harness::run(&image, &config);
+    let predecoded = harness::with_machine(&image, &config, || cache::run(&mut DecodeCache::new()));
+
+    (stepped, predecoded)
+}
+
+fn assert_same(stepped: &Outcome, predecoded: &Outcome) {
+    assert!(stepped.halted());
+    assert_eq!(predecoded.status, stepped.status);
+    assert_eq!(predecoded.output, stepped.output);
+    assert_eq!(predecoded.primary, stepped.primary);
+    assert_eq!(predecoded.ret, stepped.ret);
+    assert_eq!(predecoded.instructions, stepped.instructions);
+}
+
+#[test]
+fn count_loop() {
+    let source = "
+        lit$sr 0
+        lit$s 0
+    #loop
+        drop$sr
+        lit$s 1
+        add$s
+        dup$s
+        lit$s 200
+        cmp$s
+        dup$sr
+        drop$sr
+        lit$s #loop
+        jsr$g
+        lit 0x41
+        lit$s 0x100
+        str
+        halt
+    ";
+
+    let (stepped, predecoded) = both(source);
+
+    assert_same(&stepped, &predecoded);
+    assert_eq!(predecoded.output, b"A");
+    // The count, under the target of the last `jsr$g`, which was skipped
+    assert_eq!(predecoded.primary[..2], [200, 0]);
+}
+
+#[test]
+fn faults_mid_slice() {
+    // The division faults in the middle of a run of stack instructions, the
+    // ones after it only run once the handler returns
+    let source = "
+        lit$s #handler
+        lit$s 0x300
+        str$s
+        lit 7
+        lit 1
+        lit 0
+        div
+        lit 2
+        add
+        halt
+    #handler
+        lit$s 0x30c
+        load
+        lit$s 0x100
+        str
+        lit$s 0x308
+        load$s
+        jsr
+    ";
+
+    let (stepped, predecoded) = both(source);
+
+    assert_same(&stepped, &predecoded);
+    assert_eq!(predecoded.output, [6]);
+    assert_eq!(predecoded.primary, [9]);
+}
+
+#[test]
+fn self_modifying() {
+    // The first pass prints the immediate of #patch, then rewrites it over
+    // the already decoded instruction and runs it again
+    let source = "
+        lit$sr 0
+    #loop
+        drop$sr
+    #patch
+        lit 0x41
+        lit$s 0x100
+        str
+        lit 1
+        lit$s 0x3000
+        swap
+        lit 0
+        cmp
+        lit$s #again
+        jsr$e
+        halt
+    #again
+        lit 0x42
+        lit$s #patch
+        lit$s 2
+        add$s
+        str
+        lit$s #loop
+        jsr
+    ";
+
+    let (stepped, predecoded) = both(source);
+
+    assert_same(&stepped, &predecoded);
+    assert_eq!(predecoded.output, b"AB");
+}
+
+#[test]
+fn code_written_by_other_core() {
+    // Core 0 spins on #check until core 1 rewrites its immediate, which core
+    // 1 only does once core 0 has run it
+    let source = "
+        lit$sr 0
+        lit$s 0x10
+        load
+        lit 0
+        cmp
+        lit$s #spin
+        jsr$e
+    #wait
+        drop$sr
+        lit$s 0x3000
+        load
+        lit 1
+        cmp
+        lit$s #wait
+        jsr$g
+        lit 1
+        lit$s #check
+        lit$s 2
+        add$s
+        str
+        halt
+    #spin
+        drop$sr
+        lit 1
+        lit$s 0x3000
+        str
+    #check
+        lit 0
+        lit 0
+        cmp
+        lit$s #spin
+        jsr$e
+        lit 0x41
+        lit$s 0x100
+        str
+        halt
+    ";
+
+    let image = asm::assemble_at("input.casm", source, Config::default().machine.program.load).unwrap().image;
+    // A stale decode spins until the limit
+    let limits = Limits { instructions: Some(1_000_000), ..Limits::default() };
+    let config = Config { cores: 2, limits, ..Config::default() };
+
+    for _ in 0..20 {
+        let outcome = harness::with_machine(&image, &config, cpu::run_parallel);
+
+        assert!(outcome.halted());
+        assert_eq!(outcome.output, b"A");
+    }
+}
@@ -0,0 +1,553 @@
+//! Conformance of every instruction under its flags
+//!
+//! Each case loads a single instruction at 0x1602, sets up the stacks, the
+//! condition register and memory, runs one step and checks all of them and
+//! the instruction pointer afterwards. The tables describe the machine as it
+//! behaves, quirks included, so a change to the ISA shows up here first.
+
+use std::sync::{atomic::Ordering, Mutex, MutexGuard};
+
+use cute_vm::{
+    cpu,
+    instructions::{ConditionRegister, Instr, Status},
+    sic,
+    HALTED, MMU,
+};
+
+/// Where the instruction under test starts, so a `lit$s` immediate is aligned
+const START: u32 = 0x1602;
+/// Scratch RAM for memory operands
+const DATA: u32 = 0x3000;
+/// The last word of RAM, only its first byte is backed
+const END: u32 = 0x1000 + 0xFFFE;
+
+const NONE: Status = Status::NONE;
+const K: Status = Status::KEEP;
+const R: Status = Status::RETURN;
+const S: Status = Status::SHORT;
+
+static MACHINE: Mutex<()> = Mutex::new(());
+
+/// Cells of a 32 bit value as it sits on a stack, low half first
+fn long(value: u32) -> [u16; 2] {
+    [value as u16, (value >> 16) as u16]
+}
+
+fn cells(values: &[u32], status: Status) -> Vec<u16> {
+    values.iter().flat_map(|value| if status.contains(S) { long(*value).to_vec() } else { vec![*value as u16] }).collect()
+}
+
+#[derive(Debug, Clone)]
+struct Case {
+    instr: Instr,
+    status: Status,
+    immediate: Option<u32>,
+    primary: Vec<u16>,
+    ret: Vec<u16>,
+    cond: u16,
+    memory: Vec<(u32, u16)>,
+
+    expect_primary: Vec<u16>,
+    expect_ret: Vec<u16>,
+    expect_ip: u32,
+    expect_cond: u16,
+    expect_memory: Vec<(u32, u16)>,
+    expect_halted: bool,
+    /// Exception the step should raise
+    expect_exception: Option<u32>,
+}
+
+impl Case {
+    fn new(instr: Instr, status: Status) -> Self {
+        Self {
+            instr,
+            status,
+            immediate: None,
+            primary: Vec::new(),
+            ret: Vec::new(),
+            cond: 0,
+            memory: Vec::new(),
+            expect_primary: Vec::new(),
+            expect_ret: Vec::new(),
+            expect_ip: START + 2,
+            expect_cond: 0,
+            expect_memory: Vec::new(),
+            expect_halted: false,
+            expect_exception: None,
+        }
+    }
+
+    fn immediate(mut self, value: u32) -> Self {
+        self.immediate = Some(value);
+        self.expect_ip = START + if self.status.contains(S) { 6 } else { 4 };
+        self
+    }
+
+    /// Starting primary stack, bottom first
+    fn primary(mut self, cells: &[u16]) -> Self {
+        self.primary = cells.to_vec();
+        self
+    }
+
+    /// Starting return stack, bottom first
+    fn ret(mut self, cells: &[u16]) -> Self {
+        self.ret = cells.to_vec();
+        self
+    }
+
+    /// Condition register before and, unless changed later, after the step
+    fn cond(mut self, cond: ConditionRegister) -> Self {
+        self.cond = cond.bits();
+        self.expect_cond = cond.bits();
+        self
+    }
+
+    fn memory(mut self, addr: u32, value: u16) -> Self {
+        self.memory.push((addr, value));
+        self
+    }
+
+    fn expect_primary(mut self, cells: &[u16]) -> Self {
+        self.expect_primary = cells.to_vec();
+        self
+    }
+
+    fn expect_ret(mut self, cells: &[u16]) -> Self {
+        self.expect_ret = cells.to_vec();
+        self
+    }
+
+    fn expect_ip(mut self, ip: u32) -> Self {
+        self.expect_ip = ip;
+        self
+    }
+
+    fn expect_cond(mut self, cond: ConditionRegister) -> Self {
+        self.expect_cond = cond.bits();
+        self
+    }
+
+    fn expect_memory(mut self, addr: u32, value: u16) -> Self {
+        self.expect_memory.push((addr, value));
+        self
+    }
+
+    fn expect_halted(mut self) -> Self {
+        self.expect_halted = true;
+        self
+    }
+
+    fn expect_exception(mut self, cause: u32) -> Self {
+        self.expect_exception = Some(cause);
+        self
+    }
+
+    /// Same case with the stacks swapped, for `RETURN`
+    fn on_return(self) -> Self {
+        Self {
+            status: self.status | R,
+            primary: self.ret,
+            ret: self.primary,
+            expect_primary: self.expect_ret,
+            expect_ret: self.expect_primary,
+            ..self
+        }
+    }
+
+    fn image(&self) -> Vec<u8> {
+        let mut image = vec![Instr::Nop as u8, 0, self.instr as u8, self.status.bits()];
+
+        match self.immediate {
+            Some(value) if self.status.contains(S) => image.extend_from_slice(&value.to_le_bytes()),
+            Some(value) => image.extend_from_slice(&(value as u16).to_le_bytes()),
+            None => {},
+        }
+
+        image.extend_from_slice(&[Instr::Halt as u8, 0]);
+        image
+    }
+
+    fn run(&self) {
+        let _machine = lock();
+
+        cute_vm::load_image(&self.image(), 0xFFFF);
+        cute_vm::set_instr_ptr(START);
+        ConditionRegister::from_bits(self.cond).unwrap().write();
+
+        for (addr, value) in &self.memory {
+            MMU.lock().unwrap().write_u16(*addr, *value);
+        }
+
+        let core = &cpu::CORES[0];
+        for cell in &self.primary {
+            core.primary.lock().unwrap().push(*cell as u32, NONE);
+        }
+        for cell in &self.ret {
+            core.ret.lock().unwrap().push(*cell as u32, NONE);
+        }
+
+        cute_vm::step();
+
+        let name = format!("{:?} with {:?}", self.instr, self.status);
+
+        assert_eq!(stack(&core.primary), self.expect_primary, "primary stack after {}", name);
+        assert_eq!(stack(&core.ret), self.expect_ret, "return stack after {}", name);
+        assert_eq!(cute_vm::instr_ptr() as u32, self.expect_ip, "instruction pointer after {}", name);
+        assert_eq!(ConditionRegister::read().bits(), self.expect_cond, "condition register after {}", name);
+        assert_eq!(HALTED.load(Ordering::Relaxed), self.expect_halted, "halted after {}", name);
+
+        let raised = core.interrupt.load(Ordering::Relaxed).then(|| core.sic.lock().unwrap().cause);
+        assert_eq!(raised, self.expect_exception.map(|cause| cause | 1 << 31), "exception after {}", name);
+
+        for (addr, value) in &self.expect_memory {
+            assert_eq!(MMU.lock().unwrap().read_u16(*addr), *value, "memory at 0x{:x} after {}", addr, name);
+        }
+    }
+}
+
+fn lock() -> MutexGuard<'static, ()> {
+    MACHINE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn stack(stack: &Mutex<cute_vm::stack::Stack>) -> Vec<u16> {
+    let stack = stack.lock().unwrap();
+
+    (0..stack.top()).step_by(2).map(|index| stack.copy(index, NONE) as u16).collect()
+}
+
+fn run_all(cases: Vec<Case>) {
+    for case in cases {
+        case.run();
+    }
+}
+
+/// Every case once as given and once mirrored onto the return stack
+fn with_return(cases: Vec<Case>) -> Vec<Case> {
+    cases.into_iter().flat_map(|case| [case.clone(), case.on_return()]).collect()
+}
+
+#[test]
+fn nop() {
+    run_all(with_return(vec![
+        Case::new(Instr::Nop, NONE).primary(&[1]).expect_primary(&[1]),
+        Case::new(Instr::Nop, K | S).primary(&[1, 2]).expect_primary(&[1, 2]),
+    ]));
+}
+
+#[test]
+fn lit() {
+    run_all(with_return(vec![
+        Case::new(Instr::Lit, NONE).immediate(0x1234).expect_primary(&[0x1234]),
+        // KEEP means nothing to a push
+        Case::new(Instr::Lit, K).immediate(0x1234).primary(&[1]).expect_primary(&[1, 0x1234]),
+        Case::new(Instr::Lit, S).immediate(0x1234_5678).expect_primary(&long(0x1234_5678)),
+        Case::new(Instr::Lit, K | S).immediate(0xdead_beef).expect_primary(&long(0xdead_beef)),
+        // Pushing past the 0x100 bytes of a stack raises an overflow and leaves it
+        Case::new(Instr::Lit, NONE).immediate(0x1234).primary(&[7; 0x80]).expect_primary(&[7; 0x80])
+            .expect_exception(sic::STACK_OVERFLOW),
+        Case::new(Instr::Lit, S).immediate(0x1234).primary(&[7; 0x7f]).expect_primary(&[7; 0x7f])
+            .expect_exception(sic::STACK_OVERFLOW),
+    ]));
+}
+
+#[test]
+fn dup() {
+    run_all(with_return(vec![
+        // The copy goes to the other stack
+        Case::new(Instr::Dup, NONE).primary(&[1, 7]).expect_primary(&[1, 7]).expect_ret(&[7]),
+        Case::new(Instr::Dup, K).primary(&[7]).expect_primary(&[7]).expect_ret(&[7]),
+        Case::new(Instr::Dup, S).primary(&long(0x1_0002)).expect_primary(&long(0x1_0002)).expect_ret(&long(0x1_0002)),
+        Case::new(Instr::Dup, K | S).primary(&long(0x1_0002)).expect_primary(&long(0x1_0002)).expect_ret(&long(0x1_0002)),
+    ]));
+}
+
+#[test]
+fn over() {
+    let three = cells(&[0x1_0001, 0x2_0002, 0x3_0003], S);
+    let reversed = cells(&[0x3_0003, 0x2_0002, 0x1_0001], S);
+
+    run_all(with_return(vec![
+        // The top three swap ends, KEEP is ignored
+        Case::new(Instr::Over, NONE).primary(&[9, 1, 2, 3]).expect_primary(&[9, 3, 2, 1]),
+        Case::new(Instr::Over, K).primary(&[1, 2, 3]).expect_primary(&[3, 2, 1]),
+        Case::new(Instr::Over, S).primary(&three).expect_primary(&reversed),
+        Case::new(Instr::Over, K | S).primary(&three).expect_primary(&reversed),
+    ]));
+}
+
+#[test]
+fn str() {
+    let addr = long(DATA);
+
+    run_all(with_return(vec![
+        Case::new(Instr::Str, NONE).primary(&[1, 0xbeef, addr[0], addr[1]])
+            .memory(DATA + 2, 0x5555).expect_primary(&[1])
+            .expect_memory(DATA, 0xbeef).expect_memory(DATA + 2, 0x5555),
+        Case::new(Instr::Str, S).primary(&[0xbeef, 0xdead, addr[0], addr[1]])
+            .expect_memory(DATA, 0xbeef).expect_memory(DATA + 2, 0xdead),
+        // With KEEP the address stays, so its top half is what gets stored
+        Case::new(Instr::Str, K).primary(&[0xbeef, addr[0], addr[1]]).memory(DATA, 0x5555)
+            .expect_primary(&[0xbeef, addr[0], addr[1]]).expect_memory(DATA, 0),
+        Case::new(Instr::Str, K | S).primary(&[0xbeef, addr[0], addr[1]])
+            .expect_primary(&[0xbeef, addr[0], addr[1]])
+            .expect_memory(DATA, addr[0]).expect_memory(DATA + 2, addr[1]),
+        // Faulting stores leave memory alone
+        Case::new(Instr::Str, NONE).primary(&[1, 0xbeef, long(DATA + 1)[0], long(DATA + 1)[1]])
+            .expect_primary(&[1]).expect_memory(DATA, 0).expect_exception(1),
+        Case::new(Instr::Str, NONE).primary(&[1, 0xbeef, long(END)[0], long(END)[1]])
+            .expect_primary(&[1]).expect_exception(1),
+    ]));
+}
+
+#[test]
+fn load() {
+    let addr = long(DATA);
+
+    run_all(with_return(vec![
+        Case::new(Instr::Load, NONE).primary(&[1, addr[0], addr[1]]).memory(DATA, 0xbeef)
+            .expect_primary(&[1, 0xbeef]).expect_memory(DATA, 0xbeef),
+        Case::new(Instr::Load, K).primary(&[addr[0], addr[1]]).memory(DATA, 0xbeef)
+            .expect_primary(&[addr[0], addr[1], 0xbeef]),
+        Case::new(Instr::Load, S).primary(&addr).memory(DATA, 0xbeef).memory(DATA + 2, 0xdead)
+            .expect_primary(&long(0xdead_beef)),
+        Case::new(Instr::Load, K | S).primary(&addr).memory(DATA, 0xbeef).memory(DATA + 2, 0xdead)
+            .expect_primary(&[addr[0], addr[1], 0xbeef, 0xdead]),
+        Case::new(Instr::Load, NONE).primary(&long(END)).expect_primary(&[0]).expect_exception(0),
+    ]));
+}
+
+#[test]
+fn push() {
+    // Pops and pushes the same stack, only KEEP makes a difference
+    run_all(with_return(vec![
+        Case::new(Instr::Push, NONE).primary(&[1, 2]).expect_primary(&[1, 2]),
+        Case::new(Instr::Push, K).primary(&[1, 2]).expect_primary(&[1, 2, 2]),
+        Case::new(Instr::Push, S).primary(&long(0x1_0002)).expect_primary(&long(0x1_0002)),
+        Case::new(Instr::Push, K | S).primary(&long(0x1_0002)).expect_primary(&cells(&[0x1_0002, 0x1_0002], S)),
+    ]));
+}
+
+#[test]
+fn drop() {
+    run_all(with_return(vec![
+        Case::new(Instr::Drop, NONE).primary(&[1, 2]).expect_primary(&[1]),
+        // KEEP is honoured, leaving the value
+        Case::new(Instr::Drop, K).primary(&[1, 2]).expect_primary(&[1, 2]),
+        Case::new(Instr::Drop, S).primary(&[1, 2, 3]).expect_primary(&[1]),
+        Case::new(Instr::Drop, K | S).primary(&[1, 2, 3]).expect_primary(&[1, 2, 3]),
+        // Popping more than the stack holds raises an underflow and leaves it
+        Case::new(Instr::Drop, NONE).expect_exception(sic::STACK_UNDERFLOW),
+        Case::new(Instr::Drop, S).primary(&[1]).expect_primary(&[1]).expect_exception(sic::STACK_UNDERFLOW),
+    ]));
+}
+
+#[test]
+fn jsr() {
+    let target = long(0x2000);
+    let ret = long(START + 2);
+
+    // Only RETURN matters, the stacks are always 32 bits wide
+    for flags in [NONE, K, S, K | S] {
+        run_all(vec![
+            Case::new(Instr::Jsr, flags).primary(&[1, target[0], target[1]])
+                .expect_primary(&[1]).expect_ret(&ret).expect_ip(0x2000),
+            Case::new(Instr::Jsr, flags | R).primary(&[1]).ret(&target)
+                .expect_primary(&[1, ret[0], ret[1]]).expect_ip(0x2000),
+        ]);
+    }
+
+    // An odd target faults on the jsr, with nothing pushed
+    let odd = long(0x2001);
+    run_all(vec![
+        Case::new(Instr::Jsr, NONE).primary(&odd).expect_ip(START).expect_exception(sic::ILLEGAL_INSTRUCTION),
+        Case::new(Instr::Jsr, R).ret(&odd).expect_ip(START).expect_exception(sic::ILLEGAL_INSTRUCTION),
+    ]);
+
+    // A conditional jsr jumps when its condition is met, and otherwise only
+    // moves on to the next instruction, leaving the target on the stack
+    use ConditionRegister as C;
+    let (equal, less) = (Status::IF_EQUAL, Status::IF_LESS);
+    run_all(vec![
+        Case::new(Instr::Jsr, equal).primary(&target).cond(C::EQUAL)
+            .expect_ret(&ret).expect_ip(0x2000).expect_cond(C::EQUAL),
+        Case::new(Instr::Jsr, equal).primary(&target).cond(C::GREATER)
+            .expect_primary(&target).expect_ip(START + 2).expect_cond(C::GREATER),
+        Case::new(Instr::Jsr, equal | less).primary(&target).cond(C::LESS)
+            .expect_ret(&ret).expect_ip(0x2000).expect_cond(C::LESS),
+        Case::new(Instr::Jsr, equal | R).ret(&target).cond(C::EQUAL)
+            .expect_primary(&ret).expect_ip(0x2000).expect_cond(C::EQUAL),
+        Case::new(Instr::Jsr, equal | R).ret(&target)
+            .expect_ret(&target).expect_ip(START + 2),
+    ]);
+}
+
+#[test]
+fn cmp() {
+    use ConditionRegister as C;
+
+    run_all(with_return(vec![
+        // The top of the stack is compared against the value under it
+        Case::new(Instr::Cmp, NONE).primary(&[9, 2, 2]).cond(C::LESS).expect_primary(&[9]).expect_cond(C::EQUAL),
+        Case::new(Instr::Cmp, NONE).primary(&[1, 2]).expect_cond(C::GREATER),
+        Case::new(Instr::Cmp, NONE).primary(&[2, 1]).expect_cond(C::LESS),
+        Case::new(Instr::Cmp, S).primary(&cells(&[0x1_0000, 0x2_0000], S)).expect_cond(C::GREATER),
+        Case::new(Instr::Cmp, S).primary(&cells(&[0x2_0000, 0x1_0000], S)).expect_cond(C::LESS),
+        // With KEEP the top is compared against itself
+        Case::new(Instr::Cmp, K).primary(&[1, 2]).expect_primary(&[1, 2]).expect_cond(C::EQUAL),
+        Case::new(Instr::Cmp, K | S).primary(&cells(&[1, 2], S)).expect_primary(&cells(&[1, 2], S)).expect_cond(C::EQUAL),
+    ]));
+}
+
+#[test]
+fn arithmetic() {
+    let mut cases = Vec::new();
+
+    // (instr, deeper, top, result, top with itself)
+    for (instr, a, b, result, keep) in [
+        (Instr::Add, 5, 3, 8, 6),
+        (Instr::Sub, 5, 3, 2, 0),
+        (Instr::Mul, 5, 3, 15, 9),
+        (Instr::Div, 15, 3, 5, 1),
+    ] {
+        cases.push(Case::new(instr, NONE).primary(&[9, a, b]).expect_primary(&[9, result]));
+        cases.push(Case::new(instr, K).primary(&[a, b]).expect_primary(&[a, b, keep]));
+
+        let big = 0x1_0000;
+        let wide = match instr {
+            Instr::Add => big + 3,
+            Instr::Sub => big - 3,
+            Instr::Mul => big * 3,
+            _ => big / 3,
+        };
+        cases.push(Case::new(instr, S).primary(&cells(&[big, 3], S)).expect_primary(&long(wide)));
+        cases.push(Case::new(instr, K | S).primary(&cells(&[a as u32, b as u32], S))
+            .expect_primary(&cells(&[a as u32, b as u32, keep as u32], S)));
+    }
+
+    // 16 bit results are cut down to a cell, 32 bit ones wrap
+    cases.push(Case::new(Instr::Add, NONE).primary(&[0xffff, 2]).expect_primary(&[1]));
+    cases.push(Case::new(Instr::Mul, NONE).primary(&[0x100, 0x100]).expect_primary(&[0]));
+    cases.push(Case::new(Instr::Sub, NONE).primary(&[1, 2]).expect_primary(&[0xffff]));
+    cases.push(Case::new(Instr::Add, S).primary(&cells(&[0xffff_ffff, 2], S)).expect_primary(&long(1)));
+    cases.push(Case::new(Instr::Mul, S).primary(&cells(&[0x1_0000, 0x1_0000], S)).expect_primary(&long(0)));
+
+    // Dividing by zero uses up both operands
+    cases.push(Case::new(Instr::Div, NONE).primary(&[9, 4, 0]).expect_primary(&[9]).expect_exception(sic::DIVIDE_BY_ZERO));
+
+    run_all(with_return(cases));
+}
+
+#[test]
+fn halt() {
+    run_all(with_return(vec![
+        Case::new(Instr::Halt, NONE).primary(&[1]).expect_primary(&[1]).expect_halted(),
+        Case::new(Instr::Halt, K | S).expect_halted(),
+    ]));
+}
+
+#[test]
+fn cas() {
+    use ConditionRegister as C;
+
+    let addr = long(DATA);
+
+    run_all(with_return(vec![
+        // ( new expected addr -- old )
+        Case::new(Instr::Cas, NONE).primary(&[2, 1, addr[0], addr[1]]).memory(DATA, 1)
+            .expect_primary(&[1]).expect_memory(DATA, 2).expect_cond(C::EQUAL),
+        Case::new(Instr::Cas, NONE).primary(&[2, 1, addr[0], addr[1]]).memory(DATA, 7).cond(C::EQUAL | C::LESS)
+            .expect_primary(&[7]).expect_memory(DATA, 7).expect_cond(C::LESS),
+        Case::new(Instr::Cas, S).primary(&[0x2, 0x2, 0x1, 0x1, addr[0], addr[1]]).memory(DATA, 1).memory(DATA + 2, 1)
+            .expect_primary(&long(0x1_0001)).expect_memory(DATA, 2).expect_memory(DATA + 2, 2).expect_cond(C::EQUAL),
+        // With KEEP the top half of the address is both values
+        Case::new(Instr::Cas, K).primary(&addr).memory(DATA, 0)
+            .expect_primary(&[addr[0], addr[1], 0]).expect_memory(DATA, 0).expect_cond(C::EQUAL),
+    ]));
+}
+
+#[test]
+fn fadd_and_swap() {
+    let addr = long(DATA);
+
+    run_all(with_return(vec![
+        // ( value addr -- old )
+        Case::new(Instr::Fadd, NONE).primary(&[3, addr[0], addr[1]]).memory(DATA, 4)
+            .expect_primary(&[4]).expect_memory(DATA, 7),
+        Case::new(Instr::Fadd, NONE).primary(&[2, addr[0], addr[1]]).memory(DATA, 0xffff)
+            .expect_primary(&[0xffff]).expect_memory(DATA, 1),
+        Case::new(Instr::Fadd, S).primary(&[1, 0, addr[0], addr[1]]).memory(DATA, 0xffff).memory(DATA + 2, 0)
+            .expect_primary(&long(0xffff)).expect_memory(DATA, 0).expect_memory(DATA + 2, 1),
+        Case::new(Instr::Swap, NONE).primary(&[3, addr[0], addr[1]]).memory(DATA, 4)
+            .expect_primary(&[4]).expect_memory(DATA, 3),
+        Case::new(Instr::Swap, S).primary(&[5, 6, addr[0], addr[1]]).memory(DATA, 1).memory(DATA + 2, 2)
+            .expect_primary(&long(0x2_0001)).expect_memory(DATA, 5).expect_memory(DATA + 2, 6),
+    ]));
+}
+
+/// Every instruction with every set of condition flags against every state
+/// of the condition register: it runs when one of its flags matches, and is
+/// skipped otherwise with only the instruction pointer moving on
+#[test]
+fn conditions() {
+    let conditions = [Status::IF_EQUAL, Status::IF_GREATER, Status::IF_LESS];
+    let instrs = [
+        Instr::Nop, Instr::Lit, Instr::Dup, Instr::Over, Instr::Push, Instr::Drop, Instr::Jsr,
+        Instr::Add, Instr::Sub, Instr::Mul, Instr::Div, Instr::Halt,
+    ];
+
+    for instr in instrs {
+        for set in 1..8u8 {
+            let status = conditions.iter().enumerate()
+                .filter(|(bit, _)| set & (1 << bit) != 0)
+                .fold(NONE, |status, (_, flag)| status | *flag);
+
+            for cond in 0..8u16 {
+                let register = ConditionRegister::from_bits(cond).unwrap();
+                let met = (status.bits() >> 3) as u16 & cond != 0;
+                assert_eq!(status.condition_met(register), met);
+
+                let mut case = Case::new(instr, status).primary(&[3, 2, 0x2000, 1]).cond(register);
+                if instr == Instr::Lit {
+                    case = case.immediate(0x4444);
+                }
+
+                if met {
+                    // The unconditional version of the instruction decides the outcome
+                    let mut plain = Case::new(instr, NONE).primary(&[3, 2, 0x2000, 1]).cond(register);
+                    if instr == Instr::Lit {
+                        plain = plain.immediate(0x4444);
+                    }
+
+                    let expected = expected(&plain);
+                    case.expect_primary = expected.0;
+                    case.expect_ret = expected.1;
+                    case.expect_ip = expected.2;
+                    case.expect_halted = expected.3;
+                } else {
+                    case.expect_primary = case.primary.clone();
+                    case.expect_ret = case.ret.clone();
+                }
+
+                case.run();
+            }
+        }
+    }
+}
+
+/// Stacks, instruction pointer and halt state after running `case`
+fn expected(case: &Case) -> (Vec<u16>, Vec<u16>, u32, bool) {
+    let _machine = lock();
+
+    cute_vm::load_image(&case.image(), 0xFFFF);
+    cute_vm::set_instr_ptr(START);
+    ConditionRegister::from_bits(case.cond).unwrap().write();
+
+    let core = &cpu::CORES[0];
+    for cell in &case.primary {
+        core.primary.lock().unwrap().push(*cell as u32, NONE);
+    }
+
+    cute_vm::step();
+
+    (stack(&core.primary), stack(&core.ret), cute_vm::instr_ptr() as u32, HALTED.load(Ordering::Relaxed))
+}
@@ -0,0 +1,72 @@
+//! Cores interrupting each other
+
+use cute_vm::{
+    asm, cpu,
+    harness::{self, Config},
+};
+
+/// Core 1 waits for an interrupt, core 0 sends it the first one once it is
+/// ready. Every handler prints the ID of its core and interrupts the other
+/// core until ten have run.
+const PING_PONG: &str = "
+    lit$s #handler
+    lit$s 0x300
+    str$s
+    lit$s 0x10
+    load
+    lit 0
+    cmp
+    lit$s #first
+    jsr$e
+    lit 1
+    lit$s 0x3002
+    str
+    halt
+#first
+    lit$sr 0
+#wait
+    drop$sr
+    lit$s 0x3002
+    load
+    lit 1
+    cmp
+    lit$s #wait
+    jsr$g
+    lit 1
+    lit$s 0x18
+    str
+    halt
+#handler
+    lit$s 0x10
+    load
+    lit 0x30
+    add
+    lit$s 0x100
+    str
+    lit 1
+    lit$s 0x3000
+    fadd
+    lit 9
+    cmp
+    lit 1
+    lit$s 0x10
+    load
+    sub
+    lit$sg 0x18
+    str$g
+    halt
+";
+
+#[test]
+fn ping_pong() {
+    let image = asm::assemble_at("input.casm", PING_PONG, Config::default().machine.program.load).unwrap().image;
+    let config = Config { cores: 2, ..Config::default() };
+
+    for _ in 0..20 {
+        let outcome = harness::with_machine(&image, &config, cpu::run_parallel);
+
+        assert!(outcome.halted());
+        assert_eq!(outcome.output_str(), "1010101010");
+        assert_eq!(outcome.memory(0x3000, 2), [10, 0]);
+    }
+}
@@ -0,0 +1,60 @@
+//! lcov reports written by `--coverage`
+
+use cute_vm::{
+    asm,
+    coverage,
+    cpu::RoundRobin,
+    harness::{self, Config},
+};
+
+#[test]
+fn lcov() {
+    let assembly = asm::assemble_at("input.casm", "\
+#main
+    lit 1
+    lit 2
+    cmp
+    lit$g 7
+    lit$e 8
+    halt
+#never
+    halt
+", 0x1600).unwrap();
+
+    let mut coverage = None;
+
+    let outcome = harness::with_machine(&assembly.image, &Config::default(), || {
+        coverage::start();
+        RoundRobin::new(1).run();
+        coverage = coverage::finish();
+    });
+
+    assert!(outcome.halted());
+    assert_eq!(outcome.primary, [7]);
+    assert_eq!(coverage.unwrap().lcov(&assembly.debug), "\
+TN:
+SF:input.casm
+FN:2,main
+FN:9,never
+FNDA:1,main
+FNDA:0,never
+FNF:2
+FNH:1
+BRDA:5,0,0,1
+BRDA:5,0,1,0
+BRDA:6,0,0,0
+BRDA:6,0,1,1
+BRF:4
+BRH:2
+DA:2,1
+DA:3,1
+DA:4,1
+DA:5,1
+DA:6,1
+DA:7,1
+DA:9,0
+LF:7
+LH:6
+end_of_record
+");
+}
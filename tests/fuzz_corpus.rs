@@ -0,0 +1,33 @@
+//! Replays the corpus of every fuzz target, so inputs that once crashed the
+//! VM keep working without cargo-fuzz
+
+use std::{fs, panic, path::Path};
+
+fn replay(target: &str, run: fn(&[u8])) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus").join(target);
+
+    let mut inputs: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "No corpus in {}", dir.display());
+
+    for path in inputs {
+        let data = fs::read(&path).unwrap();
+
+        assert!(panic::catch_unwind(|| run(&data)).is_ok(), "{} crashed the VM", path.display());
+    }
+}
+
+#[test]
+fn execute() {
+    replay("execute", cute_vm::fuzz::execute);
+}
+
+#[test]
+fn mmu() {
+    replay("mmu", cute_vm::fuzz::mmu);
+}
+
+#[test]
+fn decode() {
+    replay("decode", cute_vm::fuzz::decode);
+}
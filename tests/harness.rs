@@ -0,0 +1,86 @@
+//! Headless runs
+
+use cute_vm::{
+    asm,
+    harness::{self, Config, ExitStatus},
+    limits::{Limit, Limits},
+};
+
+#[test]
+fn echo() {
+    let source = "
+        lit$sr 0
+    #loop
+        drop$sr
+        lit$s 0x106
+        load
+        lit 1
+        cmp
+        lit$s #done
+        jsr$e
+        drop$s
+        lit$s 0x104
+        load
+        lit$s 0x100
+        str
+        lit$s #loop
+        jsr
+    #done
+        halt
+    ";
+
+    let config = Config { input: b"hi!".to_vec(), ..Config::default() };
+    let outcome = harness::run_casm(source, &config).unwrap();
+
+    assert_eq!(outcome.status, ExitStatus::Halted);
+    assert_eq!(outcome.output_str(), "hi!");
+}
+
+#[test]
+fn call() {
+    let source = "
+        halt
+    #double
+        lit 2
+        mul
+        jsr$r
+    ";
+
+    let assembly = asm::assemble_at("input.casm", source, Config::default().machine.program.load).unwrap();
+    let outcome = harness::call_label(&assembly, "double", &[21], &Config::default()).unwrap();
+
+    assert_eq!(outcome.status, ExitStatus::Returned);
+    assert_eq!(outcome.primary, [42]);
+    assert_eq!(outcome.ret, []);
+
+    assert!(harness::call_label(&assembly, "missing", &[], &Config::default()).is_none());
+}
+
+#[test]
+fn instruction_limit() {
+    let source = "
+        lit$sr 0
+    #loop
+        drop$sr
+        lit$s #loop
+        jsr
+    ";
+
+    let config = Config { limits: Limits { instructions: Some(100), ..Limits::default() }, ..Config::default() };
+    let outcome = harness::run_casm(source, &config).unwrap();
+
+    assert_eq!(outcome.status, ExitStatus::Limit(Limit::Instructions(100)));
+    assert_eq!(outcome.instructions, 100);
+}
+
+#[test]
+fn vm_panic() {
+    // Too little memory for the registers of a core
+    let config = Config { memory: 0x10, ..Config::default() };
+    let outcome = harness::run_casm("halt", &config).unwrap();
+
+    assert!(matches!(&outcome.status, ExitStatus::Panicked(message) if message.contains("Not enough memory")));
+
+    // The next run starts from a clean machine
+    assert!(harness::run_casm("halt", &Config::default()).unwrap().halted());
+}
@@ -0,0 +1,50 @@
+//! Reverse execution in the debugger undoes steps from the history
+
+use cute_vm::{
+    asm,
+    cpu::{self, RoundRobin},
+    harness::{self, Config},
+    history,
+};
+
+#[test]
+fn undo() {
+    let assembly = asm::assemble_at("input.casm", "
+        lit 5
+        lit$s 0x3000
+        str
+        lit 9
+        halt
+    ", 0x1600).unwrap();
+
+    let lit = assembly.debug.lines.iter()
+        .find(|(_, line)| line.line == 5)
+        .map(|(addr, _)| *addr)
+        .unwrap();
+
+    let outcome = harness::with_machine(&assembly.image, &Config::default(), || {
+        history::start(history::DEFAULT_CAPACITY);
+        RoundRobin::new(1).run();
+        assert!(!cute_vm::running());
+
+        // Back over the halt and the last push
+        history::undo().unwrap();
+        assert_eq!(history::undo(), Some(lit));
+        assert_eq!(cpu::ip(), lit);
+        assert!(cute_vm::running());
+        assert_eq!(cute_vm::top(false), 0);
+        assert_eq!(cute_vm::mem().read_u16(0x2000).unwrap(), 5);
+
+        // Back to the start, before the store
+        while history::undo().is_some() {}
+        assert_eq!(cpu::ip(), 0x1600);
+        assert_eq!(cute_vm::mem().read_u16(0x2000).unwrap(), 0);
+
+        // Running again gets to the same end
+        RoundRobin::new(1).run();
+    });
+
+    assert!(outcome.halted());
+    assert_eq!(outcome.primary, [9]);
+    assert_eq!(outcome.memory(0x3000, 2), [5, 0]);
+}
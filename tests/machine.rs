@@ -0,0 +1,392 @@
+//! Programs running on machines laid out by a description file
+
+use cute_vm::{
+    harness::{self, Config},
+    instructions::Instr,
+    machine::{Machine, MachineError},
+};
+
+const RELOCATED: &str = "
+[memory]
+io_max = 0x1fff
+ram_base = 0x2000
+
+[cores]
+ip = 0x2200
+conditions = 0x2204
+primary_stack = 0x30ff
+return_stack = 0x31ff
+
+[program]
+load = 0x4000
+entry = 0x4000
+
+[devices]
+output = 0x800
+sic = 0x300
+";
+
+fn config(machine: &str) -> Config {
+    Config { machine: Machine::parse(machine).unwrap(), ..Config::default() }
+}
+
+#[test]
+fn default_layout() {
+    assert_eq!(Machine::parse("").unwrap(), Machine::DEFAULT);
+}
+
+#[test]
+fn relocated() {
+    let source = "
+        lit 0x4f
+        lit$s 0x800
+        str
+        lit$s #next
+        jsr
+    #next
+        lit 0x4b
+        lit$s 0x800
+        str
+        halt
+    ";
+
+    let outcome = harness::run_casm(source, &config(RELOCATED)).unwrap();
+
+    assert!(outcome.halted());
+    assert_eq!(outcome.output_str(), "OK");
+    assert_eq!(outcome.memory(0x4000, 2), [Instr::Lit as u8, 0]);
+    // `jsr` left the address of #next, linked for the relocated program
+    assert_eq!(outcome.ret, [0x4016, 0]);
+}
+
+#[test]
+fn detached_devices_fault() {
+    // Only the output device and the Sic are attached, the core ID register
+    // is gone
+    let source = "
+        lit$s #handler
+        lit$s 0x300
+        str$s
+        lit$s 0x10
+        load
+        halt
+    #handler
+        lit$s 0x304
+        load
+        lit$s 0x800
+        str
+        halt
+    ";
+
+    let outcome = harness::run_casm(source, &config(RELOCATED)).unwrap();
+
+    assert_eq!(outcome.output, [3]);
+}
+
+#[test]
+fn stack_sizes() {
+    // The fifth cell does not fit in 8 bytes
+    let source = "
+        lit$s #handler
+        lit$s 0x300
+        str$s
+        lit 1
+        lit 2
+        lit 3
+        lit 4
+        lit 5
+        halt
+    #handler
+        drop$s
+        drop$s
+        lit$s 0x304
+        load
+        lit$s 0x100
+        str
+        halt
+    ";
+
+    let machine = Machine::DEFAULT.with_stack_sizes(Some(8), None).unwrap();
+    let outcome = harness::run_casm(source, &Config { machine, ..Config::default() }).unwrap();
+
+    assert_eq!(outcome.output, [7]);
+    assert!(outcome.primary.is_empty());
+}
+
+#[test]
+fn stack_in_device_memory() {
+    // A 4 byte return stack over the output device, the first cell pushed
+    // lands on it with its high byte at the lower address
+    let source = "
+        lit$s 0x101
+        lit$s 0x4
+        str$s
+        lit$r 0x4f00
+        halt
+    ";
+
+    let machine = Machine::DEFAULT.with_stack_sizes(None, Some(4)).unwrap();
+    let outcome = harness::run_casm(source, &Config { machine, ..Config::default() }).unwrap();
+
+    assert!(outcome.halted());
+    assert_eq!(outcome.output_str(), "O");
+}
+
+#[test]
+fn unmapped_stack_faults() {
+    // The handler only has the return stack left
+    let source = "
+        lit$s #handler
+        lit$s 0x300
+        str$s
+        lit$s 0x200001
+        lit$s 0x0
+        str$s
+        lit 1
+        halt
+    #handler
+        lit$sr 0x304
+        load$r
+        lit$sr 0x100
+        str$r
+        halt
+    ";
+
+    let outcome = harness::run_casm(source, &Config::default()).unwrap();
+
+    assert_eq!(outcome.output, [1]);
+}
+
+#[test]
+fn stack_registers_read_back() {
+    let source = "
+        lit$s 0x0
+        load$s
+        lit$s 0x8
+        load$s
+        lit$s 0x4
+        load
+        halt
+    ";
+
+    let outcome = harness::run_casm(source, &Config::default()).unwrap();
+
+    // The offsets are read with the long position on the stack, primary in
+    // the high half
+    assert_eq!(outcome.primary, [0x20ff, 0, 0, 4, 0x21ff]);
+}
+
+#[test]
+fn context_switch() {
+    // Saves the stacks to 0x3000, then switches to the block at 0x3100 which
+    // moves the primary stack to 0x40ff
+    let source = "
+        lit 7
+        lit$s 0x3000
+        lit$s 0x40
+        str$s
+        lit$s 0x40ff
+        lit$s 0x3100
+        str$s
+        lit$s 0x21ff
+        lit$s 0x3104
+        str$s
+        lit$s 0x3100
+        lit$s 0x44
+        str$s
+        lit 9
+        halt
+    ";
+
+    let outcome = harness::run_casm(source, &Config::default()).unwrap();
+
+    assert_eq!(outcome.memory(0x3000, 12), [0xff, 0x20, 0, 0, 0xff, 0x21, 0, 0, 2, 0, 0, 0]);
+    assert_eq!(outcome.primary, [9]);
+    assert_eq!(outcome.memory(0x40fe, 2), [0, 9]);
+}
+
+#[test]
+fn bad_context_faults() {
+    // A misaligned block, and a block of zeros which would put the primary
+    // stack at an even address
+    for (block, register) in [(0x3002, 0x40), (0x3100, 0x44)] {
+        let source = format!("
+            lit$s #handler
+            lit$s 0x300
+            str$s
+            lit 7
+            lit$s {block}
+            lit$s {register}
+            str$s
+            halt
+        #handler
+            lit$s 0x304
+            load
+            lit$s 0x100
+            str
+            halt
+        ");
+
+        let outcome = harness::run_casm(&source, &Config::default()).unwrap();
+
+        assert_eq!(outcome.output, [3]);
+        assert_eq!(outcome.primary, [7]);
+    }
+}
+
+#[test]
+fn vectored_interrupts() {
+    // Division by zero goes through its entry in the table at 0x3000, which
+    // sets no other entry
+    let source = "
+        lit$s #other
+        lit$s 0x300
+        str$s
+        lit$s #divide
+        lit$s 0x3018
+        str$s
+        lit$s 0x3000
+        lit$s 0x310
+        str$s
+        lit 1
+        lit 0
+        div
+        halt
+    #divide
+        lit$s 0x30c
+        load
+        lit$s 0x100
+        str
+        halt
+    #other
+        halt
+    ";
+
+    let outcome = harness::run_casm(source, &Config::default()).unwrap();
+
+    assert!(outcome.halted());
+    assert_eq!(outcome.output, [6]);
+}
+
+#[test]
+fn vector_without_entry() {
+    // A stack at an even address has no entry and goes to the handler at
+    // 0x300, with cause 3 and a vector of its own
+    let source = "
+        lit$s #handler
+        lit$s 0x300
+        str$s
+        lit$s 0x3000
+        lit$s 0x310
+        str$s
+        lit$s 0x3000
+        lit$s 0x0
+        str$s
+        halt
+    #handler
+        lit$s 0x30c
+        load
+        lit$s 0x100
+        str
+        lit$s 0x304
+        load
+        lit$s 0x100
+        str
+        halt
+    ";
+
+    let outcome = harness::run_casm(source, &Config::default()).unwrap();
+
+    assert_eq!(outcome.output, [11, 3]);
+}
+
+#[test]
+fn fault_vectors() {
+    // Every kind of bad access has a vector of its own, along with the cause
+    // it shares with the others
+    let faults = [
+        ("lit$s 0x3001\n load", [14, 0]),
+        ("lit$s 0x80000\n load", [15, 0]),
+        ("lit 1\n lit$s 0x3001\n str", [16, 1]),
+        ("lit 1\n lit$s 0x80000\n str", [17, 1]),
+        ("lit 1\n lit$s 0xffe\n str", [18, 3]),
+        ("lit$s 0xffe\n load", [18, 3]),
+    ];
+
+    for (fault, expected) in faults {
+        let source = format!("
+            lit$s #handler
+            lit$s 0x300
+            str$s
+            {}
+            halt
+        #handler
+            lit$s 0x30c
+            load
+            lit$s 0x100
+            str
+            lit$s 0x304
+            load
+            lit$s 0x100
+            str
+            halt
+        ", fault);
+
+        let outcome = harness::run_casm(&source, &Config::default()).unwrap();
+
+        assert_eq!(outcome.output, expected, "{}", fault);
+    }
+}
+
+#[test]
+fn vectored_faults() {
+    // Only unmapped reads have an entry, at 0x3000 + 4 * 15, misaligned ones
+    // go to the handler at 0x300
+    for (addr, expected) in [("0x80000", &b"U"[..]), ("0x3001", b"")] {
+        let source = format!("
+            lit$s #other
+            lit$s 0x300
+            str$s
+            lit$s #unmapped
+            lit$s 0x303c
+            str$s
+            lit$s 0x3000
+            lit$s 0x310
+            str$s
+            lit$s {}
+            load
+            halt
+        #unmapped
+            lit 0x55
+            lit$s 0x100
+            str
+            halt
+        #other
+            halt
+        ", addr);
+
+        let outcome = harness::run_casm(&source, &Config::default()).unwrap();
+
+        assert!(outcome.halted());
+        assert_eq!(outcome.output, expected, "{}", addr);
+    }
+}
+
+#[test]
+fn invalid() {
+    for source in [
+        "[memory]\nram_base = 0x800",
+        "[cores]\nip = 0x1202",
+        "[cores]\nconditions = 0x1202",
+        "[program]\nload = 0x800",
+        "[devices]\noutput = 0x0\nstacks = 0x0",
+        "[devices]\nsic = 0xffe",
+        "[cores]\nspeed = 1",
+        "[cores]\nprimary_stack_size = 3",
+        "[cores]\nreturn_stack_size = 2",
+        "[cores]\nprimary_stack = 0x2100",
+        "[memory]\nram_base = 0x2000\n[cores]\nip = 0x2200\nconditions = 0x2204\nprimary_stack = 0x2010",
+    ] {
+        assert!(matches!(Machine::parse(source), Err(MachineError::Invalid(_) | MachineError::Parse(_))), "{}", source);
+    }
+}
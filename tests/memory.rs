@@ -0,0 +1,44 @@
+//! Sparse memory
+
+use cute_vm::{
+    harness::{self, Config},
+    memory::{Memory, PAGE_SIZE},
+};
+
+#[test]
+fn clone_unaligned_size() {
+    let memory = Memory::new(0xFFFF);
+    memory.write_u16(0xFFF0, 0x1234).unwrap();
+    memory.write_u16(0x10, 0x5678).unwrap();
+
+    let copy = memory.clone();
+
+    assert_eq!(copy.size(), 0xFFFF);
+    assert_eq!(copy.read_u16(0xFFF0), Ok(0x1234));
+    assert_eq!(copy.read_u16(0x10), Ok(0x5678));
+    assert_eq!(copy.resident(), 2 * PAGE_SIZE);
+}
+
+#[test]
+fn clone_outcome() {
+    // The last page of the default 0xFFFF bytes of RAM is partial
+    let outcome = harness::run_casm("
+        lit 0x42
+        lit$s 0x10ff0
+        str
+        halt
+    ", &Config::default()).unwrap();
+
+    assert_eq!(outcome.clone().memory(0x10ff0, 2), [0x42, 0]);
+}
+
+#[test]
+fn from_pages() {
+    let memory = Memory::from_pages(0x2000, [(0, &[1, 2, 3][..]), (1, &[0][..]), (0x1000, &[0, 0][..])]).unwrap();
+
+    assert_eq!(memory.read_bytes(0, 3), Ok(vec![1, 0, 3]));
+    // A page of nothing but zeros is never allocated
+    assert_eq!(memory.resident(), PAGE_SIZE);
+
+    assert!(Memory::from_pages(0x10, [(0xf, &[1, 2][..])]).is_err());
+}
@@ -0,0 +1,51 @@
+//! Reports written by `--profile`
+
+use cute_vm::{
+    asm,
+    cpu::RoundRobin,
+    harness::{self, Config},
+    profile,
+};
+
+#[test]
+fn calls() {
+    let assembly = asm::assemble_at("input.casm", "
+    #main
+        lit$s #twice
+        jsr
+        drop$s
+        halt
+    #twice
+        lit$s #once
+        jsr
+        drop$s
+        lit$s #once
+        jsr
+        drop$s
+        jsr$r
+    #once
+        jsr$r
+    ", 0x1600).unwrap();
+
+    let symbols = &assembly.debug.symbols;
+    let mut profiler = None;
+
+    let outcome = harness::with_machine(&assembly.image, &Config::default(), || {
+        profile::start(0x1600);
+        RoundRobin::new(1).run();
+        profiler = profile::finish();
+    });
+
+    let profiler = profiler.unwrap();
+
+    assert!(outcome.halted());
+    assert_eq!(profiler.folded(symbols), "main 5\nmain;twice 8\nmain;twice;once 2\n");
+
+    let report = profiler.report(symbols);
+    let lines: Vec<_> = report.lines().collect();
+
+    assert_eq!(lines[0], "Total instructions: 15");
+    assert_eq!(lines[4], "           2   13.33%  0x1626      #once");
+    assert!(report.contains("           6   40.00%  Jsr\n"));
+    assert!(report.contains("           1    6.67%  Halt\n"));
+}
@@ -0,0 +1,114 @@
+//! Problems the stack sanitizer reports
+
+use cute_vm::{
+    harness::{self, Config, Outcome},
+    instructions::Instr,
+    sanitize::Problem,
+};
+
+fn run(source: &str) -> Outcome {
+    harness::run_casm(source, &Config { sanitize: true, ..Config::default() }).unwrap()
+}
+
+fn problems(outcome: &Outcome) -> Vec<Problem> {
+    outcome.reports.iter().map(|report| report.problem.clone()).collect()
+}
+
+#[test]
+fn balanced() {
+    let outcome = run("
+        lit$s #routine
+        jsr
+        drop$s
+        lit 1
+        drop
+        halt
+    #routine
+        lit$s 0x10002
+        drop$s
+        jsr$r
+    ");
+
+    assert!(outcome.halted());
+    assert_eq!(problems(&outcome), []);
+}
+
+#[test]
+fn mismatched_widths() {
+    let outcome = run("
+        lit 1
+        lit 5
+        drop$s
+        lit$s 0x10005
+        drop$r
+        drop
+        halt
+    ");
+
+    assert_eq!(problems(&outcome), [
+        Problem::Mismatch { ret: false, popped: 32, pushed: 16 },
+        Problem::Underflow { ret: true, width: 16 },
+    ]);
+
+    // `drop$r` underflows and never gets to the long value, the core halts on
+    // the unhandled exception before the last `drop`
+    let report = &outcome.reports[0];
+    assert_eq!(report.history.last().map(|(ip, instr, _)| (*ip, *instr)), Some((report.ip, Instr::Drop)));
+    assert_eq!(report.history.len(), 3);
+}
+
+#[test]
+fn split_long() {
+    let outcome = run("
+        lit$s 0x10005
+        drop
+        halt
+    ");
+
+    assert_eq!(problems(&outcome), [Problem::Mismatch { ret: false, popped: 16, pushed: 32 }]);
+}
+
+#[test]
+fn unbalanced_return() {
+    // The routine returns through an address it pushed itself, over the one
+    // its caller left
+    let outcome = run("
+        lit$s #routine
+        jsr
+        halt
+    #routine
+        lit$sr #back
+        jsr$r
+    #back
+        halt
+    ");
+
+    assert!(matches!(problems(&outcome)[..], [Problem::Imbalance { expected: 4, found: 8, .. }]));
+}
+
+#[test]
+fn interrupt_frame() {
+    // The handler returns to where the interrupt was taken, inside a call
+    let outcome = run("
+        lit$s #handler
+        lit$s 0x300
+        str$s
+        lit$s #routine
+        jsr
+        halt
+    #routine
+        lit 0
+        lit$s 0xc
+        str
+        jsr$r
+    #handler
+        lit$s 0x308
+        load$s
+        dup$s
+        drop$s
+        jsr$r
+    ");
+
+    assert!(outcome.halted());
+    assert_eq!(problems(&outcome), []);
+}
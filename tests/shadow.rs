@@ -0,0 +1,97 @@
+//! Reads of uninitialized RAM found by shadow memory
+
+use cute_vm::{
+    harness::{self, Config, Outcome},
+    shadow::Report,
+};
+
+fn run(source: &str, allow: &[(u32, u32)]) -> Outcome {
+    harness::run_casm(source, &Config { shadow: Some(allow.to_vec()), ..Config::default() }).unwrap()
+}
+
+#[test]
+fn uninitialized() {
+    let outcome = run("
+        lit$s 0x3000
+        load$s
+        halt
+    ", &[]);
+
+    assert!(outcome.halted());
+    assert_eq!(outcome.uninitialized, [Report { ip: 0x1608, addr: 0x3000, width: 32 }]);
+}
+
+#[test]
+fn written() {
+    // The program itself was written by the loader, the data by the guest
+    let outcome = run("
+        lit 5
+        lit$s 0x3000
+        str
+        lit$s 0x3000
+        load
+        lit$s #end
+        load
+    #end
+        halt
+    ", &[]);
+
+    assert!(outcome.halted());
+    assert_eq!(outcome.uninitialized, []);
+}
+
+#[test]
+fn partly_written() {
+    let outcome = run("
+        lit 5
+        lit$s 0x3000
+        str
+        lit$s 0x3000
+        load$s
+        halt
+    ", &[]);
+
+    assert_eq!(outcome.uninitialized.len(), 1);
+}
+
+#[test]
+fn allowed() {
+    let outcome = run("
+        lit$s 0x3000
+        load
+        lit$s 0x30fc
+        load$s
+        halt
+    ", &[(0x3000, 0x30ff)]);
+
+    assert!(outcome.halted());
+    assert_eq!(outcome.uninitialized, []);
+}
+
+#[test]
+fn reported_once() {
+    // Counts to 3 like the interpreter benchmark, reading the same word
+    // every time around
+    let outcome = run("
+        lit$sr 0
+        lit$s 0
+    #loop
+        drop$sr
+        lit$s 0x3000
+        load
+        drop
+        lit$s 1
+        add$s
+        dup$s
+        lit$s 3
+        cmp$s
+        dup$sr
+        drop$sr
+        lit$s #loop
+        jsr$g
+        halt
+    ", &[]);
+
+    assert!(outcome.halted());
+    assert_eq!(outcome.uninitialized.len(), 1);
+}
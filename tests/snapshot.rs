@@ -0,0 +1,98 @@
+//! Snapshots restore the machine they were taken of
+
+use cute_vm::{
+    asm,
+    cpu::{self, RoundRobin},
+    harness::{self, Config},
+    snapshot::{self, SnapshotError},
+    timing,
+};
+
+#[test]
+fn round_trip() {
+    let assembly = asm::assemble_at("input.casm", "
+        lit 0x41
+        lit$s 0x100
+        str
+        lit 7
+        lit$sr 0x12345
+        lit 0x42
+        lit$s 0x3000
+        str
+        lit 0x43
+        lit$s 0x100
+        str
+        halt
+    ", 0x1600).unwrap();
+
+    let outcome = harness::with_machine(&assembly.image, &Config::default(), || {
+        let mut scheduler = RoundRobin::new(1);
+        for _ in 0..6 {
+            scheduler.step();
+        }
+
+        let saved = snapshot::save();
+        let (ip, cycles) = (cpu::ip(), timing::cycles());
+
+        scheduler.run();
+        assert_ne!(cpu::ip(), ip);
+        assert_ne!(timing::cycles(), cycles);
+
+        snapshot::restore(&saved).unwrap();
+
+        assert_eq!(cpu::ip(), ip);
+        assert_eq!(timing::cycles(), cycles);
+        assert_eq!(snapshot::save(), saved);
+
+        // Running on from the restored state ends the same way
+        scheduler.run();
+    });
+
+    assert!(outcome.halted());
+    assert_eq!(outcome.output_str(), "ACC");
+    assert_eq!(outcome.primary, [7]);
+    assert_eq!(outcome.ret, [0x2345, 0x1]);
+    assert_eq!(outcome.memory(0x3000, 2), [0x42, 0]);
+}
+
+#[test]
+fn rejects_truncated() {
+    let outcome = harness::with_machine(&[], &Config::default(), || {
+        let saved = snapshot::save();
+
+        assert!(snapshot::restore(&saved[..saved.len() - 1]).is_err());
+        assert!(snapshot::restore(b"NOTASNAP").is_err());
+    });
+
+    assert!(outcome.halted());
+}
+
+#[test]
+fn rejects_old_versions() {
+    harness::with_machine(&[], &Config::default(), || {
+        let mut saved = snapshot::save();
+        saved[8..12].copy_from_slice(&(snapshot::VERSION - 1).to_le_bytes());
+
+        assert!(matches!(snapshot::restore(&saved), Err(SnapshotError::UnsupportedVersion(_))));
+    });
+}
+
+#[test]
+fn rejects_memory_without_registers() {
+    harness::with_machine(&[], &Config::default(), || {
+        let saved = snapshot::save();
+        let ip = cpu::ip();
+
+        // Magic, version, MMU, core count and one core, then the output
+        // device, with 0x10 bytes of memory and no pages after them
+        let mut small = saved[..8 + 4 + 16 + 4 + 42 + 1].to_vec();
+        small.extend_from_slice(&0x10u64.to_le_bytes());
+        small.extend_from_slice(&0u32.to_le_bytes());
+
+        assert!(matches!(snapshot::restore(&small), Err(SnapshotError::BadMemorySize(0x10))));
+
+        // Nothing was touched
+        assert_eq!(cpu::ip(), ip);
+        assert_eq!(snapshot::save(), saved);
+    });
+}
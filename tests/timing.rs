@@ -0,0 +1,122 @@
+//! Cycle counting
+
+use std::time::{Duration, Instant};
+
+use cute_vm::{
+    cpu,
+    harness::{self, Config, Outcome},
+    timing::{self, IO_PENALTY, MEMORY_PENALTY},
+};
+
+/// Every core loads from RAM 200 times, then stores the cycles it took at
+/// 0x3100 for core 0 and 0x3104 for core 1
+const LOADS: &str = "
+    lit$sr 0
+    lit$s 0
+#loop
+    drop$sr
+    lit$s 0x3000
+    load
+    drop
+    lit$s 1
+    add$s
+    dup$s
+    lit$s 200
+    cmp$s
+    dup$sr
+    drop$sr
+    lit$s #loop
+    jsr$g
+    lit 0
+    lit$s 0x10
+    load
+    cmp
+    lit$s 0x20
+    load$s
+    lit$se 0x3100
+    lit$sg 0x3104
+    str$s
+    halt
+";
+
+fn run_parallel(cores: usize) -> Outcome {
+    let image = cute_vm::asm::assemble_at("input.casm", LOADS, Config::default().machine.program.load).unwrap().image;
+
+    harness::with_machine(&image, &Config { cores, ..Config::default() }, cpu::run_parallel)
+}
+
+fn cycles(outcome: &Outcome, addr: u32) -> u32 {
+    u32::from_le_bytes(outcome.memory(addr, 4).try_into().unwrap())
+}
+
+#[test]
+fn cores_count_their_own() {
+    let single = run_parallel(1);
+    let expected = cycles(&single, 0x3100);
+
+    assert!(expected > 200 * 2);
+
+    for _ in 0..20 {
+        let outcome = run_parallel(2);
+
+        assert!(outcome.halted());
+        assert_eq!([cycles(&outcome, 0x3100), cycles(&outcome, 0x3104)], [expected; 2]);
+    }
+}
+
+/// Cycles between two reads of the counter with `between` in the middle,
+/// which has to keep the second read aligned like the first
+fn delta(between: &str) -> u64 {
+    let source = format!("
+        lit$s 0x20
+        load$s
+        {}
+        lit$s 0x20
+        load$s
+        halt
+    ", between);
+
+    let outcome = harness::run_casm(&source, &Config::default()).unwrap();
+    assert!(outcome.halted());
+
+    let read = |cells: &[u16]| cells[0] as u64 | (cells[1] as u64) << 16;
+    read(&outcome.primary[2..4]) - read(&outcome.primary[0..2])
+}
+
+#[test]
+fn costs() {
+    let base = delta("");
+    let cost = |between: &str| delta(between) - base;
+    let lit = 2 + MEMORY_PENALTY;
+
+    assert_eq!(cost("nop\n nop"), 2);
+    assert_eq!(cost("lit 1\n drop\n nop"), lit + 2);
+    assert_eq!(cost("lit 6\n lit 3\n div\n drop\n nop\n nop"), 2 * lit + 10 + 3);
+    // Loads from RAM and from IO, the address pushed low cell first
+    assert_eq!(cost("lit 0x3000\n lit 0\n load\n drop"), 2 * lit + 2 + MEMORY_PENALTY + 1);
+    assert_eq!(cost("lit 0x20\n lit 0\n load\n drop"), 2 * lit + 2 + IO_PENALTY + 1);
+    // Skipped by their conditions, without fetching the immediate
+    assert_eq!(cost("lit$e 1\n nop\n nop"), 3);
+    assert_eq!(cost("div$g\n nop"), 2);
+}
+
+#[test]
+fn clock() {
+    let image = cute_vm::asm::assemble_at("input.casm", LOADS, Config::default().machine.program.load).unwrap().image;
+
+    let unthrottled = harness::run(&image, &Config::default());
+    let expected = cycles(&unthrottled, 0x3100);
+
+    // Long enough to see, short enough for a test
+    let hz = expected as u64 * 5;
+    let start = Instant::now();
+
+    let outcome = harness::with_machine(&image, &Config::default(), || {
+        timing::set_clock_hz(hz);
+        cpu::RoundRobin::new(1).run();
+        timing::set_clock_hz(0);
+    });
+
+    assert!(start.elapsed() >= Duration::from_millis(190), "ran in {:?}", start.elapsed());
+    assert_eq!(cycles(&outcome, 0x3100), expected);
+}
@@ -0,0 +1,50 @@
+//! JSON lines written by `--trace`
+
+use cute_vm::{
+    asm,
+    cpu::RoundRobin,
+    harness::{self, Config},
+    trace,
+};
+
+#[test]
+fn lines() {
+    let assembly = asm::assemble_at("input.casm", "
+        lit 5
+        lit$s 0x3000
+        str
+        halt
+    ", 0x1600).unwrap();
+
+    let path = std::env::temp_dir().join(format!("cute-vm-trace-{}.jsonl", std::process::id()));
+
+    let outcome = harness::with_machine(&assembly.image, &Config::default(), || {
+        trace::start(&path).unwrap();
+        RoundRobin::new(1).run();
+        trace::finish();
+    });
+
+    let trace = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(outcome.halted());
+
+    // The assembler pads `lit` so the long immediate after it is aligned
+    let lines: Vec<_> = trace.lines().collect();
+    assert_eq!(lines.len(), 5);
+
+    assert_eq!(lines[0], concat!(
+        r#"{"step":0,"ip":5632,"instr":"Lit","status":0,"flags":"NONE","executed":true,"#,
+        r#""primary":{"offset":2,"top":5},"return":{"offset":0,"top":null},"accesses":["#,
+        r#"{"kind":"read","space":"mem","addr":5634,"width":16,"value":5},"#,
+        r#"{"kind":"write","space":"mem","addr":8446,"width":16,"value":1280}]}"#,
+    ));
+
+    assert!(lines[3].starts_with(r#"{"step":3,"ip":5644,"instr":"Str","#));
+    assert!(lines[3].ends_with(r#"{"kind":"write","space":"mem","addr":12288,"width":16,"value":5}]}"#));
+
+    assert_eq!(lines[4], concat!(
+        r#"{"step":4,"ip":5646,"instr":"Halt","status":0,"flags":"NONE","executed":true,"#,
+        r#""primary":{"offset":0,"top":null},"return":{"offset":0,"top":null},"accesses":[]}"#,
+    ));
+}
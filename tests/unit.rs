@@ -0,0 +1,76 @@
+//! `;test` annotations and `cute test`
+
+use std::fs;
+
+use cute_vm::{
+    asm::{self, SourceLine},
+    harness::Config,
+    unit::{self, Case},
+};
+
+const ROUTINES: &str = "
+    halt
+#double ;test 3 -> 6
+    lit 2
+    mul
+    jsr$r
+#store ;test 0x2a -> mem 0x3000 = 0x2a 0
+    lit$s 0x3000
+    str
+    jsr$r
+";
+
+const FAILING: &str = "
+#wrong ;test 3 -> 7
+    lit 2
+    mul
+    jsr$r
+";
+
+#[test]
+fn parse() {
+    let source = SourceLine { file: 0, line: 1 };
+
+    let case = Case::parse("store", source, " 0x2a -> mem 0x3000 = 0x2a 0 mem 0x3004 = 1 \"ok\"").unwrap();
+    assert_eq!(case.args, [0x2a]);
+    assert_eq!(case.stack, None);
+    assert_eq!(case.memory, [(0x3000, vec![0x2a, 0]), (0x3004, vec![1])]);
+    assert_eq!(case.output.as_deref(), Some(&b"ok"[..]));
+
+    assert!(Case::parse("store", source, "-> mem 0x3000 = 0x100").is_err());
+    assert!(Case::parse("store", source, "-> mem 0x3000 =").is_err());
+    assert!(Case::parse("store", source, "-> mem 0x3000 1").is_err());
+}
+
+#[test]
+fn memory() {
+    let config = Config::default();
+    let assembly = asm::assemble_at("input.casm", ROUTINES, config.machine.program.load).unwrap();
+
+    let store = assembly.debug.tests.iter().find(|case| case.label == "store").unwrap();
+    assert_eq!(store.run(&assembly, &config), Ok(()));
+
+    let wrong = Case { memory: vec![(0x3000, vec![0x2b, 0])], ..store.clone() };
+    assert_eq!(wrong.run(&assembly, &config), Err("memory at 0x3000 holds [2a, 0], expected [2b, 0]".to_string()));
+}
+
+#[test]
+fn run_files() {
+    let dir = std::env::temp_dir().join(format!("cute-vm-unit-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    let passing = dir.join("passing.casm");
+    let failing = dir.join("failing.casm");
+    fs::write(&passing, ROUTINES).unwrap();
+    fs::write(&failing, format!("{}{}", ROUTINES, FAILING)).unwrap();
+
+    let files = |paths: &[&std::path::Path]| paths.iter().map(|path| path.display().to_string()).collect::<Vec<_>>();
+
+    let passed = unit::run_files(&files(&[&passing]), &Config::default());
+    let failed = unit::run_files(&files(&[&passing, &failing]), &Config::default());
+
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert!(passed);
+    assert!(!failed);
+}
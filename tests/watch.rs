@@ -0,0 +1,59 @@
+//! Watchpoints stop the debugger on reads and writes of memory
+
+use cute_vm::{
+    asm,
+    cpu::RoundRobin,
+    harness::{self, Config},
+    watch::{self, Watchpoint},
+};
+
+#[test]
+fn write_hit() {
+    let assembly = asm::assemble_at("input.casm", "
+        lit 5
+        lit$s 0x3000
+        str
+        lit$s 0x3000
+        load
+        halt
+    ", 0x1600).unwrap();
+
+    let str = assembly.debug.lines.iter()
+        .find(|(_, line)| line.line == 4)
+        .map(|(addr, _)| *addr)
+        .unwrap();
+
+    let outcome = harness::with_machine(&assembly.image, &Config::default(), || {
+        watch::add(Watchpoint::parse("3001:w").unwrap());
+        watch::add(Watchpoint::parse("4000-4fff").unwrap());
+
+        let mut scheduler = RoundRobin::new(1);
+        let mut hits = Vec::new();
+
+        while cute_vm::running() {
+            scheduler.step();
+            hits.extend(watch::take_hit());
+        }
+
+        watch::remove(0x3001);
+        watch::remove(0x4000);
+
+        // Only the store, the load reads the word but is not watched
+        assert_eq!(hits.len(), 1);
+
+        let hit = hits[0];
+        assert_eq!((hit.ip, hit.addr, hit.width, hit.write), (str, 0x3000, 16, true));
+        assert_eq!((hit.old, hit.new), (Some(0), 5));
+    });
+
+    assert!(outcome.halted());
+    assert!(!watch::WATCHING.load(std::sync::atomic::Ordering::Relaxed));
+}
+
+#[test]
+fn parse() {
+    assert_eq!(Watchpoint::parse("0x10-1f:r"), Some(Watchpoint { start: 0x10, end: 0x1f, read: true, write: false }));
+    assert_eq!(Watchpoint::parse("10"), Some(Watchpoint { start: 0x10, end: 0x10, read: true, write: true }));
+    assert_eq!(Watchpoint::parse("20-10"), None);
+    assert_eq!(Watchpoint::parse("10:x"), None);
+}